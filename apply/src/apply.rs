@@ -1,9 +1,9 @@
 use std::convert::TryInto;
 
-use parser::ast::Num;
+use parser::ast::{DataType, Literal, Num};
 use serde_json::value::Value;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PathIndex {
     Key(String),
     Index(usize),
@@ -27,6 +27,7 @@ impl From<usize> for PathIndex {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Path {
     p: Vec<PathIndex>,
 }
@@ -54,17 +55,74 @@ impl Path {
         }
     }
 
-    fn view<'a>(&self, v: &'a Value) -> Option<&'a Value> {
-        self.p.iter().fold(Some(v), |acc, i| {
-            acc.and_then(|v| match i {
-                PathIndex::Key(k) => v.get(k),
-                PathIndex::Index(i) => v.get(i),
-            })
+    /// Parses `address.city` and `tags[0]` forms, or a JSON Pointer
+    /// when `selector` starts with `/`.
+    pub fn parse(selector: &str) -> Result<Self, String> {
+        if let Some(pointer) = selector.strip_prefix('/') {
+            return Ok(pointer.split('/').fold(Path::new(), |path, token| {
+                let token = token.replace("~1", "/").replace("~0", "~");
+                match token.parse::<usize>() {
+                    Ok(i) => path.add(i),
+                    Err(_) => path.add(token),
+                }
+            }));
+        }
+        let mut path = Path::new();
+        for segment in selector.split('.') {
+            let (key, mut rest) = match segment.find('[') {
+                Some(open) => segment.split_at(open),
+                None => (segment, ""),
+            };
+            if key.is_empty() && (rest.is_empty() || !path.p.is_empty()) {
+                return Err(format!("empty segment in `{}`", selector));
+            }
+            if !key.is_empty() {
+                path = path.add(key);
+            }
+            while !rest.is_empty() {
+                let close = rest
+                    .find(']')
+                    .ok_or_else(|| format!("unclosed `[` in `{}`", selector))?;
+                let index = rest[1..close]
+                    .parse::<usize>()
+                    .map_err(|_| format!("bad index `{}` in `{}`", &rest[1..close], selector))?;
+                path = path.add(index);
+                rest = &rest[close + 1..];
+                if !rest.is_empty() && !rest.starts_with('[') {
+                    return Err(format!("unexpected `{}` in `{}`", rest, selector));
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// The value at this path, an index also reaches a numeric object key.
+    pub fn view<'a>(&self, v: &'a Value) -> Option<&'a Value> {
+        self.p.iter().try_fold(v, |v, i| match i {
+            PathIndex::Key(k) => v.get(k),
+            PathIndex::Index(i) => v.get(i).or_else(|| v.get(i.to_string())),
         })
     }
 }
 
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (n, i) in self.p.iter().enumerate() {
+            match i {
+                PathIndex::Key(k) if n == 0 => write!(f, "{}", k)?,
+                PathIndex::Key(k) => write!(f, ".{}", k)?,
+                PathIndex::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 pub trait Select<Output> {
+    fn select_null(&self) -> Option<Output> {
+        None
+    }
+
     fn select_bool(&self, _value: bool) -> Option<Output> {
         None
     }
@@ -91,8 +149,9 @@ where
     S: Select<Output>,
 {
     p.view(data).and_then(|value| match value {
+        Value::Null => s.select_null(),
         Value::Bool(b) => s.select_bool(*b),
-        Value::String(st) => s.select_string(&st),
+        Value::String(st) => s.select_string(st),
         Value::Number(n) => {
             if n.is_i64() {
                 s.select_i64(n.as_i64().unwrap())
@@ -156,3 +215,92 @@ where
 {
     SelectorBool(Box::new(f))
 }
+
+/// Selects a literal of the given type, null goes through as `Nil`.
+pub struct SelectorLiteral(pub DataType);
+
+impl Select<Literal> for SelectorLiteral {
+    fn select_null(&self) -> Option<Literal> {
+        Some(Literal::Nil)
+    }
+
+    fn select_bool(&self, value: bool) -> Option<Literal> {
+        match self.0 {
+            DataType::Boolean => Some(Literal::Boolean(value)),
+            _ => None,
+        }
+    }
+
+    fn select_string(&self, value: &str) -> Option<Literal> {
+        match self.0 {
+            DataType::String => Some(Literal::String(String::from(value))),
+            _ => None,
+        }
+    }
+
+    fn select_u64(&self, value: u64) -> Option<Literal> {
+        let value: i64 = value.try_into().ok()?;
+        self.select_i64(value)
+    }
+
+    fn select_i64(&self, value: i64) -> Option<Literal> {
+        match self.0 {
+            DataType::Number => Some(Literal::Number(Num::Integer(value))),
+            _ => None,
+        }
+    }
+
+    fn select_f64(&self, value: f64) -> Option<Literal> {
+        match self.0 {
+            DataType::Number => Some(Literal::Number(Num::Float(value))),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod path_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_forms() {
+        let expected = Path::new().add("address").add("tags").add(0).add("name");
+        assert_eq!(Path::parse("address.tags[0].name"), Ok(expected.clone()));
+        assert_eq!(Path::parse("/address/tags/0/name"), Ok(expected.clone()));
+        assert_eq!(expected.to_string(), "address.tags[0].name");
+        assert_eq!(
+            Path::parse("/a~1b/c~0d"),
+            Ok(Path::new().add("a/b").add("c~d"))
+        );
+        assert_eq!(
+            Path::parse("grid[1][2]"),
+            Ok(Path::new().add("grid").add(1).add(2))
+        );
+        assert!(Path::parse("address..city").is_err());
+        assert!(Path::parse("tags[0").is_err());
+        assert!(Path::parse("tags[x]").is_err());
+        assert!(Path::parse("tags[0]name").is_err());
+    }
+
+    #[test]
+    fn select_nested() {
+        let data = json!({"address": {"city": "Brussels", "zip": 1000}, "tags": ["a", null]});
+        let string = SelectorLiteral(DataType::String);
+        let number = SelectorLiteral(DataType::Number);
+        let city = Path::parse("address.city").unwrap();
+        assert_eq!(
+            select(&string, &city, &data),
+            Some(Literal::String(String::from("Brussels")))
+        );
+        assert_eq!(select(&number, &city, &data), None);
+        let zip = Path::parse("/address/zip").unwrap();
+        assert_eq!(
+            select(&number, &zip, &data),
+            Some(Literal::Number(Num::Integer(1000)))
+        );
+        let tag = Path::parse("tags[1]").unwrap();
+        assert_eq!(select(&string, &tag, &data), Some(Literal::Nil));
+        assert!(Path::parse("tags[2]").unwrap().view(&data).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+//! Arithmetic on resolved values.
+//!
+//! Promotion rules:
+//! - `Integer` with `Integer` gives an `Integer` for `+`, `-`, `*` and `%`,
+//!   an overflow is an error rather than a silent wrap.
+//! - `/` always gives a `Float`, `7 / 2` is `3.5`.
+//! - as soon as one operand is a `Float`, the result is a `Float`.
+//! - dividing by zero, with `/` or `%`, is an error.
+//! - operands that are not numbers are an error.
+
+use parser::ast::{Literal, Num};
+
+use crate::error::{ApplyError, ApplyResult};
+
+fn number(lit: Literal) -> ApplyResult<Num> {
+    match lit {
+        Literal::Number(n) => Ok(n),
+        _ => Err(ApplyError::Arithmetic(format!("{} is not a number", lit))),
+    }
+}
+
+fn overflow(op: &str, a: i64, b: i64) -> ApplyError {
+    ApplyError::Arithmetic(format!("{} {} {} overflows", a, op, b))
+}
+
+fn checked<I, F>(op: &str, left: Literal, right: Literal, int: I, float: F) -> ApplyResult<Literal>
+where
+    I: Fn(i64, i64) -> Option<i64>,
+    F: Fn(f64, f64) -> f64,
+{
+    match (number(left)?, number(right)?) {
+        (Num::Integer(a), Num::Integer(b)) => int(a, b)
+            .map(|n| Literal::Number(Num::Integer(n)))
+            .ok_or_else(|| overflow(op, a, b)),
+        (a, b) => Ok(Literal::Number(Num::Float(float(
+            a.as_float(),
+            b.as_float(),
+        )))),
+    }
+}
+
+pub fn neg(value: Literal) -> ApplyResult<Literal> {
+    match number(value)? {
+        Num::Integer(n) => n
+            .checked_neg()
+            .map(|n| Literal::Number(Num::Integer(n)))
+            .ok_or_else(|| ApplyError::Arithmetic(format!("-({}) overflows", n))),
+        Num::Float(f) => Ok(Literal::Number(Num::Float(-f))),
+    }
+}
+
+pub fn add(left: Literal, right: Literal) -> ApplyResult<Literal> {
+    checked("+", left, right, i64::checked_add, |a, b| a + b)
+}
+
+pub fn sub(left: Literal, right: Literal) -> ApplyResult<Literal> {
+    checked("-", left, right, i64::checked_sub, |a, b| a - b)
+}
+
+pub fn mul(left: Literal, right: Literal) -> ApplyResult<Literal> {
+    checked("*", left, right, i64::checked_mul, |a, b| a * b)
+}
+
+pub fn div(left: Literal, right: Literal) -> ApplyResult<Literal> {
+    let a = number(left)?.as_float();
+    let b = number(right)?.as_float();
+    if b == 0.0 {
+        Err(ApplyError::Arithmetic(format!("{} / 0", a)))
+    } else {
+        Ok(Literal::Number(Num::Float(a / b)))
+    }
+}
+
+pub fn rem(left: Literal, right: Literal) -> ApplyResult<Literal> {
+    let a = number(left)?;
+    let b = number(right)?;
+    if b.as_float() == 0.0 {
+        return Err(ApplyError::Arithmetic(format!("{} % 0", a)));
+    }
+    checked(
+        "%",
+        Literal::Number(a),
+        Literal::Number(b),
+        i64::checked_rem,
+        |a, b| a % b,
+    )
+}
+
+#[cfg(test)]
+mod arith_test {
+    use super::*;
+
+    fn int(n: i64) -> Literal {
+        Literal::Number(Num::Integer(n))
+    }
+
+    fn float(n: f64) -> Literal {
+        Literal::Number(Num::Float(n))
+    }
+
+    #[test]
+    fn promotion() {
+        assert_eq!(add(int(1), int(2)).unwrap(), int(3));
+        assert_eq!(add(int(1), float(0.5)).unwrap(), float(1.5));
+        assert_eq!(mul(float(2.0), int(3)).unwrap(), float(6.0));
+        assert_eq!(div(int(7), int(2)).unwrap(), float(3.5));
+        assert_eq!(div(int(8), int(2)).unwrap(), float(4.0));
+        assert_eq!(rem(int(7), int(2)).unwrap(), int(1));
+        assert_eq!(neg(int(7)).unwrap(), int(-7));
+    }
+
+    #[test]
+    fn failures() {
+        assert!(div(int(1), int(0)).is_err());
+        assert!(rem(int(1), float(0.0)).is_err());
+        assert!(add(int(i64::MAX), int(1)).is_err());
+        assert!(neg(int(i64::MIN)).is_err());
+        assert!(add(int(1), Literal::from("a")).is_err());
+        assert!(sub(Literal::Nil, int(1)).is_err());
+    }
+}
@@ -0,0 +1,600 @@
+//! Static types of a map, checked before any source is opened.
+//!
+//! A `select` has the type it declares and a function the return type
+//! of its signature. A call to a function declared with `fn` has the
+//! type of its body, its parameters taking the types of the arguments.
+//! Colors are strings at run time, so a string fits where a color is
+//! expected and the other way round.
+
+use std::fmt;
+
+use parser::ast::{
+    splice, Command, Constructor, DataType, Directive, Expr, FunctionCall, Intent, Literal,
+    MapSpec, PredGroup, Predicate, Value,
+};
+use parser::format::{format_predicate, format_value};
+
+use crate::function::user::{UserFunctions, MAX_CALL_DEPTH};
+use crate::function::FunctionRegistry;
+use crate::sym::pattern::Patterns;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Number,
+    String,
+    Boolean,
+    Color,
+    List(Box<Type>),
+    /// known at run time only, `nil` or an argument taking anything
+    Any,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::Boolean => write!(f, "Boolean"),
+            Type::Color => write!(f, "Color"),
+            Type::List(item) => write!(f, "List of {}", item),
+            Type::Any => write!(f, "Any"),
+        }
+    }
+}
+
+impl From<DataType> for Type {
+    fn from(arg: DataType) -> Self {
+        match arg {
+            DataType::String => Type::String,
+            DataType::Number => Type::Number,
+            DataType::Boolean => Type::Boolean,
+        }
+    }
+}
+
+impl Type {
+    /// Whether a value of type `found` fits where `self` is expected.
+    pub fn accepts(&self, found: &Type) -> bool {
+        match (self, found) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Color, Type::String) | (Type::String, Type::Color) => true,
+            (Type::List(a), Type::List(b)) => a.accepts(b),
+            (a, b) => a == b,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Params {
+    Fixed(Vec<Type>),
+    /// any number of arguments of this type
+    Variadic(Type),
+}
+
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub params: Params,
+    pub returns: Type,
+}
+
+impl Signature {
+    pub fn fixed(params: Vec<Type>, returns: Type) -> Self {
+        Signature {
+            params: Params::Fixed(params),
+            returns,
+        }
+    }
+
+    pub fn variadic(param: Type, returns: Type) -> Self {
+        Signature {
+            params: Params::Variadic(param),
+            returns,
+        }
+    }
+
+    fn check(&self, name: &str, args: &[Type]) -> Check<()> {
+        let expected: Vec<&Type> = match &self.params {
+            Params::Fixed(params) if params.len() != args.len() => {
+                return Err(format!(
+                    "`{}` takes {} arguments, got {}",
+                    name,
+                    params.len(),
+                    args.len()
+                ))
+            }
+            Params::Fixed(params) => params.iter().collect(),
+            Params::Variadic(param) => args.iter().map(|_| param).collect(),
+        };
+        for (i, (param, arg)) in expected.iter().zip(args).enumerate() {
+            if !param.accepts(arg) {
+                return Err(format!(
+                    "`{}` argument {} expects {}, found {}",
+                    name,
+                    i + 1,
+                    param,
+                    arg
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    /// byte offset of the directive in the map file, if known
+    pub position: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+type Check<T> = Result<T, String>;
+
+/// What names a value can use: the registered functions, the functions
+/// declared with `fn` and, while inferring a call, the argument types.
+#[derive(Clone, Copy)]
+pub struct Env<'a> {
+    registry: &'a FunctionRegistry,
+    functions: &'a UserFunctions,
+    params: &'a [Type],
+    /// the declared functions being inferred, innermost last
+    calls: &'a [&'a str],
+}
+
+impl<'a> Env<'a> {
+    pub fn new(registry: &'a FunctionRegistry, functions: &'a UserFunctions) -> Self {
+        Env {
+            registry,
+            functions,
+            params: &[],
+            calls: &[],
+        }
+    }
+}
+
+fn literal_type(lit: &Literal) -> Check<Type> {
+    match lit {
+        Literal::Nil => Ok(Type::Any),
+        Literal::Boolean(_) => Ok(Type::Boolean),
+        Literal::Number(_) => Ok(Type::Number),
+        Literal::String(_) => Ok(Type::String),
+        Literal::List(items) => {
+            let mut item = Type::Any;
+            for t in items.iter().map(literal_type) {
+                let t = t?;
+                if !item.accepts(&t) {
+                    return Err(format!("list mixes {} and {}", item, t));
+                }
+                if item == Type::Any {
+                    item = t;
+                }
+            }
+            Ok(Type::List(Box::new(item)))
+        }
+    }
+}
+
+fn number_operand(op: &str, value: &Value, env: &Env) -> Check<Type> {
+    let t = type_of(value, env)?;
+    if Type::Number.accepts(&t) {
+        Ok(Type::Number)
+    } else {
+        Err(format!(
+            "`{}` expects Number, found {} in {}",
+            op,
+            t,
+            format_value(value)
+        ))
+    }
+}
+
+fn arith(op: &str, (left, right): &(Value, Value), env: &Env) -> Check<Type> {
+    number_operand(op, left, env).and_then(|_| number_operand(op, right, env))
+}
+
+/// The type of `value`, errors inside a `data` value are left to
+/// the `data` directive that defines it.
+pub fn type_of(value: &Value, env: &Env) -> Check<Type> {
+    match value {
+        Value::Lit(lit) => literal_type(lit),
+        Value::Data(data) => match data.constructor.as_ref() {
+            Constructor::Select(select) => Ok(select.datatype.into()),
+            Constructor::Val(inner) => Ok(type_of(inner, env).unwrap_or(Type::Any)),
+            Constructor::Param(i) => Ok(env.params.get(*i).cloned().unwrap_or(Type::Any)),
+        },
+        Value::Call(f) => call_type(f, env),
+        Value::Fn(f) => {
+            let func = env
+                .registry
+                .get(&f.name)
+                .map_err(|_| format!("unknown function `{}`", f.name))?;
+            let signature = func.signature();
+            let args = f
+                .args
+                .iter()
+                .map(|arg| type_of(arg, env))
+                .collect::<Check<Vec<Type>>>()?;
+            signature.check(&f.name, &args)?;
+            Ok(signature.returns)
+        }
+        Value::Expr(expr) => match expr.as_ref() {
+            Expr::Neg(inner) => number_operand("-", inner, env),
+            Expr::Add(operands) => arith("+", operands, env),
+            Expr::Sub(operands) => arith("-", operands, env),
+            Expr::Mul(operands) => arith("*", operands, env),
+            Expr::Div(operands) => arith("/", operands, env),
+            Expr::Rem(operands) => arith("%", operands, env),
+        },
+        Value::Match(m) => {
+            let subject = type_of(&m.subject, env)?;
+            let result = type_of(&m.default, env)?;
+            for (case, branch) in m.arms.iter() {
+                let fits = match type_of(case, env)? {
+                    Type::List(item) => subject.accepts(&item),
+                    t => subject.accepts(&t),
+                };
+                if !fits {
+                    return Err(format!(
+                        "`match` case {} does not fit a {} subject",
+                        format_value(case),
+                        subject
+                    ));
+                }
+                let t = type_of(branch, env)?;
+                if !result.accepts(&t) {
+                    return Err(format!("`match` branches mix {} and {}", t, result));
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// The type of the body of a declared function called with `call`.
+/// The type of a recursive call, or one past `MAX_CALL_DEPTH` nested
+/// calls, is left to run time.
+fn call_type(call: &FunctionCall, env: &Env) -> Check<Type> {
+    let args = call
+        .args
+        .iter()
+        .map(|arg| type_of(arg, env))
+        .collect::<Check<Vec<Type>>>()?;
+    let recursive = env.calls.contains(&call.name.as_str());
+    let def = match env.functions.get(&call.name) {
+        Some(def) if !recursive && env.calls.len() < MAX_CALL_DEPTH => def,
+        _ => return Ok(Type::Any),
+    };
+    let calls: Vec<&str> = env
+        .calls
+        .iter()
+        .copied()
+        .chain(Some(def.name.as_str()))
+        .collect();
+    let inner = Env {
+        params: &args,
+        calls: &calls,
+        ..*env
+    };
+    match type_of(&def.body, &inner) {
+        Ok(t) => Ok(t),
+        Err(err) => {
+            // an error with arguments of any type is the body's own,
+            // it is reported where the function is declared
+            let alone = Env {
+                params: &[],
+                calls: &calls,
+                ..*env
+            };
+            match type_of(&def.body, &alone) {
+                Ok(_) => Err(format!("in `{}`: {}", call.name, err)),
+                Err(_) => Ok(Type::Any),
+            }
+        }
+    }
+}
+
+fn check_predicate(predicate: &Predicate, env: &Env) -> Check<()> {
+    let (left, right) = match predicate.operands() {
+        Some(operands) => operands,
+        None => return Ok(()),
+    };
+    let (lt, rt) = (type_of(left, env)?, type_of(right, env)?);
+    let text = format_predicate(predicate);
+    match predicate {
+        Predicate::In(_) | Predicate::NotIn(_) => {
+            let item = match &rt {
+                Type::List(item) => item.as_ref(),
+                t => t,
+            };
+            if item.accepts(&lt) {
+                Ok(())
+            } else {
+                Err(format!("`{}` looks for a {} in {}", text, lt, rt))
+            }
+        }
+        Predicate::Like(_)
+        | Predicate::ILike(_)
+        | Predicate::Match(_)
+        | Predicate::IMatch(_)
+        | Predicate::StartsWith(_) => match [&lt, &rt].iter().find(|t| !Type::String.accepts(t)) {
+            Some(t) => Err(format!("`{}` expects String, found {}", text, t)),
+            None => Ok(()),
+        },
+        _ if lt.accepts(&rt) => Ok(()),
+        _ => Err(format!("`{}` compares {} with {}", text, lt, rt)),
+    }
+}
+
+fn check_group(group: &PredGroup, errors: &mut Vec<String>, env: &Env) {
+    match group {
+        PredGroup::Empty => {}
+        PredGroup::Pred(predicate) => errors.extend(check_predicate(predicate, env).err()),
+        PredGroup::Not(inner) => check_group(inner, errors, env),
+        PredGroup::And { left, right } | PredGroup::Or { left, right } => {
+            check_group(left, errors, env);
+            check_group(right, errors, env);
+        }
+    }
+}
+
+/// Patterns are compiled once per rule, a malformed one fails the rule.
+fn check_patterns(group: &PredGroup) -> Check<()> {
+    Patterns::compile(group)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn expect(what: &str, expected: Type, value: &Value, env: &Env) -> Check<()> {
+    let found = type_of(value, env)?;
+    if expected.accepts(&found) {
+        Ok(())
+    } else {
+        Err(format!("{} expects {}, found {}", what, expected, found))
+    }
+}
+
+fn check_command(command: &Command, env: &Env) -> Check<()> {
+    match command {
+        Command::Clear(_) | Command::DrawGeometry(_) => Ok(()),
+        Command::Circle(c) => expect("`circle` radius", Type::Number, &c.radius, env),
+        Command::Square(s) => expect("`square` size", Type::Number, &s.size, env),
+        Command::Fill(f) => expect("`fill` color", Type::Color, &f.color, env),
+        Command::Stroke(s) => expect("`stroke` color", Type::Color, &s.color, env)
+            .and_then(|_| expect("`stroke` size", Type::Number, &s.size, env)),
+        Command::Pattern(p) => expect("`pattern` path", Type::String, &p.path, env),
+        Command::Text(t) => type_of(&t.content, env).map(|_| ()),
+    }
+}
+
+fn check_intent(intent: &Intent, env: &Env) -> Check<()> {
+    match intent {
+        Intent::Anchor(_) => Ok(()),
+        Intent::Text(t) => type_of(&t.content, env).map(|_| ()),
+        Intent::Size(s) => expect("`size`", Type::Number, &s.size, env),
+    }
+}
+
+fn check_directive(directive: &Directive, env: &Env) -> Vec<String> {
+    let mut errors = Vec::new();
+    match directive {
+        Directive::Data(data) => {
+            if let Constructor::Val(value) = data.constructor.as_ref() {
+                errors.extend(type_of(value, env).err());
+            }
+        }
+        Directive::Fn(def) => errors.extend(type_of(&def.body, env).err()),
+        Directive::Sym(sym) => {
+            check_group(&sym.predicate, &mut errors, env);
+            errors.extend(check_patterns(&sym.predicate).err());
+            errors.extend(
+                sym.consequent
+                    .iter()
+                    .filter_map(|c| check_command(c, env).err()),
+            );
+        }
+        Directive::Else(fallback) => {
+            errors.extend(
+                fallback
+                    .consequent
+                    .iter()
+                    .filter_map(|c| check_command(c, env).err()),
+            );
+        }
+        Directive::Label(label) => {
+            check_group(&label.predicate, &mut errors, env);
+            errors.extend(check_patterns(&label.predicate).err());
+            errors.extend(
+                label
+                    .consequent
+                    .iter()
+                    .filter_map(|i| check_intent(i, env).err()),
+            );
+        }
+        Directive::Include(include) => {
+            for inner in include.directives.iter() {
+                errors.extend(
+                    check_directive(inner, env)
+                        .into_iter()
+                        .map(|e| format!("in `{}`: {}", include.path, e)),
+                );
+            }
+        }
+        _ => {}
+    }
+    errors
+}
+
+fn check_block(directives: &[Directive], positions: &[usize], env: &Env) -> Vec<TypeError> {
+    directives
+        .iter()
+        .enumerate()
+        .flat_map(|(i, directive)| {
+            check_directive(directive, env)
+                .into_iter()
+                .map(move |message| TypeError {
+                    position: positions.get(i).copied(),
+                    message,
+                })
+        })
+        .collect()
+}
+
+/// Every type error of the map, in source order.
+pub fn check(spec: &MapSpec, registry: &FunctionRegistry) -> Vec<TypeError> {
+    let map_functions = UserFunctions::default().extend(&splice(&spec.map.directives));
+    let env = Env::new(registry, &map_functions);
+    let mut errors = check_block(&spec.map.directives, &spec.map.positions, &env);
+    for layer in spec.layers.iter() {
+        let functions = map_functions.extend(&splice(&layer.directives));
+        let env = Env::new(registry, &functions);
+        errors.extend(check_block(&layer.directives, &layer.positions, &env));
+    }
+    errors
+}
+
+#[cfg(test)]
+mod check_test {
+    use super::*;
+    use crate::error::ApplyResult;
+    use crate::function::Function;
+    use crate::map_source;
+    use parser::parse_str;
+
+    struct Area;
+
+    impl Function for Area {
+        fn call(&self, _args: Vec<Literal>) -> ApplyResult<Literal> {
+            Ok(Literal::from(1))
+        }
+
+        fn signature(&self) -> Signature {
+            Signature::fixed(vec![Type::Number], Type::Number)
+        }
+    }
+
+    fn errors(body: &str) -> Vec<String> {
+        let layer = format!(
+            "source geojson \"a\"\ndata name select \"name\" string\ndata size select \"size\" number\n{}\n",
+            body
+        );
+        let map_str = map_source("data blue rgb(0, 0, 255)\n", &[&layer]);
+        let spec = parse_str(&map_str).unwrap();
+        check(&spec, &FunctionRegistry::new())
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn well_typed() {
+        let body = "sym size >= 12 & name in (\"a\", \"b\") -> stroke blue size * 2\nsym name like \"a%\" -> fill \"#FF0000\"\nlabel size > 1 -> text concat(name, size) -> size 10\nsym max(size, 1) > 2 -> circle sqrt(size) / 10";
+        assert!(errors(body).is_empty());
+    }
+
+    #[test]
+    fn predicates() {
+        assert_eq!(
+            errors("sym size >= \"12\" -> fill blue"),
+            vec!["`size >= \"12\"` compares Number with String"]
+        );
+        assert_eq!(
+            errors("sym size in (\"a\", \"b\") -> fill blue"),
+            vec!["`size in (\"a\", \"b\")` looks for a Number in List of String"]
+        );
+        assert_eq!(
+            errors("sym size like \"1%\" -> fill blue"),
+            vec!["`size like \"1%\"` expects String, found Number"]
+        );
+        let found = errors("sym name ~ \"(unclosed\" -> fill blue");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("Pattern "));
+    }
+
+    #[test]
+    fn commands_and_functions() {
+        assert_eq!(
+            errors("sym true = true -> stroke 2 blue"),
+            vec!["`stroke` color expects Color, found Number"]
+        );
+        assert_eq!(
+            errors("sym true = true -> fill rgb(0, name, 0)"),
+            vec!["`rgb` argument 2 expects Number, found String"]
+        );
+        assert_eq!(
+            errors("sym true = true -> circle name + 1"),
+            vec!["`+` expects Number, found String in name"]
+        );
+        assert_eq!(
+            errors("data bad rgb(1, 2)\nsym true = true -> fill bad"),
+            vec!["`rgb` takes 3 arguments, got 2"]
+        );
+        assert_eq!(
+            errors("sym true = true -> fill match(name, \"park\", rgb(0, 255, 0), 12)"),
+            vec!["`match` branches mix Color and Number"]
+        );
+    }
+
+    #[test]
+    fn declared_functions() {
+        assert!(errors("fn twice(x) = x * 2\nsym twice(size) > 1 -> circle twice(1)").is_empty());
+        assert_eq!(
+            errors("fn bad(x) = name + x\nsym true = true -> circle bad(1)"),
+            vec!["`+` expects Number, found String in name"]
+        );
+        assert_eq!(
+            errors("fn twice(x) = x * 2\nsym true = true -> fill twice(1)"),
+            vec!["`fill` color expects Color, found Number"]
+        );
+        assert_eq!(
+            errors("fn label_of(x) = concat(x, \"!\")\nsym size > label_of(name) -> fill blue"),
+            vec!["`size > label_of(name)` compares Number with String"]
+        );
+        assert_eq!(
+            errors("fn twice(x) = x * 2\nsym true = true -> circle twice(name)"),
+            vec!["in `twice`: `*` expects Number, found String in x"]
+        );
+        assert!(
+            errors("fn deep(x) = deep(x) + deep(x)\nsym true = true -> circle deep(1)").is_empty()
+        );
+    }
+
+    #[test]
+    fn registered_functions() {
+        assert_eq!(
+            errors("sym true = true -> circle area(1)"),
+            vec!["unknown function `area`"]
+        );
+        let map_str = map_source(
+            "",
+            &["source geojson \"a\"\nsym true = true -> circle area(\"x\")\n"],
+        );
+        let spec = parse_str(&map_str).unwrap();
+        let mut registry = FunctionRegistry::new();
+        registry.register("area", Box::new(Area));
+        let found: Vec<String> = check(&spec, &registry)
+            .into_iter()
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(
+            found,
+            vec!["`area` argument 1 expects Number, found String"]
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let map_str = map_source(
+            "",
+            &["source geojson \"a\"\nsym 1 = \"1\" -> fill \"red\"\n"],
+        );
+        let spec = parse_str(&map_str).unwrap();
+        let found = check(&spec, &FunctionRegistry::new());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].position, map_str.find("sym"));
+    }
+}
@@ -0,0 +1,342 @@
+//! Colours at run time.
+//!
+//! A colour travels as a hex string, `#RRGGBB` when it is opaque and
+//! `#RRGGBBAA` otherwise, which is what the renderer reads. `Color`
+//! parses the hex forms and the CSS named colours.
+
+use std::fmt;
+
+/// Red, green and blue from 0 to 255, alpha from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: f64,
+}
+
+/// Channels out of range are clamped, fractions are rounded.
+fn channel(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+fn hex_digits(digits: &str) -> Option<Vec<u8>> {
+    if !digits.is_ascii() {
+        return None;
+    }
+    match digits.len() {
+        3 | 4 => digits
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| (d * 17) as u8))
+            .collect(),
+        6 | 8 => (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+            .collect(),
+        _ => None,
+    }
+}
+
+impl Color {
+    pub fn rgba(red: f64, green: f64, blue: f64, alpha: f64) -> Self {
+        Color {
+            red: channel(red),
+            green: channel(green),
+            blue: channel(blue),
+            alpha: alpha.clamp(0.0, 1.0),
+        }
+    }
+
+    /// `hue` in degrees, `saturation` and `lightness` from 0 to 1.
+    pub fn hsla(hue: f64, saturation: f64, lightness: f64, alpha: f64) -> Self {
+        let hue = hue.rem_euclid(360.0) / 60.0;
+        let s = saturation.clamp(0.0, 1.0);
+        let l = lightness.clamp(0.0, 1.0);
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u8 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = l - chroma / 2.0;
+        Color::rgba((r + m) * 255.0, (g + m) * 255.0, (b + m) * 255.0, alpha)
+    }
+
+    /// Hue in degrees, saturation and lightness from 0 to 1.
+    pub fn hsl(&self) -> (f64, f64, f64) {
+        let [r, g, b] = [self.red, self.green, self.blue].map(|c| f64::from(c) / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let l = (max + min) / 2.0;
+        let delta = max - min;
+        if delta == 0.0 {
+            return (0.0, 0.0, l);
+        }
+        let s = delta / (1.0 - (2.0 * l - 1.0).abs());
+        let h = if max == r {
+            ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            (b - r) / delta + 2.0
+        } else {
+            (r - g) / delta + 4.0
+        };
+        (h * 60.0, s, l)
+    }
+
+    /// `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, a CSS name or `transparent`,
+    /// regardless of case.
+    pub fn parse(color: &str) -> Option<Self> {
+        let color = color.trim().to_lowercase();
+        if let Some(digits) = color.strip_prefix('#') {
+            return hex_digits(digits).map(|c| Color {
+                red: c[0],
+                green: c[1],
+                blue: c[2],
+                alpha: c.get(3).map_or(1.0, |a| f64::from(*a) / 255.0),
+            });
+        }
+        if color == "transparent" {
+            return Some(Color::rgba(0.0, 0.0, 0.0, 0.0));
+        }
+        NAMED_COLORS
+            .iter()
+            .find(|(name, _)| *name == color)
+            .map(|(_, [red, green, blue])| Color {
+                red: *red,
+                green: *green,
+                blue: *blue,
+                alpha: 1.0,
+            })
+    }
+
+    /// `weight` of `other`, from 0 to 1, alpha included.
+    pub fn mix(&self, other: &Color, weight: f64) -> Self {
+        let w = weight.clamp(0.0, 1.0);
+        let blend = |a: u8, b: u8| f64::from(a) * (1.0 - w) + f64::from(b) * w;
+        Color::rgba(
+            blend(self.red, other.red),
+            blend(self.green, other.green),
+            blend(self.blue, other.blue),
+            self.alpha * (1.0 - w) + other.alpha * w,
+        )
+    }
+
+    pub fn with_alpha(&self, alpha: f64) -> Self {
+        Color {
+            alpha: alpha.clamp(0.0, 1.0),
+            ..*self
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.red, self.green, self.blue)?;
+        if self.alpha < 1.0 {
+            write!(f, "{:02X}", channel(self.alpha * 255.0))?;
+        }
+        Ok(())
+    }
+}
+
+/// The CSS named colours.
+const NAMED_COLORS: [(&str, [u8; 3]); 148] = [
+    ("aliceblue", [240, 248, 255]),
+    ("antiquewhite", [250, 235, 215]),
+    ("aqua", [0, 255, 255]),
+    ("aquamarine", [127, 255, 212]),
+    ("azure", [240, 255, 255]),
+    ("beige", [245, 245, 220]),
+    ("bisque", [255, 228, 196]),
+    ("black", [0, 0, 0]),
+    ("blanchedalmond", [255, 235, 205]),
+    ("blue", [0, 0, 255]),
+    ("blueviolet", [138, 43, 226]),
+    ("brown", [165, 42, 42]),
+    ("burlywood", [222, 184, 135]),
+    ("cadetblue", [95, 158, 160]),
+    ("chartreuse", [127, 255, 0]),
+    ("chocolate", [210, 105, 30]),
+    ("coral", [255, 127, 80]),
+    ("cornflowerblue", [100, 149, 237]),
+    ("cornsilk", [255, 248, 220]),
+    ("crimson", [220, 20, 60]),
+    ("cyan", [0, 255, 255]),
+    ("darkblue", [0, 0, 139]),
+    ("darkcyan", [0, 139, 139]),
+    ("darkgoldenrod", [184, 134, 11]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgreen", [0, 100, 0]),
+    ("darkgrey", [169, 169, 169]),
+    ("darkkhaki", [189, 183, 107]),
+    ("darkmagenta", [139, 0, 139]),
+    ("darkolivegreen", [85, 107, 47]),
+    ("darkorange", [255, 140, 0]),
+    ("darkorchid", [153, 50, 204]),
+    ("darkred", [139, 0, 0]),
+    ("darksalmon", [233, 150, 122]),
+    ("darkseagreen", [143, 188, 143]),
+    ("darkslateblue", [72, 61, 139]),
+    ("darkslategray", [47, 79, 79]),
+    ("darkslategrey", [47, 79, 79]),
+    ("darkturquoise", [0, 206, 209]),
+    ("darkviolet", [148, 0, 211]),
+    ("deeppink", [255, 20, 147]),
+    ("deepskyblue", [0, 191, 255]),
+    ("dimgray", [105, 105, 105]),
+    ("dimgrey", [105, 105, 105]),
+    ("dodgerblue", [30, 144, 255]),
+    ("firebrick", [178, 34, 34]),
+    ("floralwhite", [255, 250, 240]),
+    ("forestgreen", [34, 139, 34]),
+    ("fuchsia", [255, 0, 255]),
+    ("gainsboro", [220, 220, 220]),
+    ("ghostwhite", [248, 248, 255]),
+    ("gold", [255, 215, 0]),
+    ("goldenrod", [218, 165, 32]),
+    ("gray", [128, 128, 128]),
+    ("green", [0, 128, 0]),
+    ("greenyellow", [173, 255, 47]),
+    ("grey", [128, 128, 128]),
+    ("honeydew", [240, 255, 240]),
+    ("hotpink", [255, 105, 180]),
+    ("indianred", [205, 92, 92]),
+    ("indigo", [75, 0, 130]),
+    ("ivory", [255, 255, 240]),
+    ("khaki", [240, 230, 140]),
+    ("lavender", [230, 230, 250]),
+    ("lavenderblush", [255, 240, 245]),
+    ("lawngreen", [124, 252, 0]),
+    ("lemonchiffon", [255, 250, 205]),
+    ("lightblue", [173, 216, 230]),
+    ("lightcoral", [240, 128, 128]),
+    ("lightcyan", [224, 255, 255]),
+    ("lightgoldenrodyellow", [250, 250, 210]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgreen", [144, 238, 144]),
+    ("lightgrey", [211, 211, 211]),
+    ("lightpink", [255, 182, 193]),
+    ("lightsalmon", [255, 160, 122]),
+    ("lightseagreen", [32, 178, 170]),
+    ("lightskyblue", [135, 206, 250]),
+    ("lightslategray", [119, 136, 153]),
+    ("lightslategrey", [119, 136, 153]),
+    ("lightsteelblue", [176, 196, 222]),
+    ("lightyellow", [255, 255, 224]),
+    ("lime", [0, 255, 0]),
+    ("limegreen", [50, 205, 50]),
+    ("linen", [250, 240, 230]),
+    ("magenta", [255, 0, 255]),
+    ("maroon", [128, 0, 0]),
+    ("mediumaquamarine", [102, 205, 170]),
+    ("mediumblue", [0, 0, 205]),
+    ("mediumorchid", [186, 85, 211]),
+    ("mediumpurple", [147, 112, 219]),
+    ("mediumseagreen", [60, 179, 113]),
+    ("mediumslateblue", [123, 104, 238]),
+    ("mediumspringgreen", [0, 250, 154]),
+    ("mediumturquoise", [72, 209, 204]),
+    ("mediumvioletred", [199, 21, 133]),
+    ("midnightblue", [25, 25, 112]),
+    ("mintcream", [245, 255, 250]),
+    ("mistyrose", [255, 228, 225]),
+    ("moccasin", [255, 228, 181]),
+    ("navajowhite", [255, 222, 173]),
+    ("navy", [0, 0, 128]),
+    ("oldlace", [253, 245, 230]),
+    ("olive", [128, 128, 0]),
+    ("olivedrab", [107, 142, 35]),
+    ("orange", [255, 165, 0]),
+    ("orangered", [255, 69, 0]),
+    ("orchid", [218, 112, 214]),
+    ("palegoldenrod", [238, 232, 170]),
+    ("palegreen", [152, 251, 152]),
+    ("paleturquoise", [175, 238, 238]),
+    ("palevioletred", [219, 112, 147]),
+    ("papayawhip", [255, 239, 213]),
+    ("peachpuff", [255, 218, 185]),
+    ("peru", [205, 133, 63]),
+    ("pink", [255, 192, 203]),
+    ("plum", [221, 160, 221]),
+    ("powderblue", [176, 224, 230]),
+    ("purple", [128, 0, 128]),
+    ("rebeccapurple", [102, 51, 153]),
+    ("red", [255, 0, 0]),
+    ("rosybrown", [188, 143, 143]),
+    ("royalblue", [65, 105, 225]),
+    ("saddlebrown", [139, 69, 19]),
+    ("salmon", [250, 128, 114]),
+    ("sandybrown", [244, 164, 96]),
+    ("seagreen", [46, 139, 87]),
+    ("seashell", [255, 245, 238]),
+    ("sienna", [160, 82, 45]),
+    ("silver", [192, 192, 192]),
+    ("skyblue", [135, 206, 235]),
+    ("slateblue", [106, 90, 205]),
+    ("slategray", [112, 128, 144]),
+    ("slategrey", [112, 128, 144]),
+    ("snow", [255, 250, 250]),
+    ("springgreen", [0, 255, 127]),
+    ("steelblue", [70, 130, 180]),
+    ("tan", [210, 180, 140]),
+    ("teal", [0, 128, 128]),
+    ("thistle", [216, 191, 216]),
+    ("tomato", [255, 99, 71]),
+    ("turquoise", [64, 224, 208]),
+    ("violet", [238, 130, 238]),
+    ("wheat", [245, 222, 179]),
+    ("white", [255, 255, 255]),
+    ("whitesmoke", [245, 245, 245]),
+    ("yellow", [255, 255, 0]),
+    ("yellowgreen", [154, 205, 50]),
+];
+
+#[cfg(test)]
+mod color_test {
+    use super::*;
+
+    fn hex(color: &str) -> String {
+        Color::parse(color)
+            .map(|c| c.to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(hex("#f00"), "#FF0000");
+        assert_eq!(hex("#F008"), "#FF000088");
+        assert_eq!(hex("#00ff0080"), "#00FF0080");
+        assert_eq!(hex("#00FF00ff"), "#00FF00");
+        assert_eq!(hex("RebeccaPurple"), "#663399");
+        assert_eq!(hex("transparent"), "#00000000");
+        assert_eq!(hex("#12345"), "");
+        assert_eq!(hex("#ééé"), "");
+        assert_eq!(hex("reddish"), "");
+    }
+
+    #[test]
+    fn hsl() {
+        assert_eq!(Color::hsla(0.0, 1.0, 0.5, 1.0).to_string(), "#FF0000");
+        assert_eq!(Color::hsla(480.0, 1.0, 0.25, 1.0).to_string(), "#008000");
+        assert_eq!(Color::hsla(210.0, 0.5, 0.6, 0.5).to_string(), "#6699CC80");
+        let (h, s, l) = Color::parse("#6699cc").unwrap().hsl();
+        assert_eq!(
+            (h.round(), (s * 100.0).round(), (l * 100.0).round()),
+            (210.0, 50.0, 60.0)
+        );
+        assert_eq!(Color::parse("grey").unwrap().hsl().0, 0.0);
+    }
+
+    #[test]
+    fn mix() {
+        let red = Color::parse("red").unwrap();
+        let blue = Color::parse("#0000FF00").unwrap();
+        assert_eq!(red.mix(&blue, 0.5).to_string(), "#80008080");
+        assert_eq!(red.mix(&blue, 0.0), red);
+        assert_eq!(red.with_alpha(0.25).to_string(), "#FF000040");
+    }
+}
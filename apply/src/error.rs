@@ -3,6 +3,8 @@ pub enum ApplyError {
     FunctionNotFound(String),
     FunctionArg(String),
     FunctionFail(String),
+    Arithmetic(String),
+    Pattern(String),
     Sym(String),
     Resolve(String),
     SourceInit(String),
@@ -15,6 +17,7 @@ pub enum ApplyError {
     MissingExtent,
     Wrapped(Box<dyn std::error::Error>),
     NotAFeatureCollection(String),
+    Layer(String, Box<ApplyError>),
 }
 
 impl<E> From<E> for ApplyError
@@ -32,6 +35,8 @@ impl std::fmt::Display for ApplyError {
             ApplyError::FunctionNotFound(desc) => write!(f, "FunctionNotFound {}", desc),
             ApplyError::FunctionArg(desc) => write!(f, "FunctionArg {}", desc),
             ApplyError::FunctionFail(desc) => write!(f, "FunctionFail {}", desc),
+            ApplyError::Arithmetic(desc) => write!(f, "Arithmetic {}", desc),
+            ApplyError::Pattern(desc) => write!(f, "Pattern {}", desc),
             ApplyError::Sym(desc) => write!(f, "Sym {}", desc),
             ApplyError::Resolve(desc) => write!(f, "Resolve {}", desc),
             ApplyError::SourceInit(desc) => write!(f, "SourceInit {}", desc),
@@ -44,6 +49,7 @@ impl std::fmt::Display for ApplyError {
             ApplyError::MissingExtent => write!(f, "Missing extent in map block"),
             ApplyError::Wrapped(err) => write!(f, "Other -> {}", err),
             ApplyError::NotAFeatureCollection(desc) => write!(f, "NotAFeatureCollection {}", desc),
+            ApplyError::Layer(name, err) => write!(f, "Layer {} -> {}", name, err),
         }
     }
 }
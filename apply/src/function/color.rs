@@ -0,0 +1,234 @@
+//! Colour functions, they return the hex strings of `crate::color`.
+//!
+//! Channels go from 0 to 255 and are clamped, saturation, lightness and
+//! the amounts of `lighten`, `darken` and `saturate` are percentages,
+//! alpha and the weight of `mix` go from 0 to 1, like `opacity`.
+
+use parser::ast::Literal;
+
+use crate::check::{Signature, Type};
+use crate::color::Color;
+use crate::error::ApplyResult;
+
+use super::{arg_error, arity, number_arg, numbers, Function};
+
+fn color_arg(name: &str, args: &[Literal], i: usize) -> ApplyResult<Color> {
+    match args.get(i) {
+        Some(Literal::String(s)) => Color::parse(s)
+            .ok_or_else(|| arg_error(name, format!("argument {} is not a colour: {:?}", i + 1, s))),
+        Some(other) => Err(arg_error(
+            name,
+            format!("argument {} expects a colour, got {}", i + 1, other),
+        )),
+        None => Err(arg_error(name, format!("misses argument {}", i + 1))),
+    }
+}
+
+fn floats(name: &str, args: &[Literal], n: usize) -> ApplyResult<Vec<f64>> {
+    numbers(name, args, n).map(|n| n.iter().map(|n| n.as_float()).collect())
+}
+
+fn color(color: Color) -> ApplyResult<Literal> {
+    Ok(Literal::String(color.to_string()))
+}
+
+/// A colour changed by a number.
+fn adjust(name: &str, args: &[Literal], f: impl Fn(Color, f64) -> Color) -> ApplyResult<Literal> {
+    arity(name, args, 2)?;
+    let c = color_arg(name, args, 0)?;
+    let amount = number_arg(name, args, 1)?.as_float();
+    color(f(c, amount))
+}
+
+/// A colour with its lightness shifted by `points` percent.
+fn shift_lightness(c: Color, points: f64) -> Color {
+    let (h, s, l) = c.hsl();
+    Color::hsla(h, s, l + points / 100.0, c.alpha)
+}
+
+pub struct Rgb;
+
+impl Function for Rgb {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let c = floats("rgb", &args, 3)?;
+        color(Color::rgba(c[0], c[1], c[2], 1.0))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number; 3], Type::Color)
+    }
+}
+
+pub struct Rgba;
+
+impl Function for Rgba {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let c = floats("rgba", &args, 4)?;
+        color(Color::rgba(c[0], c[1], c[2], c[3]))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number; 4], Type::Color)
+    }
+}
+
+/// `hsl(hue, saturation, lightness)`, the hue in degrees.
+pub struct Hsl;
+
+impl Function for Hsl {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let c = floats("hsl", &args, 3)?;
+        color(Color::hsla(c[0], c[1] / 100.0, c[2] / 100.0, 1.0))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number; 3], Type::Color)
+    }
+}
+
+pub struct Hsla;
+
+impl Function for Hsla {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let c = floats("hsla", &args, 4)?;
+        color(Color::hsla(c[0], c[1] / 100.0, c[2] / 100.0, c[3]))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number; 4], Type::Color)
+    }
+}
+
+pub struct Lighten;
+
+impl Function for Lighten {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        adjust("lighten", &args, shift_lightness)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Color, Type::Number], Type::Color)
+    }
+}
+
+pub struct Darken;
+
+impl Function for Darken {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        adjust("darken", &args, |c, points| shift_lightness(c, -points))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Color, Type::Number], Type::Color)
+    }
+}
+
+/// A negative amount desaturates.
+pub struct Saturate;
+
+impl Function for Saturate {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        adjust("saturate", &args, |c, points| {
+            let (h, s, l) = c.hsl();
+            Color::hsla(h, s + points / 100.0, l, c.alpha)
+        })
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Color, Type::Number], Type::Color)
+    }
+}
+
+/// `mix(a, b, weight)` goes from `a` at 0 to `b` at 1.
+pub struct Mix;
+
+impl Function for Mix {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "mix";
+        arity(name, &args, 3)?;
+        let a = color_arg(name, &args, 0)?;
+        let b = color_arg(name, &args, 1)?;
+        let weight = number_arg(name, &args, 2)?.as_float();
+        color(a.mix(&b, weight))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Color, Type::Color, Type::Number], Type::Color)
+    }
+}
+
+/// `fade(color, alpha)` replaces the alpha of a colour.
+pub struct Fade;
+
+impl Function for Fade {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        adjust("fade", &args, |c, alpha| c.with_alpha(alpha))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Color, Type::Number], Type::Color)
+    }
+}
+
+#[cfg(test)]
+mod color_test {
+    use super::*;
+    use crate::error::ApplyError;
+
+    fn call(f: &dyn Function, args: Vec<Literal>) -> String {
+        match f.call(args) {
+            Ok(Literal::String(s)) => s,
+            Ok(other) => panic!("unexpected {:?}", other),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn n(value: f64) -> Literal {
+        Literal::from(value)
+    }
+
+    fn s(value: &str) -> Literal {
+        Literal::from(value)
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(call(&Rgb, vec![n(255.0), n(127.6), n(0.0)]), "#FF8000");
+        assert_eq!(call(&Rgb, vec![n(300.0), n(-4.0), n(16.0)]), "#FF0010");
+        assert_eq!(
+            call(&Rgba, vec![n(255.0), n(0.0), n(0.0), n(0.5)]),
+            "#FF000080"
+        );
+        assert_eq!(call(&Hsl, vec![n(120.0), n(100.0), n(25.0)]), "#008000");
+        assert_eq!(
+            call(&Hsla, vec![n(240.0), n(100.0), n(50.0), n(0.0)]),
+            "#0000FF00"
+        );
+    }
+
+    #[test]
+    fn manipulation() {
+        assert_eq!(call(&Lighten, vec![s("#008000"), n(25.0)]), "#00FF00");
+        assert_eq!(call(&Darken, vec![s("red"), n(50.0)]), "#000000");
+        assert_eq!(call(&Darken, vec![s("#FF000080"), n(25.0)]), "#80000080");
+        assert_eq!(call(&Saturate, vec![s("#6699CC"), n(-50.0)]), "#999999");
+        assert_eq!(call(&Mix, vec![s("white"), s("black"), n(0.25)]), "#BFBFBF");
+        assert_eq!(call(&Fade, vec![s("navy"), n(0.5)]), "#00008080");
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(matches!(
+            Fade.call(vec![s("nope"), n(0.5)]),
+            Err(ApplyError::FunctionArg(_))
+        ));
+        assert_eq!(
+            call(&Lighten, vec![n(1.0), n(1.0)]),
+            "FunctionArg `lighten` argument 1 expects a colour, got 1"
+        );
+        assert_eq!(
+            call(&Rgb, vec![n(1.0), s("2"), n(3.0)]),
+            "FunctionArg `rgb` argument 2 expects a number, got 2"
+        );
+    }
+}
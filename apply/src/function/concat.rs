@@ -1,5 +1,6 @@
 use parser::ast::{Literal, Num};
 
+use crate::check::{Signature, Type};
 use crate::error::ApplyResult;
 
 use super::Function;
@@ -12,4 +13,8 @@ impl Function for Concat {
             args.iter().map(|v| format!("{}", v)).collect(),
         ))
     }
+
+    fn signature(&self) -> Signature {
+        Signature::variadic(Type::Any, Type::String)
+    }
 }
@@ -0,0 +1,387 @@
+//! Math functions.
+//!
+//! Promotion rules, in line with `arith`:
+//! - `abs`, `min`, `max` and `clamp` keep `Integer` arguments `Integer`,
+//!   one `Float` argument makes the result a `Float`.
+//! - `round`, `floor` and `ceil` give an `Integer`, the `Integer` they get
+//!   is returned as is.
+//! - `pow` of two `Integer` with a non negative exponent is an `Integer`,
+//!   an overflow is an error rather than a silent wrap.
+//! - `sqrt`, `log`, `log10`, `exp`, `scale_linear` and any other `pow`
+//!   always give a `Float`.
+//!
+//! NaN and infinite arguments are rejected with `FunctionArg`, a NaN
+//! or infinite result, like `sqrt(-1)` or `log(0)`, is a `FunctionFail`.
+
+use std::convert::TryFrom;
+
+use parser::ast::{Literal, Num};
+
+use crate::check::{Signature, Type};
+use crate::error::{ApplyError, ApplyResult};
+
+use super::{arg_error, number_arg, numbers, Function};
+
+/// A `Float` result, unless it is NaN or infinite.
+fn finite(name: &str, result: f64) -> ApplyResult<Literal> {
+    if result.is_finite() {
+        Ok(Literal::Number(Num::Float(result)))
+    } else {
+        Err(ApplyError::FunctionFail(format!(
+            "`{}` has no finite result for these arguments",
+            name
+        )))
+    }
+}
+
+/// A function of one number that always gives a `Float`.
+fn float(name: &str, args: &[Literal], f: impl Fn(f64) -> f64) -> ApplyResult<Literal> {
+    let x = numbers(name, args, 1)?[0].as_float();
+    finite(name, f(x))
+}
+
+/// `round`, `floor` and `ceil` to an `Integer`.
+fn integral(name: &str, args: &[Literal], f: impl Fn(f64) -> f64) -> ApplyResult<Literal> {
+    match numbers(name, args, 1)?[0] {
+        Num::Integer(n) => Ok(Literal::Number(Num::Integer(n))),
+        Num::Float(x) => {
+            let n = f(x);
+            if n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                Ok(Literal::Number(Num::Integer(n as i64)))
+            } else {
+                Err(ApplyError::FunctionFail(format!(
+                    "`{}({})` does not fit an integer",
+                    name, x
+                )))
+            }
+        }
+    }
+}
+
+/// The smallest or largest of at least one number.
+fn extremum(name: &str, args: &[Literal], keep_left: fn(f64, f64) -> bool) -> ApplyResult<Literal> {
+    if args.is_empty() {
+        return Err(arg_error(name, String::from("takes at least 1 argument")));
+    }
+    let mut best = number_arg(name, args, 0)?;
+    for i in 1..args.len() {
+        let n = number_arg(name, args, i)?;
+        if !keep_left(best.as_float(), n.as_float()) {
+            best = n;
+        }
+    }
+    let promoted = args
+        .iter()
+        .any(|a| matches!(a, Literal::Number(Num::Float(_))));
+    match (best, promoted) {
+        (Num::Integer(n), true) => Ok(Literal::Number(Num::Float(n as f64))),
+        (best, _) => Ok(Literal::Number(best)),
+    }
+}
+
+pub struct Abs;
+
+impl Function for Abs {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        match numbers("abs", &args, 1)?[0] {
+            Num::Integer(n) => n
+                .checked_abs()
+                .map(|n| Literal::Number(Num::Integer(n)))
+                .ok_or_else(|| ApplyError::FunctionFail(format!("`abs({})` overflows", n))),
+            Num::Float(f) => Ok(Literal::Number(Num::Float(f.abs()))),
+        }
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Min;
+
+impl Function for Min {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        extremum("min", &args, |best, n| best <= n)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::variadic(Type::Number, Type::Number)
+    }
+}
+
+pub struct Max;
+
+impl Function for Max {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        extremum("max", &args, |best, n| best >= n)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::variadic(Type::Number, Type::Number)
+    }
+}
+
+/// Halfway cases round away from zero.
+pub struct Round;
+
+impl Function for Round {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        integral("round", &args, f64::round)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Floor;
+
+impl Function for Floor {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        integral("floor", &args, f64::floor)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Ceil;
+
+impl Function for Ceil {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        integral("ceil", &args, f64::ceil)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Sqrt;
+
+impl Function for Sqrt {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        float("sqrt", &args, f64::sqrt)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+/// The natural logarithm.
+pub struct Log;
+
+impl Function for Log {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        float("log", &args, f64::ln)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Log10;
+
+impl Function for Log10 {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        float("log10", &args, f64::log10)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+pub struct Exp;
+
+impl Function for Exp {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        float("exp", &args, f64::exp)
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number], Type::Number)
+    }
+}
+
+/// `pow(base, exponent)`
+pub struct Pow;
+
+impl Function for Pow {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "pow";
+        let n = numbers(name, &args, 2)?;
+        match (&n[0], &n[1]) {
+            (Num::Integer(base), Num::Integer(exp)) if *exp >= 0 => u32::try_from(*exp)
+                .ok()
+                .and_then(|exp| base.checked_pow(exp))
+                .map(|n| Literal::Number(Num::Integer(n)))
+                .ok_or_else(|| {
+                    ApplyError::FunctionFail(format!("`pow({}, {})` overflows", base, exp))
+                }),
+            (base, exp) => finite(name, base.as_float().powf(exp.as_float())),
+        }
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number, Type::Number], Type::Number)
+    }
+}
+
+/// `clamp(value, low, high)`
+pub struct Clamp;
+
+impl Function for Clamp {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "clamp";
+        let n = numbers(name, &args, 3)?;
+        let (value, low, high) = (&n[0], &n[1], &n[2]);
+        // large integers can be equal as floats and still out of order
+        let reversed = match (low, high) {
+            (Num::Integer(l), Num::Integer(h)) => l > h,
+            _ => low.as_float() > high.as_float(),
+        };
+        if reversed {
+            return Err(arg_error(
+                name,
+                format!("low bound {} is above high bound {}", low, high),
+            ));
+        }
+        match (value, low, high) {
+            (Num::Integer(v), Num::Integer(l), Num::Integer(h)) => {
+                Ok(Literal::Number(Num::Integer((*v).clamp(*l, *h))))
+            }
+            _ => Ok(Literal::Number(Num::Float(
+                value.as_float().clamp(low.as_float(), high.as_float()),
+            ))),
+        }
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number, Type::Number, Type::Number], Type::Number)
+    }
+}
+
+/// `scale_linear(value, d0, d1, r0, r1)` maps the domain `d0..d1`
+/// onto the range `r0..r1`, values outside the domain are not clamped.
+pub struct ScaleLinear;
+
+impl Function for ScaleLinear {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "scale_linear";
+        let n: Vec<f64> = numbers(name, &args, 5)?.iter().map(Num::as_float).collect();
+        let (value, d0, d1, r0, r1) = (n[0], n[1], n[2], n[3], n[4]);
+        if d0 == d1 {
+            return Err(arg_error(name, format!("domain {}..{} is empty", d0, d1)));
+        }
+        finite(name, r0 + (value - d0) * (r1 - r0) / (d1 - d0))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::Number; 5], Type::Number)
+    }
+}
+
+#[cfg(test)]
+mod math_test {
+    use super::*;
+
+    fn int(n: i64) -> Literal {
+        Literal::Number(Num::Integer(n))
+    }
+
+    fn float(n: f64) -> Literal {
+        Literal::Number(Num::Float(n))
+    }
+
+    #[test]
+    fn promotion() {
+        assert_eq!(Abs.call(vec![int(-3)]).unwrap(), int(3));
+        assert_eq!(Abs.call(vec![float(-0.5)]).unwrap(), float(0.5));
+        assert_eq!(Min.call(vec![int(3), int(1), int(2)]).unwrap(), int(1));
+        assert_eq!(Max.call(vec![int(3), float(1.5)]).unwrap(), float(3.0));
+        assert_eq!(Round.call(vec![float(2.5)]).unwrap(), int(3));
+        assert_eq!(Round.call(vec![float(-2.5)]).unwrap(), int(-3));
+        assert_eq!(Floor.call(vec![float(-1.5)]).unwrap(), int(-2));
+        assert_eq!(Ceil.call(vec![int(7)]).unwrap(), int(7));
+        assert_eq!(Pow.call(vec![int(2), int(10)]).unwrap(), int(1024));
+        assert_eq!(Pow.call(vec![int(2), int(-1)]).unwrap(), float(0.5));
+        assert_eq!(Sqrt.call(vec![int(16)]).unwrap(), float(4.0));
+        assert_eq!(Log10.call(vec![int(1000)]).unwrap(), float(3.0));
+        assert_eq!(Clamp.call(vec![int(12), int(0), int(10)]).unwrap(), int(10));
+        assert_eq!(
+            Clamp.call(vec![float(-1.0), int(0), int(10)]).unwrap(),
+            float(0.0)
+        );
+        assert_eq!(
+            ScaleLinear
+                .call(vec![int(150), int(100), int(200), int(2), int(10)])
+                .unwrap(),
+            float(6.0)
+        );
+    }
+
+    #[test]
+    fn failures() {
+        assert!(matches!(
+            Sqrt.call(vec![int(-1)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        assert!(matches!(
+            Log.call(vec![int(0)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        assert!(matches!(
+            Exp.call(vec![int(1000)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        assert!(matches!(
+            Pow.call(vec![int(10), int(19)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        assert!(matches!(
+            Abs.call(vec![int(i64::MIN)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        assert!(matches!(
+            Round.call(vec![float(1e20)]),
+            Err(ApplyError::FunctionFail(_))
+        ));
+        let message = |result: ApplyResult<Literal>| match result {
+            Err(ApplyError::FunctionArg(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            message(Abs.call(vec![float(f64::NAN)])),
+            "`abs` argument 1 is not a number"
+        );
+        assert_eq!(
+            message(Max.call(vec![int(1), float(f64::INFINITY)])),
+            "`max` argument 2 is infinite"
+        );
+        assert_eq!(message(Min.call(vec![])), "`min` takes at least 1 argument");
+        assert_eq!(
+            message(Sqrt.call(vec![Literal::from("4")])),
+            "`sqrt` argument 1 expects a number, got 4"
+        );
+        assert_eq!(
+            message(Clamp.call(vec![int(1), int(2), int(0)])),
+            "`clamp` low bound 2 is above high bound 0"
+        );
+        assert_eq!(
+            message(Clamp.call(vec![
+                int(0),
+                int(4611686018427387905),
+                int(4611686018427387904)
+            ])),
+            "`clamp` low bound 4611686018427387905 is above high bound 4611686018427387904"
+        );
+        assert_eq!(
+            message(ScaleLinear.call(vec![int(1), int(2), int(2), int(0), int(1)])),
+            "`scale_linear` domain 2..2 is empty"
+        );
+    }
+}
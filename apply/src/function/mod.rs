@@ -1,18 +1,172 @@
-use parser::ast::Literal;
+use std::{collections::HashMap, rc::Rc};
 
+use parser::ast::{Literal, Num};
+
+use crate::check::Signature;
 use crate::error::{ApplyError, ApplyResult};
 
+mod color;
 mod concat;
-mod rgb;
+mod math;
+mod string;
+pub mod user;
 
 pub trait Function {
     fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal>;
+    /// Arity and types, used by the checker before anything runs.
+    fn signature(&self) -> Signature;
+}
+
+pub(crate) fn arg_error(name: &str, message: String) -> ApplyError {
+    ApplyError::FunctionArg(format!("`{}` {}", name, message))
+}
+
+pub(crate) fn arity(name: &str, args: &[Literal], n: usize) -> ApplyResult<()> {
+    if args.len() == n {
+        Ok(())
+    } else {
+        Err(arg_error(
+            name,
+            format!("takes {} arguments, got {}", n, args.len()),
+        ))
+    }
+}
+
+/// A finite number, NaN and infinity are rejected.
+pub(crate) fn number_arg(name: &str, args: &[Literal], i: usize) -> ApplyResult<Num> {
+    match args.get(i) {
+        Some(Literal::Number(Num::Float(f))) if f.is_nan() => Err(arg_error(
+            name,
+            format!("argument {} is not a number", i + 1),
+        )),
+        Some(Literal::Number(Num::Float(f))) if f.is_infinite() => {
+            Err(arg_error(name, format!("argument {} is infinite", i + 1)))
+        }
+        Some(Literal::Number(n)) => Ok(n.clone()),
+        Some(other) => Err(arg_error(
+            name,
+            format!("argument {} expects a number, got {}", i + 1, other),
+        )),
+        None => Err(arg_error(name, format!("misses argument {}", i + 1))),
+    }
+}
+
+/// Exactly `n` arguments, all numbers.
+pub(crate) fn numbers(name: &str, args: &[Literal], n: usize) -> ApplyResult<Vec<Num>> {
+    arity(name, args, n)?;
+    (0..n).map(|i| number_arg(name, args, i)).collect()
+}
+
+/// The Rust functions a map can call by name, the built-ins and
+/// whatever the application registers. Clones share the functions.
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    functions: Rc<HashMap<String, Rc<dyn Function>>>,
+}
+
+impl FunctionRegistry {
+    /// A registry holding the built-in functions.
+    pub fn new() -> Self {
+        let mut registry = FunctionRegistry {
+            functions: Rc::new(HashMap::new()),
+        };
+        registry.register("concat", Box::new(concat::Concat));
+        registry.register("lowercase", Box::new(string::Lowercase));
+        registry.register("uppercase", Box::new(string::Uppercase));
+        registry.register("trim", Box::new(string::Trim));
+        registry.register("substr", Box::new(string::Substr));
+        registry.register("replace", Box::new(string::Replace));
+        registry.register("length", Box::new(string::Length));
+        registry.register("split_part", Box::new(string::SplitPart));
+        registry.register("starts_with", Box::new(string::StartsWith));
+        registry.register("ends_with", Box::new(string::EndsWith));
+        registry.register("contains", Box::new(string::Contains));
+        registry.register("pad", Box::new(string::Pad));
+        registry.register("title_case", Box::new(string::TitleCase));
+        registry.register("abs", Box::new(math::Abs));
+        registry.register("min", Box::new(math::Min));
+        registry.register("max", Box::new(math::Max));
+        registry.register("round", Box::new(math::Round));
+        registry.register("floor", Box::new(math::Floor));
+        registry.register("ceil", Box::new(math::Ceil));
+        registry.register("sqrt", Box::new(math::Sqrt));
+        registry.register("pow", Box::new(math::Pow));
+        registry.register("log", Box::new(math::Log));
+        registry.register("log10", Box::new(math::Log10));
+        registry.register("exp", Box::new(math::Exp));
+        registry.register("clamp", Box::new(math::Clamp));
+        registry.register("scale_linear", Box::new(math::ScaleLinear));
+        registry.register("rgb", Box::new(color::Rgb));
+        registry.register("rgba", Box::new(color::Rgba));
+        registry.register("hsl", Box::new(color::Hsl));
+        registry.register("hsla", Box::new(color::Hsla));
+        registry.register("lighten", Box::new(color::Lighten));
+        registry.register("darken", Box::new(color::Darken));
+        registry.register("saturate", Box::new(color::Saturate));
+        registry.register("mix", Box::new(color::Mix));
+        registry.register("fade", Box::new(color::Fade));
+        registry
+    }
+
+    /// Adds `function` under `name`, replacing a function of the same name.
+    pub fn register(&mut self, name: &str, function: Box<dyn Function>) {
+        Rc::make_mut(&mut self.functions).insert(String::from(name), Rc::from(function));
+    }
+
+    pub fn get(&self, name: &str) -> ApplyResult<&dyn Function> {
+        self.functions
+            .get(name)
+            .map(|f| f.as_ref())
+            .ok_or_else(|| ApplyError::FunctionNotFound(name.into()))
+    }
+
+    /// Registered names, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.functions.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
 }
 
-pub fn find_function(name: &str) -> ApplyResult<Box<dyn Function>> {
-    match name {
-        "rgb" => Ok(Box::new(rgb::RGB)),
-        "concat" => Ok(Box::new(concat::Concat)),
-        _ => Err(ApplyError::FunctionNotFound(name.into())),
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod function_test {
+    use super::*;
+    use crate::check::Type;
+
+    struct Double;
+
+    impl Function for Double {
+        fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+            match args.as_slice() {
+                [Literal::Number(n)] => Ok(Literal::from(n.as_float() * 2.0)),
+                _ => Err(ApplyError::Conversion),
+            }
+        }
+
+        fn signature(&self) -> Signature {
+            Signature::fixed(vec![Type::Number], Type::Number)
+        }
+    }
+
+    #[test]
+    fn register() {
+        let builtins = FunctionRegistry::new();
+        let mut registry = builtins.clone();
+        registry.register("double", Box::new(Double));
+        assert!(registry.names().contains(&"double"));
+        assert!(!builtins.names().contains(&"double"));
+        let double = registry.get("double").unwrap();
+        let result = double.call(vec![Literal::from(2)]).unwrap();
+        assert_eq!(result, Literal::from(4.0));
+        assert!(matches!(
+            builtins.get("double"),
+            Err(ApplyError::FunctionNotFound(_))
+        ));
     }
 }
@@ -0,0 +1,409 @@
+//! String functions, they count and slice characters, not bytes.
+
+use parser::ast::{Literal, Num};
+
+use crate::check::{Signature, Type};
+use crate::error::ApplyResult;
+
+use super::{arg_error, arity, Function};
+
+/// Widths come from map data, a larger one is refused rather than
+/// allocated.
+pub const MAX_PAD_WIDTH: u64 = 10_000;
+
+fn string_arg<'a>(name: &str, args: &'a [Literal], i: usize) -> ApplyResult<&'a str> {
+    match args.get(i) {
+        Some(Literal::String(s)) => Ok(s),
+        Some(other) => Err(arg_error(
+            name,
+            format!("argument {} expects a string, got {}", i + 1, other),
+        )),
+        None => Err(arg_error(name, format!("misses argument {}", i + 1))),
+    }
+}
+
+fn integer_arg(name: &str, args: &[Literal], i: usize) -> ApplyResult<i64> {
+    match args.get(i) {
+        Some(Literal::Number(Num::Integer(n))) => Ok(*n),
+        Some(Literal::Number(Num::Float(f))) if f.fract() == 0.0 => Ok(*f as i64),
+        Some(other) => Err(arg_error(
+            name,
+            format!("argument {} expects an integer, got {}", i + 1, other),
+        )),
+        None => Err(arg_error(name, format!("misses argument {}", i + 1))),
+    }
+}
+
+/// A string function of a single string.
+fn unary(name: &str, args: &[Literal], f: impl Fn(&str) -> Literal) -> ApplyResult<Literal> {
+    arity(name, args, 1)?;
+    string_arg(name, args, 0).map(f)
+}
+
+/// A test of a string against another.
+fn test(name: &str, args: &[Literal], f: impl Fn(&str, &str) -> bool) -> ApplyResult<Literal> {
+    arity(name, args, 2)?;
+    let (s, other) = (string_arg(name, args, 0)?, string_arg(name, args, 1)?);
+    Ok(Literal::Boolean(f(s, other)))
+}
+
+fn count(s: &str) -> i64 {
+    s.chars().count() as i64
+}
+
+pub struct Lowercase;
+
+impl Function for Lowercase {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        unary("lowercase", &args, |s| Literal::from(s.to_lowercase()))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String], Type::String)
+    }
+}
+
+pub struct Uppercase;
+
+impl Function for Uppercase {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        unary("uppercase", &args, |s| Literal::from(s.to_uppercase()))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String], Type::String)
+    }
+}
+
+pub struct Trim;
+
+impl Function for Trim {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        unary("trim", &args, |s| Literal::from(s.trim()))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String], Type::String)
+    }
+}
+
+pub struct Length;
+
+impl Function for Length {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        unary("length", &args, |s| Literal::from(count(s)))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String], Type::Number)
+    }
+}
+
+/// Upper case at the start of each word, lower case elsewhere.
+pub struct TitleCase;
+
+impl Function for TitleCase {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        unary("title_case", &args, |s| {
+            let mut in_word = false;
+            let mut title = String::with_capacity(s.len());
+            for c in s.chars() {
+                if !c.is_alphanumeric() {
+                    title.push(c);
+                } else if in_word {
+                    title.extend(c.to_lowercase());
+                } else {
+                    title.extend(c.to_uppercase());
+                }
+                in_word = c.is_alphanumeric() || c == '\'' || c == '’';
+            }
+            Literal::from(title)
+        })
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String], Type::String)
+    }
+}
+
+/// `substr(s, start, length)`, `start` counts from 0, or back
+/// from the end when negative.
+pub struct Substr;
+
+impl Function for Substr {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "substr";
+        arity(name, &args, 3)?;
+        let s = string_arg(name, &args, 0)?;
+        let start = integer_arg(name, &args, 1)?;
+        let length = integer_arg(name, &args, 2)?;
+        if length < 0 {
+            return Err(arg_error(
+                name,
+                format!("length should not be negative, got {}", length),
+            ));
+        }
+        let start = if start < 0 {
+            (count(s) + start).max(0)
+        } else {
+            start
+        };
+        let sub: String = s
+            .chars()
+            .skip(start as usize)
+            .take(length as usize)
+            .collect();
+        Ok(Literal::from(sub))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::Number, Type::Number], Type::String)
+    }
+}
+
+/// `replace(s, from, to)` replaces every occurrence of `from`.
+pub struct Replace;
+
+impl Function for Replace {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "replace";
+        arity(name, &args, 3)?;
+        let s = string_arg(name, &args, 0)?;
+        let from = string_arg(name, &args, 1)?;
+        let to = string_arg(name, &args, 2)?;
+        if from.is_empty() {
+            return Err(arg_error(
+                name,
+                String::from("can't replace an empty string"),
+            ));
+        }
+        Ok(Literal::from(s.replace(from, to)))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::String, Type::String], Type::String)
+    }
+}
+
+/// `split_part(s, separator, n)`, the `n`th part counting from 1,
+/// or back from the end when negative. An empty string past the last part.
+pub struct SplitPart;
+
+impl Function for SplitPart {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "split_part";
+        arity(name, &args, 3)?;
+        let s = string_arg(name, &args, 0)?;
+        let separator = string_arg(name, &args, 1)?;
+        let n = integer_arg(name, &args, 2)?;
+        if separator.is_empty() {
+            return Err(arg_error(
+                name,
+                String::from("can't split on an empty string"),
+            ));
+        }
+        let parts: Vec<&str> = s.split(separator).collect();
+        let index = match n {
+            0 => return Err(arg_error(name, String::from("parts count from 1"))),
+            n if n > 0 => Some(n as usize - 1),
+            n => parts.len().checked_sub(n.unsigned_abs() as usize),
+        };
+        let part = index.and_then(|i| parts.get(i)).copied().unwrap_or("");
+        Ok(Literal::from(part))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::String, Type::Number], Type::String)
+    }
+}
+
+pub struct StartsWith;
+
+impl Function for StartsWith {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        test("starts_with", &args, |s, prefix| s.starts_with(prefix))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::String], Type::Boolean)
+    }
+}
+
+pub struct EndsWith;
+
+impl Function for EndsWith {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        test("ends_with", &args, |s, suffix| s.ends_with(suffix))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::String], Type::Boolean)
+    }
+}
+
+pub struct Contains;
+
+impl Function for Contains {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        test("contains", &args, |s, needle| s.contains(needle))
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::String], Type::Boolean)
+    }
+}
+
+/// `pad(s, width, fill)` fills the start of `s` up to `width` characters,
+/// or its end when `width` is negative. `fill` is a single character
+/// and `width` at most `MAX_PAD_WIDTH` either way.
+pub struct Pad;
+
+impl Function for Pad {
+    fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+        let name = "pad";
+        arity(name, &args, 3)?;
+        let s = string_arg(name, &args, 0)?;
+        let width = integer_arg(name, &args, 1)?;
+        if width.unsigned_abs() > MAX_PAD_WIDTH {
+            return Err(arg_error(
+                name,
+                format!("width {} is above {}", width, MAX_PAD_WIDTH),
+            ));
+        }
+        let fill = string_arg(name, &args, 2)?;
+        let mut fill_chars = fill.chars();
+        let c = match (fill_chars.next(), fill_chars.next()) {
+            (Some(c), None) => c,
+            _ => {
+                return Err(arg_error(
+                    name,
+                    format!("fill should be a single character, got {:?}", fill),
+                ))
+            }
+        };
+        let missing = (width.unsigned_abs() as usize).saturating_sub(s.chars().count());
+        let padding: String = std::iter::repeat_n(c, missing).collect();
+        if width < 0 {
+            Ok(Literal::from(format!("{}{}", s, padding)))
+        } else {
+            Ok(Literal::from(format!("{}{}", padding, s)))
+        }
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::fixed(vec![Type::String, Type::Number, Type::String], Type::String)
+    }
+}
+
+#[cfg(test)]
+mod string_test {
+    use super::*;
+    use crate::error::ApplyError;
+
+    fn call(f: &dyn Function, args: Vec<Literal>) -> ApplyResult<Literal> {
+        f.call(args)
+    }
+
+    fn s(value: &str) -> Literal {
+        Literal::from(value)
+    }
+
+    fn n(value: i64) -> Literal {
+        Literal::from(value)
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(call(&Lowercase, vec![s("ÉCOLE Σ")]).unwrap(), s("école σ"));
+        assert_eq!(call(&Uppercase, vec![s("straße")]).unwrap(), s("STRASSE"));
+        assert_eq!(
+            call(&TitleCase, vec![s("rue de l'ÉGLISE-saint-éloi")]).unwrap(),
+            s("Rue De L'église-Saint-Éloi")
+        );
+        assert_eq!(call(&Trim, vec![s("\u{a0} park\t")]).unwrap(), s("park"));
+    }
+
+    #[test]
+    fn characters() {
+        assert_eq!(call(&Length, vec![s("Liège")]).unwrap(), n(5));
+        assert_eq!(
+            call(&Substr, vec![s("Liège"), n(2), n(2)]).unwrap(),
+            s("èg")
+        );
+        assert_eq!(
+            call(&Substr, vec![s("Liège"), n(-3), n(10)]).unwrap(),
+            s("ège")
+        );
+        assert_eq!(call(&Pad, vec![s("7"), n(3), s("0")]).unwrap(), s("007"));
+        assert_eq!(call(&Pad, vec![s("é"), n(-3), s("·")]).unwrap(), s("é··"));
+        assert_eq!(
+            call(&Pad, vec![s("long"), n(2), s(" ")]).unwrap(),
+            s("long")
+        );
+    }
+
+    #[test]
+    fn parts() {
+        let code = s("62063-A-0123");
+        assert_eq!(
+            call(&SplitPart, vec![code.clone(), s("-"), n(2)]).unwrap(),
+            s("A")
+        );
+        assert_eq!(
+            call(&SplitPart, vec![code.clone(), s("-"), n(-1)]).unwrap(),
+            s("0123")
+        );
+        assert_eq!(
+            call(&SplitPart, vec![code.clone(), s("-"), n(4)]).unwrap(),
+            s("")
+        );
+        assert_eq!(
+            call(&Replace, vec![code.clone(), s("-"), s(" / ")]).unwrap(),
+            s("62063 / A / 0123")
+        );
+        assert_eq!(
+            call(&StartsWith, vec![code.clone(), s("62")]).unwrap(),
+            Literal::Boolean(true)
+        );
+        assert_eq!(
+            call(&EndsWith, vec![code.clone(), s("A")]).unwrap(),
+            Literal::Boolean(false)
+        );
+        assert_eq!(
+            call(&Contains, vec![code, s("-A-")]).unwrap(),
+            Literal::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let message = |result: ApplyResult<Literal>| match result {
+            Err(ApplyError::FunctionArg(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            message(call(&Lowercase, vec![n(1)])),
+            "`lowercase` argument 1 expects a string, got 1"
+        );
+        assert_eq!(
+            message(call(&Trim, vec![])),
+            "`trim` takes 1 arguments, got 0"
+        );
+        assert_eq!(
+            message(call(&Substr, vec![s("a"), Literal::from(0.5), n(1)])),
+            "`substr` argument 2 expects an integer, got 0.5"
+        );
+        assert_eq!(
+            message(call(&Pad, vec![s("a"), n(3), s("ab")])),
+            "`pad` fill should be a single character, got \"ab\""
+        );
+        assert_eq!(
+            message(call(&Pad, vec![s("a"), n(10_000_000_000), s("x")])),
+            "`pad` width 10000000000 is above 10000"
+        );
+        assert_eq!(
+            message(call(&SplitPart, vec![s("a"), s(","), n(0)])),
+            "`split_part` parts count from 1"
+        );
+    }
+}
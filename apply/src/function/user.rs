@@ -0,0 +1,83 @@
+use std::{collections::HashMap, rc::Rc};
+
+use parser::ast::{Constructor, Data, Directive, Expr, FunctionCall, FunctionDef, Literal, Value};
+
+/// Nested calls beyond this depth fail, it stops runaway recursion.
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// The functions declared with `fn` in the map block and a layer block.
+/// The parser refuses a name declared in an enclosing block, so a name
+/// means one function for the whole layer.
+#[derive(Clone, Default)]
+pub struct UserFunctions {
+    functions: Rc<HashMap<String, FunctionDef>>,
+}
+
+impl UserFunctions {
+    /// These functions and the ones declared in `directives`.
+    pub fn extend(&self, directives: &[Directive]) -> Self {
+        let mut functions = self.functions.as_ref().clone();
+        for directive in directives {
+            if let Directive::Fn(def) = directive {
+                functions.insert(def.name.clone(), def.clone());
+            }
+        }
+        UserFunctions {
+            functions: Rc::new(functions),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FunctionDef> {
+        self.functions.get(name)
+    }
+}
+
+fn bind_all(values: Vec<Value>, args: &[Literal]) -> Vec<Value> {
+    values.into_iter().map(|v| bind(v, args)).collect()
+}
+
+/// `value` with the parameters of the enclosing function replaced by `args`.
+pub fn bind(value: Value, args: &[Literal]) -> Value {
+    let pair = |(left, right): (Value, Value)| (bind(left, args), bind(right, args));
+    match value {
+        Value::Lit(_) => value,
+        Value::Data(data) => match *data.constructor {
+            Constructor::Param(i) => {
+                args.get(i)
+                    .cloned()
+                    .map(Value::Lit)
+                    .unwrap_or(Value::Data(Data {
+                        ident: data.ident,
+                        constructor: Box::new(Constructor::Param(i)),
+                    }))
+            }
+            Constructor::Val(inner) => Value::Data(Data {
+                ident: data.ident,
+                constructor: Box::new(Constructor::Val(bind(inner, args))),
+            }),
+            Constructor::Select(_) => Value::Data(data),
+        },
+        Value::Fn(f) => Value::Fn(FunctionCall {
+            name: f.name,
+            args: bind_all(f.args, args),
+        }),
+        Value::Call(f) => Value::Call(FunctionCall {
+            name: f.name,
+            args: bind_all(f.args, args),
+        }),
+        Value::Match(mut m) => {
+            m.subject = bind(m.subject, args);
+            m.arms = m.arms.into_iter().map(pair).collect();
+            m.default = bind(m.default, args);
+            Value::Match(m)
+        }
+        Value::Expr(expr) => Value::Expr(Box::new(match *expr {
+            Expr::Neg(inner) => Expr::Neg(bind(inner, args)),
+            Expr::Add(operands) => Expr::Add(pair(operands)),
+            Expr::Sub(operands) => Expr::Sub(pair(operands)),
+            Expr::Mul(operands) => Expr::Mul(pair(operands)),
+            Expr::Div(operands) => Expr::Div(pair(operands)),
+            Expr::Rem(operands) => Expr::Rem(pair(operands)),
+        })),
+    }
+}
@@ -1,11 +1,12 @@
 use crate::{
     error::ApplyError,
-    op::OpList,
-    source::{geojson_source::GeoJSON, Source},
-    sym::make_symbology,
+    function::{user::UserFunctions, FunctionRegistry},
+    op::{Op, OpList},
+    source::{geojson_source::GeoJSON, Source, SourceT},
+    sym::{make_fallback, make_symbology},
 };
 
-use parser::ast::{Directive, LayerBlock, Source as SourceSpec};
+use parser::ast::{splice, Directive, LayerBlock, Matching, Source as SourceSpec};
 
 use crate::error::ApplyResult;
 
@@ -21,23 +22,205 @@ pub fn make_source(spec: SourceSpec, target_srid: i64) -> ApplyResult<Source> {
     }
 }
 
-pub fn run_layer(spec: LayerBlock, target_srid: i64) -> ApplyResult<OpList> {
-    let source = spec
-        .directives
+/// `scale` is the scale denominator of the output, layers and rules
+/// outside their scale range are skipped before the source is read.
+/// Errors carry the layer name, a translucent layer is drawn as a group.
+/// `functions` are the ones declared in the map block.
+pub fn run_layer(
+    spec: LayerBlock,
+    target_srid: i64,
+    scale: f64,
+    registry: &FunctionRegistry,
+    functions: &UserFunctions,
+) -> ApplyResult<OpList> {
+    let name = spec.name().unwrap_or_else(|| String::from("(unnamed)"));
+    let opacity = spec.opacity();
+    let ops = layer_ops(spec, target_srid, scale, registry, functions)
+        .map_err(|err| ApplyError::Layer(name, Box::new(err)))?;
+    if opacity < 1.0 && !ops.is_empty() {
+        Ok([vec![Op::PushGroup], ops, vec![Op::PopGroup(opacity)]].concat())
+    } else {
+        Ok(ops)
+    }
+}
+
+fn layer_ops(
+    spec: LayerBlock,
+    target_srid: i64,
+    scale: f64,
+    registry: &FunctionRegistry,
+    functions: &UserFunctions,
+) -> ApplyResult<OpList> {
+    let directives = splice(&spec.directives);
+    let in_range = directives.iter().all(|d| match d {
+        Directive::Scale(range) => range.contains(scale),
+        _ => true,
+    });
+    if !in_range {
+        return Ok(Vec::new());
+    }
+    let source = directives
         .iter()
         .find_map(|d| match d {
             Directive::Source(s) => make_source(s.clone(), target_srid).ok(),
             _ => None,
         })
-        .ok_or(ApplyError::MissingSource)?;
+        .ok_or(ApplyError::MissingSource)?
+        .with_functions(registry.clone(), functions.extend(&directives));
 
-    Ok(spec
-        .directives
+    let matching = directives
         .iter()
-        .filter_map(|d| match d {
-            Directive::Sym(s) => make_symbology(s.clone(), source.clone()).ok(),
+        .rev()
+        .find_map(|d| match d {
+            Directive::Matching(m) => Some(*m),
             _ => None,
         })
-        .flatten()
-        .collect())
+        .unwrap_or(Matching::All);
+    let mut matched = vec![false; source.iter().count()];
+    let mut ops = Vec::new();
+    for directive in directives.iter() {
+        match directive {
+            Directive::Sym(s) if !s.scale.contains(scale) => {}
+            Directive::Sym(s) => ops.extend(make_symbology(
+                s.clone(),
+                source.clone(),
+                &mut matched,
+                matching,
+            )?),
+            Directive::Else(e) => {
+                ops.extend(make_fallback(e.clone(), source.clone(), &mut matched)?)
+            }
+            _ => {}
+        }
+    }
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod layer_test {
+    use super::*;
+    use crate::map_source;
+    use parser::parse_str;
+
+    // first.geojson has 34 `tree` and 12 `un` points
+    fn count_shapes(rules: &str) -> usize {
+        count_shapes_at(rules, 10000.0)
+    }
+
+    fn count_shapes_at(rules: &str, scale: f64) -> usize {
+        let ops = layer(rules, scale).unwrap();
+        ops.iter().filter(|op| matches!(op, Op::Start)).count()
+    }
+
+    fn layer(rules: &str, scale: f64) -> ApplyResult<OpList> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../parser/data/first.geojson");
+        let layer = format!(
+            "source geojson \"{}\"\ndata kind select \"symbol\" string\n{}\n",
+            path, rules
+        );
+        let map_str = map_source("", &[&layer]);
+        let spec = parse_str(&map_str).unwrap();
+        let functions = UserFunctions::default().extend(&splice(&spec.map.directives));
+        let registry = FunctionRegistry::new();
+        run_layer(spec.layers[0].clone(), 3857, scale, &registry, &functions)
+    }
+
+    #[test]
+    fn opacity_group() {
+        let ops = layer("opacity 0.5\nsym kind = \"un\" -> circle 1", 1.0).unwrap();
+        assert!(matches!(ops.first(), Some(Op::PushGroup)));
+        assert!(matches!(ops.last(), Some(Op::PopGroup(o)) if *o == 0.5));
+        let groups = ops.iter().filter(|op| matches!(op, Op::PushGroup)).count();
+        assert_eq!(groups, 1);
+        let opaque = layer("sym kind = \"un\" -> circle 1", 1.0).unwrap();
+        assert!(matches!(opaque.first(), Some(Op::Start)));
+    }
+
+    #[test]
+    fn named_errors() {
+        match layer("name \"trees\"\nsym kind ~ \"(\" -> circle 1", 1.0) {
+            Err(err @ ApplyError::Layer(_, _)) => {
+                assert!(err.to_string().starts_with("Layer trees -> "))
+            }
+            other => panic!("unexpected result {:?}", other.map(|ops| ops.len())),
+        }
+    }
+
+    #[test]
+    fn all_matches_draw() {
+        assert_eq!(
+            count_shapes("sym kind = \"tree\" -> circle 1\nsym kind != \"x\" -> circle 1"),
+            80
+        );
+    }
+
+    #[test]
+    fn first_match_wins() {
+        assert_eq!(
+            count_shapes(
+                "matching first\nsym kind = \"tree\" -> circle 1\nsym kind != \"x\" -> circle 1"
+            ),
+            46
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        assert_eq!(
+            count_shapes("sym kind = \"tree\" -> circle 1\nelse -> circle 1"),
+            46
+        );
+        assert_eq!(
+            count_shapes("sym kind = \"none\" -> circle 1\nsym * -> circle 1\nelse -> circle 1"),
+            46
+        );
+    }
+
+    #[test]
+    fn scale_ranges() {
+        let rules = "sym maxscale 20000 kind = \"tree\" -> circle 1\nsym minscale 20000 kind != \"x\" -> circle 1";
+        assert_eq!(count_shapes_at(rules, 10000.0), 34);
+        assert_eq!(count_shapes_at(rules, 20000.0), 46);
+        let layer = format!("minscale 5000 maxscale 50000\n{}", rules);
+        assert_eq!(count_shapes_at(&layer, 1000.0), 0);
+        assert_eq!(count_shapes_at(&layer, 50000.0), 0);
+        assert_eq!(count_shapes_at(&layer, 40000.0), 46);
+    }
+
+    #[test]
+    fn declared_functions() {
+        let rules = "fn double(r) = r * 2\nfn radius(k) = match(k, \"un\", double(1), 0)\nsym radius(kind) > 1 -> circle radius(kind)";
+        assert_eq!(count_shapes(rules), 12);
+    }
+
+    #[test]
+    fn colors() {
+        let ops = layer(
+            "sym kind = \"un\" -> circle 1 -> fill fade(\"red\", 0.5) -> stroke \"navy\" 1",
+            1.0,
+        )
+        .unwrap();
+        assert!(
+            matches!(ops.iter().find(|op| matches!(op, Op::Fill(_))), Some(Op::Fill(c)) if c == "#FF000080")
+        );
+        assert!(
+            matches!(ops.iter().find(|op| matches!(op, Op::Stroke { .. })), Some(Op::Stroke { color, .. }) if color == "#000080")
+        );
+        // a feature with an unknown colour is skipped
+        let unknown = layer("sym kind = \"un\" -> circle 1 -> fill \"reddish\"", 1.0).unwrap();
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn geometry_class() {
+        assert_eq!(count_shapes("sym geometry is point -> circle 1"), 46);
+        assert_eq!(
+            count_shapes("sym geometry is polygon | geometry is multi -> circle 1"),
+            0
+        );
+        assert_eq!(
+            count_shapes("sym !geometry is line & kind = \"un\" -> circle 1"),
+            12
+        );
+    }
 }
@@ -1,4 +1,7 @@
 pub mod apply;
+pub mod arith;
+pub mod check;
+pub mod color;
 pub mod error;
 pub mod function;
 pub mod geom;
@@ -9,4 +12,15 @@ pub mod map;
 pub mod source;
 pub mod sym;
 
-pub use map::run_map;
+pub use map::{map_extent, run_map, MapOutput};
+
+/// Source of a test map, `map` holds the directives following its
+/// header and each of `layers` the lines of a layer, newline ended.
+#[cfg(test)]
+pub(crate) fn map_source(map: &str, layers: &[&str]) -> String {
+    let mut source = format!("map\nsrid 3857\nextent 0 0 1 1\n{}", map);
+    for layer in layers {
+        source.push_str(&format!("\n\nlayer\n{}", layer));
+    }
+    source
+}
@@ -1,35 +1,81 @@
-use parser::ast::{Directive, MapSpec};
+use parser::ast::{splice, Directive, Extent, MapSpec};
 
 use crate::{
     error::{ApplyError, ApplyResult},
+    function::{user::UserFunctions, FunctionRegistry},
     layer::run_layer,
     op::OpList,
 };
 
-pub fn run_map(spec: MapSpec) -> ApplyResult<OpList> {
-    let srid = spec
-        .map
-        .directives
+pub struct MapOutput {
+    pub extent: Extent,
+    pub ops: OpList,
+    /// Why layers were left out, the map is drawn without them.
+    pub warnings: Vec<ApplyError>,
+}
+
+/// The extent of the map block, needed to compute the output scale
+/// before running the map.
+pub fn map_extent(spec: &MapSpec) -> ApplyResult<Extent> {
+    splice(&spec.map.directives)
         .iter()
         .find_map(|d| match d {
-            Directive::Srid(s) => Some(s.value),
+            Directive::Extent(e) => Some(e.clone()),
             _ => None,
         })
-        .ok_or(ApplyError::MissingSrid)?;
-    let _extent = spec
-        .map
-        .directives
+        .ok_or(ApplyError::MissingExtent)
+}
+
+/// `scale` is the scale denominator the map is rendered at,
+/// `registry` holds the functions the map can call.
+pub fn run_map(spec: MapSpec, scale: f64, registry: &FunctionRegistry) -> ApplyResult<MapOutput> {
+    let map_directives = splice(&spec.map.directives);
+    let srid = map_directives
         .iter()
         .find_map(|d| match d {
-            Directive::Extent(e) => Some(e),
+            Directive::Srid(s) => Some(s.value),
             _ => None,
         })
-        .ok_or(ApplyError::MissingExtent)?;
+        .ok_or(ApplyError::MissingSrid)?;
+    let extent = map_extent(&spec)?;
+    let functions = UserFunctions::default().extend(&map_directives);
 
-    Ok(spec
-        .layers
-        .iter()
-        .filter_map(|layer| run_layer(layer.clone(), srid).ok())
-        .flatten()
-        .collect())
+    let mut ops = Vec::new();
+    let mut warnings = Vec::new();
+    for layer in spec.layers.iter().filter(|layer| layer.visible()) {
+        match run_layer(layer.clone(), srid, scale, registry, &functions) {
+            Ok(layer_ops) => ops.extend(layer_ops),
+            Err(err) => warnings.push(err),
+        }
+    }
+
+    Ok(MapOutput {
+        extent,
+        ops,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod map_test {
+    use super::*;
+    use crate::map_source;
+    use parser::parse_str;
+
+    #[test]
+    fn failing_layers_are_reported() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../parser/data/first.geojson");
+        let layer = |name: &str, pattern: &str| {
+            format!(
+                "name \"{}\"\nsource geojson \"{}\"\ndata kind select \"symbol\" string\nsym kind ~ \"{}\" -> circle 1\n",
+                name, path, pattern
+            )
+        };
+        let map_str = map_source("", &[&layer("broken", "("), &layer("trees", "tree")]);
+        let spec = parse_str(&map_str).unwrap();
+        let output = run_map(spec, 1.0, &FunctionRegistry::new()).unwrap();
+        assert!(!output.ops.is_empty());
+        assert_eq!(output.warnings.len(), 1);
+        assert!(matches!(&output.warnings[0], ApplyError::Layer(name, _) if name == "broken"));
+    }
 }
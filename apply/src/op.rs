@@ -29,6 +29,10 @@ pub enum Op {
     Transform(Mat),
     Save,
     Restore,
+    /// draws what follows offscreen until the matching `PopGroup`
+    PushGroup,
+    /// composites the group with the given opacity
+    PopGroup(f64),
 }
 
 fn point_as_string(p: &Point) -> String {
@@ -61,6 +65,8 @@ impl std::fmt::Display for Op {
             Op::Close => write!(formatter, "[close]"),
             Op::Save => write!(formatter, "[save]"),
             Op::Restore => write!(formatter, "[restore]"),
+            Op::PushGroup => write!(formatter, "[push group]"),
+            Op::PopGroup(opacity) => write!(formatter, "[pop group {}]", opacity),
             Op::Transform((a, b, c, d, e, f)) => {
                 write!(formatter, "[transform {} {} {} {} {} {}]", a, b, c, d, e, f)
             }
@@ -4,7 +4,10 @@ use proj::Proj;
 use std::fs::read_to_string;
 use std::rc::Rc;
 
-use crate::error::{ApplyError, ApplyResult};
+use crate::{
+    error::{ApplyError, ApplyResult},
+    function::{user::UserFunctions, FunctionRegistry},
+};
 
 use super::{Resolver, SourceT};
 
@@ -13,6 +16,8 @@ pub struct GeoJSON {
     pub data: Rc<FeatureCollection>,
     pub source_srid: i64,
     pub target_srid: i64,
+    pub registry: FunctionRegistry,
+    pub functions: UserFunctions,
 }
 
 fn load_file(path: String) -> ApplyResult<FeatureCollection> {
@@ -24,6 +29,20 @@ fn load_file(path: String) -> ApplyResult<FeatureCollection> {
     }
 }
 
+/// Property names found on the features of a GeoJSON file, sorted.
+pub fn property_names(path: &str) -> ApplyResult<Vec<String>> {
+    let fc = load_file(String::from(path))?;
+    let mut names: Vec<String> = fc
+        .features
+        .iter()
+        .filter_map(|f| f.properties.as_ref())
+        .flat_map(|props| props.keys().cloned())
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
 impl GeoJSON {
     pub fn init(path: String, source_srid: Option<Num>, target_srid: i64) -> ApplyResult<Self> {
         let source_srid: i64 = match source_srid {
@@ -43,6 +62,8 @@ impl GeoJSON {
             source_srid,
             target_srid,
             data: Rc::new(load_file(path)?),
+            registry: FunctionRegistry::new(),
+            functions: UserFunctions::default(),
         })
     }
 }
@@ -62,4 +83,12 @@ impl SourceT for GeoJSON {
     }
 }
 
-impl Resolver for GeoJSON {}
+impl Resolver for GeoJSON {
+    fn registry(&self) -> &FunctionRegistry {
+        &self.registry
+    }
+
+    fn functions(&self) -> &UserFunctions {
+        &self.functions
+    }
+}
@@ -1,14 +1,19 @@
 use geojson::Feature;
 use geojson_source::GeoJSON;
-use parser::ast::{Constructor, Literal, Num, Select, Value};
+use parser::ast::{Constructor, Expr, Literal, Num, Select, Value};
 use proj::Proj;
 use serde_json::Value as JsonValue;
 use std::{cell::RefCell, convert::TryInto, rc::Rc};
 use Value::{Data, Lit};
 
 use crate::{
+    apply::{self, Path, SelectorLiteral},
+    arith,
     error::{ApplyError, ApplyResult},
-    function::find_function,
+    function::{
+        user::{bind, UserFunctions, MAX_CALL_DEPTH},
+        FunctionRegistry,
+    },
 };
 
 pub mod geojson_source;
@@ -24,39 +29,111 @@ pub trait Resolver: Clone {
             .clone()
             .ok_or(ApplyError::Select(format!("[GeoJSON] missing properties")))?;
 
-        let dt = select.datatype;
-
-        props
-            .get(&select.selector)
-            .and_then(try_literal)
-            .and_then(|lit| match (&lit, dt) {
-                (Literal::Nil, _) => Some(lit),
-                (Literal::String(_), parser::ast::DataType::String) => Some(lit),
-                (Literal::Number(_), parser::ast::DataType::Number) => Some(lit),
-                (Literal::Boolean(_), parser::ast::DataType::Boolean) => Some(lit),
-                _ => None,
-            })
-            .ok_or(ApplyError::Select(format!(
+        // a key holding dots or brackets is taken as is
+        let path = if props.contains_key(&select.selector) {
+            Path::new().add(select.selector.as_str())
+        } else {
+            Path::parse(&select.selector)
+                .map_err(|err| ApplyError::Select(format!("[GeoJSON] invalid path: {}", err)))?
+        };
+        let props = JsonValue::Object(props);
+        if path.view(&props).is_none() {
+            return Err(ApplyError::Select(format!(
+                "[GeoJSON] missing property: {}",
+                path
+            )));
+        }
+
+        apply::select(&SelectorLiteral(select.datatype), &path, &props).ok_or(ApplyError::Select(
+            format!(
                 "[GeoJSON] failed to get or convert property: {}",
                 &select.selector
-            )))
+            ),
+        ))
     }
 
+    /// The Rust functions that `resolve` can call.
+    fn registry(&self) -> &FunctionRegistry;
+
+    /// The functions declared with `fn` that `resolve` can call.
+    fn functions(&self) -> &UserFunctions;
+
     fn resolve(&self, value: Value, feature: &Feature) -> ApplyResult<Literal> {
+        self.resolve_at(value, feature, 0)
+    }
+
+    /// `depth` counts the nested calls to declared functions.
+    fn resolve_at(&self, value: Value, feature: &Feature, depth: usize) -> ApplyResult<Literal> {
+        let resolve = |value: Value| self.resolve_at(value, feature, depth);
         match value {
             Lit(l) => Ok(l),
             Value::Fn(f) => {
-                let func = find_function(&f.name)?;
+                let func = self.registry().get(&f.name)?;
                 let mut args: Vec<Literal> = Vec::new();
                 for arg in f.args.iter() {
-                    args.push(self.resolve(arg.clone(), feature)?);
+                    args.push(resolve(arg.clone())?);
                 }
                 func.call(args)
             }
+            Value::Call(f) => {
+                let functions = self.functions();
+                let def = functions
+                    .get(&f.name)
+                    .ok_or_else(|| ApplyError::FunctionNotFound(f.name.clone()))?;
+                if def.params.len() != f.args.len() {
+                    return Err(ApplyError::FunctionArg(format!(
+                        "`{}` takes {} arguments, got {}",
+                        f.name,
+                        def.params.len(),
+                        f.args.len()
+                    )));
+                }
+                if depth >= MAX_CALL_DEPTH {
+                    return Err(ApplyError::FunctionFail(format!(
+                        "`{}` nested more than {} calls deep",
+                        f.name, MAX_CALL_DEPTH
+                    )));
+                }
+                let mut args: Vec<Literal> = Vec::new();
+                for arg in f.args.into_iter() {
+                    args.push(resolve(arg)?);
+                }
+                self.resolve_at(bind(def.body.clone(), &args), feature, depth + 1)
+            }
             Data(data) => match *data.constructor {
-                Constructor::Val(inner) => self.resolve(inner, feature),
+                Constructor::Val(inner) => resolve(inner),
                 Constructor::Select(select) => self.select(select, feature),
+                Constructor::Param(_) => Err(ApplyError::Resolve(format!(
+                    "parameter `{}` outside of its function",
+                    data.ident
+                ))),
             },
+            Value::Match(m) => {
+                let subject = resolve(m.subject)?;
+                for (case, value) in m.arms {
+                    let hit = match resolve(case)? {
+                        Literal::List(items) => items.contains(&subject),
+                        case => case == subject,
+                    };
+                    if hit {
+                        return resolve(value);
+                    }
+                }
+                resolve(m.default)
+            }
+            Value::Expr(expr) => {
+                let both = |(left, right): (Value, Value)| {
+                    resolve(left).and_then(|left| resolve(right).map(|right| (left, right)))
+                };
+                match *expr {
+                    Expr::Neg(inner) => arith::neg(resolve(inner)?),
+                    Expr::Add(operands) => both(operands).and_then(|(l, r)| arith::add(l, r)),
+                    Expr::Sub(operands) => both(operands).and_then(|(l, r)| arith::sub(l, r)),
+                    Expr::Mul(operands) => both(operands).and_then(|(l, r)| arith::mul(l, r)),
+                    Expr::Div(operands) => both(operands).and_then(|(l, r)| arith::div(l, r)),
+                    Expr::Rem(operands) => both(operands).and_then(|(l, r)| arith::rem(l, r)),
+                }
+            }
         }
     }
 }
@@ -80,7 +157,31 @@ impl SourceT for Source {
     }
 }
 
+impl Source {
+    pub fn with_functions(self, registry: FunctionRegistry, functions: UserFunctions) -> Self {
+        match self {
+            Source::GeoJSON(gj) => Source::GeoJSON(GeoJSON {
+                registry,
+                functions,
+                ..gj
+            }),
+        }
+    }
+}
+
 impl Resolver for Source {
+    fn registry(&self) -> &FunctionRegistry {
+        match self {
+            Source::GeoJSON(gj) => gj.registry(),
+        }
+    }
+
+    fn functions(&self) -> &UserFunctions {
+        match self {
+            Source::GeoJSON(gj) => gj.functions(),
+        }
+    }
+
     fn select(&self, select: Select, feature: &Feature) -> ApplyResult<Literal> {
         match self {
             Source::GeoJSON(gj) => gj.select(select, feature),
@@ -92,6 +193,12 @@ impl Resolver for Source {
             Source::GeoJSON(gj) => gj.resolve(value, feature),
         }
     }
+
+    fn resolve_at(&self, value: Value, feature: &Feature, depth: usize) -> ApplyResult<Literal> {
+        match self {
+            Source::GeoJSON(gj) => gj.resolve_at(value, feature, depth),
+        }
+    }
 }
 
 pub type SharedSource = Rc<RefCell<dyn SourceT>>;
@@ -118,3 +225,142 @@ pub fn try_literal(json_value: &JsonValue) -> Option<Literal> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod source_test {
+    use super::*;
+    use crate::{
+        check::{Signature, Type},
+        function::Function,
+        map_source,
+    };
+    use geojson::FeatureCollection;
+    use geojson_source::GeoJSON;
+    use parser::{ast::DataType, parse_str};
+    use serde_json::json;
+
+    struct Zone;
+
+    impl Function for Zone {
+        fn call(&self, args: Vec<Literal>) -> ApplyResult<Literal> {
+            match args.as_slice() {
+                [Literal::String(code)] => Ok(Literal::from(&code[..2])),
+                _ => Err(ApplyError::FunctionArg(String::from("zone"))),
+            }
+        }
+
+        fn signature(&self) -> Signature {
+            Signature::fixed(vec![Type::String], Type::String)
+        }
+    }
+
+    fn select(source: &GeoJSON, selector: &str, feature: &Feature) -> ApplyResult<Literal> {
+        let select = Select {
+            selector: String::from(selector),
+            datatype: DataType::String,
+        };
+        source.select(select, feature)
+    }
+
+    fn empty_source() -> GeoJSON {
+        GeoJSON {
+            data: Rc::new(FeatureCollection {
+                bbox: None,
+                features: Vec::new(),
+                foreign_members: None,
+            }),
+            source_srid: 4326,
+            target_srid: 3857,
+            registry: FunctionRegistry::new(),
+            functions: UserFunctions::default(),
+        }
+    }
+
+    fn feature() -> Feature {
+        Feature {
+            bbox: None,
+            geometry: None,
+            id: None,
+            properties: None,
+            foreign_members: None,
+        }
+    }
+
+    #[test]
+    fn nested_select() {
+        let source = empty_source();
+        let properties = json!({
+            "address": {"city": "Liège"},
+            "tags": ["park"],
+            "a.b": "flat",
+        });
+        let feature = Feature {
+            properties: properties.as_object().cloned(),
+            ..feature()
+        };
+        let city = select(&source, "address.city", &feature).unwrap();
+        assert_eq!(city, Literal::String(String::from("Liège")));
+        let tag = select(&source, "tags[0]", &feature).unwrap();
+        assert_eq!(tag, Literal::String(String::from("park")));
+        let flat = select(&source, "a.b", &feature).unwrap();
+        assert_eq!(flat, Literal::String(String::from("flat")));
+        match select(&source, "address.street", &feature) {
+            Err(ApplyError::Select(msg)) => {
+                assert_eq!(msg, "[GeoJSON] missing property: address.street")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registered_functions() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("zone", Box::new(Zone));
+        let source = GeoJSON {
+            registry,
+            ..empty_source()
+        };
+        let feature = feature();
+        let call = Value::Fn(parser::ast::FunctionCall {
+            name: String::from("zone"),
+            args: vec![Lit(Literal::from("AB-123"))],
+        });
+        let zone = source.resolve(call.clone(), &feature).unwrap();
+        assert_eq!(zone, Literal::from("AB"));
+        assert!(matches!(
+            empty_source().resolve(call, &feature),
+            Err(ApplyError::FunctionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn declared_functions() {
+        let map_str = map_source(
+            "fn fact(n) = match(n, 0, 1, n * fact(n - 1))\nfn forever(n) = forever(n + 1)\n",
+            &["source geojson \"a\"\ndata f fact(5)\ndata g forever(0)\n"],
+        );
+        let spec = parse_str(&map_str).unwrap();
+        let source = GeoJSON {
+            functions: UserFunctions::default().extend(&spec.map.directives),
+            ..empty_source()
+        };
+        let feature = feature();
+        let value = |i: usize| match &spec.layers[0].directives[i] {
+            parser::ast::Directive::Data(d) => Value::Data(d.clone()),
+            other => panic!("unexpected directive {:?}", other),
+        };
+        let fact = source.resolve(value(1), &feature).unwrap();
+        assert_eq!(fact, Literal::Number(Num::Integer(120)));
+        match source.resolve(value(2), &feature) {
+            Err(ApplyError::FunctionFail(msg)) => {
+                assert_eq!(msg, "`forever` nested more than 64 calls deep")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // the functions live in the source, not in the map spec
+        match empty_source().resolve(value(1), &feature) {
+            Err(ApplyError::FunctionNotFound(name)) => assert_eq!(name, "fact"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use parser::ast::Fill;
+
+use crate::{error::ApplyResult, op::fill};
+
+use super::{SymCommand, SymInput, SymOuput};
+
+impl SymCommand for Fill {
+    fn exec(&self, input: &SymInput) -> ApplyResult<SymOuput> {
+        let color = input.resolve_color(self.color.clone())?;
+        Ok(input.concat_ops(vec![fill(color)]))
+    }
+}
@@ -1,16 +1,23 @@
 use std::convert::TryFrom;
 
 use crate::{
+    color::Color,
     error::{ApplyError, ApplyResult},
     geom::{from_geojson, Geometry},
     source::{Resolver, Source},
 };
 use crate::{op::OpList, source::SourceT};
 use geojson::Feature;
-use parser::ast::{Command, Literal, PredGroup, Predicate, Sym, Value};
+use parser::ast::{
+    Command, Else, GeometryClass, Literal, Matching, PredGroup, Predicate, Sym, Value,
+};
+use pattern::Patterns;
+use proj::Proj;
 
 pub mod circle;
 pub mod clear;
+pub mod fill;
+pub mod pattern;
 pub mod stroke;
 
 pub struct SymInput {
@@ -61,6 +68,14 @@ impl SymInput {
             Err(err) => Err(err),
         }
     }
+
+    /// The hex string of a colour, named colours are converted.
+    pub fn resolve_color(&self, value: Value) -> ApplyResult<String> {
+        let color = self.resolve_string(value)?;
+        Color::parse(&color)
+            .map(|c| c.to_string())
+            .ok_or_else(|| ApplyError::Resolve(format!("`{}` is not a colour", color)))
+    }
 }
 
 pub struct SymOuput {
@@ -89,35 +104,64 @@ pub fn exec_command<'a>(
         Command::Clear(c) => c.exec(&input),
         Command::Circle(c) => c.exec(&input),
         Command::Stroke(c) => c.exec(&input),
+        Command::Fill(c) => c.exec(&input),
         _ => Err(ApplyError::CommandNotFound),
     }
 }
 
+/// The projected geometry of a feature, if it has a usable one.
+pub fn feature_geometry(feature: &Feature, proj: &Proj) -> Option<Geometry> {
+    feature
+        .geometry
+        .as_ref()
+        .and_then(|g| from_geojson(g.clone(), proj))
+}
+
 pub fn exec_consequent(
     commands: Vec<Command>,
     source: Source,
     feature: &Feature,
+    geom: &Geometry,
 ) -> ApplyResult<SymOuput> {
     let init_output = Ok(SymOuput { ops: Vec::new() });
-    let opt_geom = feature.geometry.as_ref();
-    let proj = source.proj();
-    if let Some(geom) = opt_geom.and_then(|r| from_geojson(r.clone(), &proj)) {
-        commands.iter().fold(init_output, |acc, command| {
-            acc.and_then(|output| {
-                exec_command(command, source.clone(), feature, geom.clone(), output)
-            })
-        })
-    } else {
-        init_output
+    commands.iter().fold(init_output, |acc, command| {
+        acc.and_then(|output| exec_command(command, source.clone(), feature, geom.clone(), output))
+    })
+}
+
+fn geometry_is(class: GeometryClass, geom: &Geometry) -> bool {
+    match class {
+        GeometryClass::Point => matches!(geom, Geometry::Point(_) | Geometry::MultiPoint(_)),
+        GeometryClass::Line => matches!(
+            geom,
+            Geometry::Line(_) | Geometry::LineString(_) | Geometry::MultiLineString(_)
+        ),
+        GeometryClass::Polygon => matches!(
+            geom,
+            Geometry::Polygon(_)
+                | Geometry::MultiPolygon(_)
+                | Geometry::Rect(_)
+                | Geometry::Triangle(_)
+        ),
+        GeometryClass::Multi => matches!(
+            geom,
+            Geometry::MultiPoint(_) | Geometry::MultiLineString(_) | Geometry::MultiPolygon(_)
+        ),
+        GeometryClass::Collection => matches!(geom, Geometry::GeometryCollection(_)),
     }
 }
 
+/// `geom` is the projected feature geometry, a geometry class
+/// test is false without one.
 pub fn eval_predicate(
     predicate: Predicate,
+    patterns: &Patterns,
     source: Source,
     feature: &Feature,
+    geom: Option<&Geometry>,
 ) -> ApplyResult<bool> {
     match predicate {
+        Predicate::GeometryIs(class) => Ok(geom.is_some_and(|g| geometry_is(class, g))),
         Predicate::Equal((left, right)) => source
             .resolve(left, feature)
             .and_then(|left| source.resolve(right, feature).map(|right| left == right)),
@@ -136,52 +180,112 @@ pub fn eval_predicate(
         Predicate::LesserThanOrEqual((left, right)) => source
             .resolve(left, feature)
             .and_then(|left| source.resolve(right, feature).map(|right| left <= right)),
+        Predicate::In((left, right)) => source.resolve(left, feature).and_then(|left| {
+            source
+                .resolve(right, feature)
+                .map(|right| member(&left, &right))
+        }),
+        Predicate::NotIn((left, right)) => source.resolve(left, feature).and_then(|left| {
+            source
+                .resolve(right, feature)
+                .map(|right| !member(&left, &right))
+        }),
+        Predicate::Like(_)
+        | Predicate::ILike(_)
+        | Predicate::Match(_)
+        | Predicate::IMatch(_)
+        | Predicate::StartsWith(_) => {
+            let (left, right) = predicate
+                .operands()
+                .cloned()
+                .ok_or(ApplyError::Conversion)?;
+            let subject = source.resolve(left, feature)?;
+            let pattern = source.resolve(right, feature)?;
+            patterns.is_match(&predicate, &subject, &pattern)
+        }
+    }
+}
+
+fn member(item: &Literal, list: &Literal) -> bool {
+    match list {
+        Literal::List(items) => items.contains(item),
+        _ => item == list,
     }
 }
 
 pub fn eval_predicate_group<'a>(
     group: PredGroup,
+    patterns: &Patterns,
     source: Source,
     feature: &Feature,
+    geom: Option<&Geometry>,
 ) -> ApplyResult<bool> {
     match group {
         PredGroup::Empty => Ok(false),
-        PredGroup::Pred(predicate) => eval_predicate(predicate, source, feature),
-        PredGroup::And { left, right } => eval_predicate_group(*left, source.clone(), feature)
-            .and_then(|left| {
-                eval_predicate_group(*right, source.clone(), feature).map(|right| left && right)
-            }),
-        PredGroup::Or { left, right } => eval_predicate_group(*left, source.clone(), feature)
-            .and_then(|left| {
-                eval_predicate_group(*right, source.clone(), feature).map(|right| left || right)
-            }),
+        PredGroup::Pred(predicate) => eval_predicate(predicate, patterns, source, feature, geom),
+        PredGroup::Not(inner) => {
+            eval_predicate_group(*inner, patterns, source, feature, geom).map(|inner| !inner)
+        }
+        PredGroup::And { left, right } => {
+            eval_predicate_group(*left, patterns, source.clone(), feature, geom).and_then(|left| {
+                eval_predicate_group(*right, patterns, source.clone(), feature, geom)
+                    .map(|right| left && right)
+            })
+        }
+        PredGroup::Or { left, right } => {
+            eval_predicate_group(*left, patterns, source.clone(), feature, geom).and_then(|left| {
+                eval_predicate_group(*right, patterns, source.clone(), feature, geom)
+                    .map(|right| left || right)
+            })
+        }
     }
 }
 
-pub fn make_symbology_for_feature(
+/// `matched` holds, for each feature of the source, whether an earlier
+/// rule of the layer matched it. Fails early on a malformed pattern,
+/// failures on a single feature only skip that feature.
+pub fn make_symbology(
     sym: Sym,
     source: Source,
-    feature: &Feature,
+    matched: &mut [bool],
+    matching: Matching,
 ) -> ApplyResult<OpList> {
-    let group = sym.predicate.clone();
-    if eval_predicate_group(group, source.clone(), feature)? {
-        let commands = sym.consequent.clone();
-        let output = exec_consequent(commands, source.clone(), feature)?;
-        Ok(output.ops)
-    } else {
-        Ok(Vec::new())
+    let patterns = Patterns::compile(&sym.predicate)?;
+    let proj = source.proj();
+    let mut ops = Vec::new();
+    for (i, f) in source.iter().enumerate() {
+        if matching == Matching::First && matched[i] {
+            continue;
+        }
+        let group = sym.predicate.clone();
+        let geom = feature_geometry(f, &proj);
+        if let Ok(true) = eval_predicate_group(group, &patterns, source.clone(), f, geom.as_ref()) {
+            matched[i] = true;
+            if let Some(geom) = geom {
+                if let Ok(output) =
+                    exec_consequent(sym.consequent.clone(), source.clone(), f, &geom)
+                {
+                    ops.extend(output.ops);
+                }
+            }
+        }
     }
+    Ok(ops)
 }
 
-pub fn make_symbology(sym: Sym, source: Source) -> ApplyResult<OpList> {
-    let ops = source
-        .iter()
-        .enumerate()
-        .filter_map(|(i, f)| {
-            println!("[{}]", i);
-            make_symbology_for_feature(sym.clone(), source.clone(), f).ok()
-        })
-        .flatten()
-        .collect();
+/// Draws the features no earlier rule matched, they count as matched afterward.
+pub fn make_fallback(fallback: Else, source: Source, matched: &mut [bool]) -> ApplyResult<OpList> {
+    let proj = source.proj();
+    let mut ops = Vec::new();
+    for (i, f) in source.iter().enumerate() {
+        if !matched[i] {
+            matched[i] = true;
+            let output = feature_geometry(f, &proj)
+                .map(|geom| exec_consequent(fallback.consequent.clone(), source.clone(), f, &geom));
+            if let Some(Ok(output)) = output {
+                ops.extend(output.ops);
+            }
+        }
+    }
     Ok(ops)
 }
@@ -0,0 +1,200 @@
+//! Patterns of the `like`, `ilike`, `~` and `~*` predicates.
+//!
+//! Patterns known before looking at features, string literals and `data`
+//! holding one, are compiled once per `Sym`. The others are compiled
+//! when a feature is evaluated.
+
+use parser::ast::{Constructor, Literal, PredGroup, Predicate, Value};
+use regex::Regex;
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::error::{ApplyError, ApplyResult};
+
+/// Regex source of a `like` pattern, `%` matches any run of
+/// characters, newlines included, and `_` a single one. A `\` makes
+/// the character after it literal, as in `\%`, `\_` and `\\`.
+pub fn like_to_regex(pattern: &str, case_insensitive: bool) -> String {
+    let mut source = String::from(if case_insensitive { "(?si)^" } else { "(?s)^" });
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => source.push_str(".*"),
+            '_' => source.push('.'),
+            '\\' => {
+                let c = chars.next().unwrap_or('\\');
+                source.push_str(&regex::escape(&c.to_string()));
+            }
+            c => source.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    source.push('$');
+    source
+}
+
+fn regex_source(predicate: &Predicate, pattern: &str) -> Option<String> {
+    match predicate {
+        Predicate::Like(_) => Some(like_to_regex(pattern, false)),
+        Predicate::ILike(_) => Some(like_to_regex(pattern, true)),
+        Predicate::Match(_) => Some(String::from(pattern)),
+        Predicate::IMatch(_) => Some(format!("(?i){}", pattern)),
+        _ => None,
+    }
+}
+
+fn static_pattern(value: &Value) -> Option<&str> {
+    match value {
+        Value::Lit(Literal::String(s)) => Some(s),
+        Value::Data(d) => match d.constructor.as_ref() {
+            Constructor::Val(v) => static_pattern(v),
+            Constructor::Select(_) | Constructor::Param(_) => None,
+        },
+        _ => None,
+    }
+}
+
+fn compile(source: &str) -> ApplyResult<Regex> {
+    Regex::new(source).map_err(|err| ApplyError::Pattern(format!("{}", err)))
+}
+
+#[derive(Clone, Default)]
+pub struct Patterns {
+    compiled: HashMap<String, Regex>,
+}
+
+impl Patterns {
+    /// Fails on the first malformed pattern.
+    pub fn compile(group: &PredGroup) -> ApplyResult<Self> {
+        let mut patterns = Patterns::default();
+        patterns.collect(group)?;
+        Ok(patterns)
+    }
+
+    fn collect(&mut self, group: &PredGroup) -> ApplyResult<()> {
+        match group {
+            PredGroup::Empty => Ok(()),
+            PredGroup::Pred(predicate) => {
+                let source = predicate
+                    .operands()
+                    .and_then(|(_, right)| static_pattern(right))
+                    .and_then(|p| regex_source(predicate, p));
+                if let Some(source) = source {
+                    if let Entry::Vacant(entry) = self.compiled.entry(source) {
+                        let regex = compile(entry.key())?;
+                        entry.insert(regex);
+                    }
+                }
+                Ok(())
+            }
+            PredGroup::Not(inner) => self.collect(inner),
+            PredGroup::And { left, right } | PredGroup::Or { left, right } => {
+                self.collect(left)?;
+                self.collect(right)
+            }
+        }
+    }
+
+    /// Tests `subject` against `pattern` the way `predicate` says,
+    /// a nil subject never matches.
+    pub fn is_match(
+        &self,
+        predicate: &Predicate,
+        subject: &Literal,
+        pattern: &Literal,
+    ) -> ApplyResult<bool> {
+        let pattern = match pattern {
+            Literal::String(s) => s,
+            _ => {
+                return Err(ApplyError::Pattern(format!(
+                    "{} is not a string pattern",
+                    pattern
+                )))
+            }
+        };
+        let subject = match subject {
+            Literal::Nil => return Ok(false),
+            Literal::String(s) => s.clone(),
+            _ => format!("{}", subject),
+        };
+        if let Predicate::StartsWith(_) = predicate {
+            return Ok(subject.starts_with(pattern.as_str()));
+        }
+        let source = regex_source(predicate, pattern)
+            .ok_or_else(|| ApplyError::Pattern(String::from("not a pattern predicate")))?;
+        match self.compiled.get(&source) {
+            Some(regex) => Ok(regex.is_match(&subject)),
+            None => compile(&source).map(|regex| regex.is_match(&subject)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pattern_test {
+    use super::*;
+    use crate::map_source;
+    use parser::ast::Directive;
+    use parser::parse_str;
+
+    fn predicate(pred: &str) -> PredGroup {
+        let layer = format!("source geojson \"a.geojson\"\nsym {} -> clear\n", pred);
+        let map_str = map_source("", &[&layer]);
+        match &parse_str(&map_str).unwrap().layers[0].directives[1] {
+            Directive::Sym(s) => s.predicate.clone(),
+            other => panic!("unexpected directive {:?}", other),
+        }
+    }
+
+    fn matches(pred: &str, subject: &str) -> bool {
+        let group = predicate(pred);
+        let patterns = Patterns::compile(&group).unwrap();
+        match group {
+            PredGroup::Pred(p) => {
+                let (_, right) = p.operands().unwrap();
+                let pattern = match right {
+                    Value::Lit(l) => l.clone(),
+                    _ => panic!("pattern should be a literal"),
+                };
+                patterns
+                    .is_match(&p, &Literal::from(subject), &pattern)
+                    .unwrap()
+            }
+            _ => panic!("a single predicate"),
+        }
+    }
+
+    #[test]
+    fn like() {
+        assert_eq!(like_to_regex("Rue %", false), "(?s)^Rue .*$");
+        assert!(matches("true like \"Rue %\"", "Rue Haute"));
+        assert!(!matches("true like \"Rue %\"", "rue Haute"));
+        assert!(matches("true ilike \"rue %\"", "Rue Haute"));
+        assert!(matches("true like \"a_c.\"", "abc."));
+        assert!(!matches("true like \"a_c.\"", "abcd"));
+        assert!(matches("true like \"a%\"", "a\nb"));
+        assert!(matches("true like \"a_b\"", "a\nb"));
+    }
+
+    #[test]
+    fn like_escape() {
+        assert_eq!(like_to_regex("100\\%", false), "(?s)^100%$");
+        assert!(matches("true like \"100\\\\%\"", "100%"));
+        assert!(!matches("true like \"100\\\\%\"", "1000"));
+        assert!(matches("true like \"a\\\\_b\"", "a_b"));
+        assert!(!matches("true like \"a\\\\_b\"", "acb"));
+        assert!(matches("true like \"C:\\\\\\\\%\"", "C:\\dir"));
+    }
+
+    #[test]
+    fn regex_and_prefix() {
+        assert!(matches("true ~ \"^(Av|Bd)\\\\.\"", "Av. Louise"));
+        assert!(!matches("true ~ \"^(Av|Bd)\\\\.\"", "Avenue Louise"));
+        assert!(matches("true ~* \"^bd\"", "Bd Anspach"));
+        assert!(matches("true ^= \"Rue\"", "Rue Haute"));
+        assert!(!matches("true ^= \"Rue\"", "Avenue"));
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Patterns::compile(&predicate("true ~ \"(unclosed\"")).is_err());
+        assert!(Patterns::compile(&predicate("true like \"(fine\"")).is_ok());
+    }
+}
@@ -7,7 +7,7 @@ use super::{SymCommand, SymInput, SymOuput};
 impl SymCommand for Stroke {
     fn exec(&self, input: &SymInput) -> ApplyResult<SymOuput> {
         let size = input.resolve_float(self.size.clone())?;
-        let color = input.resolve_string(self.color.clone())?;
+        let color = input.resolve_color(self.color.clone())?;
         Ok(input.concat_ops(vec![stroke(color, size)]))
     }
 }
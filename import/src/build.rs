@@ -0,0 +1,203 @@
+use parser::ast::{
+    Constructor, Data, DataType, Directive, Extent, FunctionCall, Literal, MapBlock, Num,
+    PredGroup, Select, Srid, Value,
+};
+
+/// Half of the meridian length in web mercator meters.
+pub const HALF_WORLD: f64 = 20_037_508.342_789_244;
+
+/// A web mercator map, `half_width` meters around the center.
+pub fn web_map((x, y): (f64, f64), half_width: f64) -> MapBlock {
+    let bound = |v: f64| num(v.round());
+    let extent = Extent {
+        minx: bound(x - half_width),
+        miny: bound(y - half_width),
+        maxx: bound(x + half_width),
+        maxy: bound(y + half_width),
+    };
+    MapBlock {
+        directives: vec![Srid { value: 3857 }.into(), extent.into()],
+        positions: Vec::new(),
+    }
+}
+
+/// Scale denominator of a web map zoom level, 512 pixel tiles
+/// and the OGC 0.28mm pixel.
+pub fn zoom_scale(zoom: f64) -> Num {
+    let resolution = 40_075_016.686 / (512.0 * 2f64.powf(zoom));
+    Num::Integer((resolution / 0.00028).round() as i64)
+}
+
+/// Whole numbers are kept as integers.
+pub fn num(n: f64) -> Num {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        Num::Integer(n as i64)
+    } else {
+        Num::Float(n)
+    }
+}
+
+pub fn number(n: f64) -> Value {
+    Value::Lit(Literal::Number(num(n)))
+}
+
+pub fn rgb(color: [u8; 3]) -> Value {
+    rgba(color, 1.0)
+}
+
+/// `rgb(..)` when opaque, `rgba(..)` otherwise.
+pub fn rgba(color: [u8; 3], alpha: f64) -> Value {
+    let mut args: Vec<Value> = color
+        .iter()
+        .map(|c| Value::Lit(Literal::Number(Num::Integer(i64::from(*c)))))
+        .collect();
+    let name = if alpha < 1.0 {
+        args.push(number((alpha.max(0.0) * 100.0).round() / 100.0));
+        "rgba"
+    } else {
+        "rgb"
+    };
+    Value::Fn(FunctionCall {
+        name: String::from(name),
+        args,
+    })
+}
+
+/// Both sides when there are two, an empty group stands for no condition.
+pub fn and(left: PredGroup, right: PredGroup) -> PredGroup {
+    match (left, right) {
+        (PredGroup::Empty, right) => right,
+        (left, PredGroup::Empty) => left,
+        (left, right) => PredGroup::And {
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+    }
+}
+
+pub fn or(left: PredGroup, right: PredGroup) -> PredGroup {
+    PredGroup::Or {
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+const NAMED_COLORS: [(&str, [u8; 3]); 12] = [
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("gray", [128, 128, 128]),
+    ("grey", [128, 128, 128]),
+    ("silver", [192, 192, 192]),
+    ("red", [255, 0, 0]),
+    ("maroon", [128, 0, 0]),
+    ("orange", [255, 165, 0]),
+    ("yellow", [255, 255, 0]),
+    ("green", [0, 128, 0]),
+    ("blue", [0, 0, 255]),
+    ("navy", [0, 0, 128]),
+];
+
+fn hex_digits(digits: &str) -> Option<Vec<u8>> {
+    match digits.len() {
+        3 | 4 => digits
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| (d * 17) as u8))
+            .collect(),
+        6 | 8 => (0..digits.len())
+            .step_by(2)
+            .map(|i| {
+                digits
+                    .get(i..i + 2)
+                    .and_then(|d| u8::from_str_radix(d, 16).ok())
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn functional(args: &str) -> Option<Vec<f64>> {
+    args.split(',').map(|a| a.trim().parse().ok()).collect()
+}
+
+/// A CSS colour as red, green, blue and alpha, for the `#rgb`, `#rrggbb`,
+/// `rgb()` and `rgba()` forms and a few names.
+pub fn css_color(color: &str) -> Option<([u8; 3], f64)> {
+    let color = color.trim().to_lowercase();
+    let channels = if let Some(digits) = color.strip_prefix('#') {
+        hex_digits(digits).map(|c| {
+            let alpha = c.get(3).map_or(1.0, |a| f64::from(*a) / 255.0);
+            (vec![c[0], c[1], c[2]], alpha)
+        })
+    } else if let Some(args) = color
+        .strip_prefix("rgba(")
+        .or_else(|| color.strip_prefix("rgb("))
+        .and_then(|a| a.strip_suffix(')'))
+    {
+        functional(args)
+            .filter(|c| c.len() == 3 || c.len() == 4)
+            .map(|c| {
+                let rgb = c.iter().take(3).map(|v| v.round().clamp(0.0, 255.0) as u8);
+                (rgb.collect(), c.get(3).copied().unwrap_or(1.0))
+            })
+    } else {
+        NAMED_COLORS
+            .iter()
+            .find(|(name, _)| *name == color)
+            .map(|(_, c)| (c.to_vec(), 1.0))
+    };
+    channels.map(|(c, alpha)| ([c[0], c[1], c[2]], alpha))
+}
+
+/// A `data` identifier for a property name.
+fn ident(property: &str) -> String {
+    let ident: String = property
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '_' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    match ident.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => ident,
+        _ => format!("p_{}", ident),
+    }
+}
+
+/// The properties a layer reads, each gets a `data` directive.
+#[derive(Default)]
+pub struct Properties {
+    data: Vec<Data>,
+}
+
+impl Properties {
+    /// A reference to a property, the type of its first use sticks.
+    pub fn get(&mut self, property: &str, datatype: DataType) -> Value {
+        let existing = self.data.iter().find(|d| match d.constructor.as_ref() {
+            Constructor::Select(s) => s.selector == property,
+            Constructor::Val(_) | Constructor::Param(_) => false,
+        });
+        if let Some(data) = existing {
+            return Value::Data(data.clone());
+        }
+        let base = ident(property);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.data.iter().any(|d| d.ident == name) {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        let data = Data {
+            ident: name,
+            constructor: Box::new(Constructor::Select(Select {
+                selector: String::from(property),
+                datatype,
+            })),
+        };
+        self.data.push(data.clone());
+        Value::Data(data)
+    }
+
+    pub fn directives(&self) -> Vec<Directive> {
+        self.data.iter().cloned().map(Directive::from).collect()
+    }
+}
@@ -0,0 +1,18 @@
+#[derive(Debug)]
+pub enum ImportError {
+    Json(String),
+    Xml(String),
+    Document(String),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Json(desc) => write!(f, "Json {}", desc),
+            ImportError::Xml(desc) => write!(f, "Xml {}", desc),
+            ImportError::Document(desc) => write!(f, "Document {}", desc),
+        }
+    }
+}
+
+pub type ImportResult<T> = Result<T, ImportError>;
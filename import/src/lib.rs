@@ -0,0 +1,31 @@
+mod build;
+pub mod error;
+pub mod maplibre;
+pub mod sld;
+
+use parser::ast::MapSpec;
+use std::fmt;
+
+pub use error::{ImportError, ImportResult};
+
+/// A part of the imported document that has no counterpart in a map spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// the layer it was found in, if any
+    pub layer: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.layer {
+            Some(layer) => write!(f, "layer {}: {}", layer, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub struct Imported {
+    pub spec: MapSpec,
+    pub warnings: Vec<Warning>,
+}
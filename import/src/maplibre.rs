@@ -0,0 +1,631 @@
+//! MapLibre (and Mapbox GL) style documents: GeoJSON sources
+//! and `fill`, `line` and `circle` layers.
+//!
+//! Sizes are copied as they are, in pixels in the style and
+//! in map units in the spec.
+
+use parser::ast::{
+    Circle, Command, DataType, Directive, Driver, Else, Fill, GeometryClass, LayerBlock, Literal,
+    MapBlock, MapSpec, Name, Num, Opacity, PredGroup, Predicate, ScaleRange, Source, Stroke, Sym,
+    Value, Visible,
+};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+
+use crate::{
+    build::{
+        and, css_color, num, number, or, rgb, rgba, web_map, zoom_scale, Properties, HALF_WORLD,
+    },
+    ImportError, ImportResult, Imported, Warning,
+};
+
+/// Width in pixels of the view the style center and zoom describe.
+const VIEW_SIZE: f64 = 1024.0;
+
+pub fn import_style(style: &str) -> ImportResult<Imported> {
+    let style: JsonValue =
+        serde_json::from_str(style).map_err(|e| ImportError::Json(e.to_string()))?;
+    from_value(&style)
+}
+
+pub fn from_value(style: &JsonValue) -> ImportResult<Imported> {
+    let style = style
+        .as_object()
+        .ok_or_else(|| ImportError::Document(String::from("a style is an object")))?;
+    let mut warnings = Vec::new();
+    let map = map_block(style, &mut warnings);
+    let sources = sources(style, &mut warnings);
+    let layers = style
+        .get("layers")
+        .and_then(|l| l.as_array())
+        .ok_or_else(|| ImportError::Document(String::from("a style has a list of layers")))?
+        .iter()
+        .filter_map(|layer| LayerImport::new(layer, &mut warnings).layer(layer, &sources))
+        .collect();
+    Ok(Imported {
+        spec: MapSpec { map, layers },
+        warnings,
+    })
+}
+
+fn warning(message: String) -> Warning {
+    Warning {
+        layer: None,
+        message,
+    }
+}
+
+fn mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let x = lon * HALF_WORLD / 180.0;
+    let y = ((90.0 + lat).to_radians() / 2.0).tan().ln() * HALF_WORLD / std::f64::consts::PI;
+    (x, y)
+}
+
+/// Web mercator, the extent around the style center at its zoom level.
+fn map_block(style: &Map<String, JsonValue>, warnings: &mut Vec<Warning>) -> MapBlock {
+    let center = style
+        .get("center")
+        .and_then(|c| c.as_array())
+        .and_then(|c| {
+            match (
+                c.first().and_then(|x| x.as_f64()),
+                c.get(1).and_then(|y| y.as_f64()),
+            ) {
+                (Some(lon), Some(lat)) => Some((lon, lat)),
+                _ => None,
+            }
+        });
+    let zoom = style.get("zoom").and_then(|z| z.as_f64());
+    match (center, zoom) {
+        (Some((lon, lat)), Some(zoom)) => {
+            let half_width = VIEW_SIZE / 2.0 * 2.0 * HALF_WORLD / (512.0 * 2f64.powf(zoom));
+            web_map(mercator(lon, lat), half_width)
+        }
+        _ => {
+            warnings.push(warning(String::from(
+                "the style has no center and zoom, the extent is the whole world",
+            )));
+            web_map((0.0, 0.0), HALF_WORLD)
+        }
+    }
+}
+
+/// Paths of the GeoJSON sources that point to a file or URL.
+fn sources(style: &Map<String, JsonValue>, warnings: &mut Vec<Warning>) -> HashMap<String, String> {
+    let mut paths = HashMap::new();
+    let sources = style.get("sources").and_then(|s| s.as_object());
+    for (name, source) in sources.into_iter().flatten() {
+        let kind = source.get("type").and_then(|t| t.as_str()).unwrap_or("");
+        match (kind, source.get("data")) {
+            ("geojson", Some(JsonValue::String(path))) => {
+                paths.insert(name.clone(), path.clone());
+            }
+            ("geojson", _) => warnings.push(warning(format!(
+                "source {}: inline GeoJSON data is not supported",
+                name
+            ))),
+            (kind, _) => warnings.push(warning(format!(
+                "source {}: type `{}` is not supported",
+                name, kind
+            ))),
+        }
+    }
+    paths
+}
+
+struct LayerImport<'a> {
+    id: String,
+    warnings: &'a mut Vec<Warning>,
+    properties: Properties,
+}
+
+type Paint = Map<String, JsonValue>;
+
+impl<'a> LayerImport<'a> {
+    fn new(layer: &JsonValue, warnings: &'a mut Vec<Warning>) -> Self {
+        let id = layer
+            .get("id")
+            .and_then(|i| i.as_str())
+            .unwrap_or("(unnamed)");
+        LayerImport {
+            id: String::from(id),
+            warnings,
+            properties: Properties::default(),
+        }
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(Warning {
+            layer: Some(self.id.clone()),
+            message,
+        });
+    }
+
+    fn layer(mut self, layer: &JsonValue, sources: &HashMap<String, String>) -> Option<LayerBlock> {
+        let kind = layer.get("type").and_then(|t| t.as_str()).unwrap_or("");
+        let class = match kind {
+            "fill" => PredGroup::Pred(Predicate::GeometryIs(GeometryClass::Polygon)),
+            "circle" => PredGroup::Pred(Predicate::GeometryIs(GeometryClass::Point)),
+            "line" => PredGroup::Empty,
+            _ => {
+                self.warn(format!("type `{}` is not supported, skipped", kind));
+                return None;
+            }
+        };
+        let source = layer.get("source").and_then(|s| s.as_str()).unwrap_or("");
+        let path = match sources.get(source) {
+            Some(path) => path.clone(),
+            None => {
+                self.warn(format!("source `{}` is not available, skipped", source));
+                return None;
+            }
+        };
+        let filter = match layer.get("filter").map(|f| self.filter(f)) {
+            None => PredGroup::Empty,
+            Some(Ok(filter)) => filter,
+            Some(Err(message)) => {
+                self.warn(format!("{}, skipped", message));
+                return None;
+            }
+        };
+        let empty = Map::new();
+        let paint = layer
+            .get("paint")
+            .and_then(|p| p.as_object())
+            .unwrap_or(&empty);
+        let layout = layer
+            .get("layout")
+            .and_then(|p| p.as_object())
+            .unwrap_or(&empty);
+        let commands = self.paint(kind, paint);
+
+        let mut directives: Vec<Directive> = vec![Name {
+            value: self.id.clone(),
+        }
+        .into()];
+        if !self.visible(layout) {
+            directives.push(Visible { value: false }.into());
+        }
+        let scale = ScaleRange {
+            min: layer
+                .get("maxzoom")
+                .and_then(|z| z.as_f64())
+                .map(zoom_scale),
+            max: layer
+                .get("minzoom")
+                .and_then(|z| z.as_f64())
+                .map(zoom_scale),
+        };
+        if !scale.is_open() {
+            directives.push(scale.into());
+        }
+        if let Some(opacity) = self.opacity(paint, &format!("{}-opacity", kind)) {
+            directives.push(Opacity { value: opacity }.into());
+        }
+        directives.push(
+            Source {
+                driver: Driver::Geojson,
+                path,
+                srid: Some(Num::Integer(4326)),
+            }
+            .into(),
+        );
+        directives.extend(self.properties.directives());
+        directives.push(match and(filter, class) {
+            PredGroup::Empty => Else {
+                consequent: commands,
+            }
+            .into(),
+            predicate => Sym {
+                scale: ScaleRange::default(),
+                predicate,
+                consequent: commands,
+            }
+            .into(),
+        });
+        Some(LayerBlock {
+            directives,
+            positions: Vec::new(),
+        })
+    }
+
+    fn visible(&mut self, layout: &Paint) -> bool {
+        let mut visible = true;
+        for (key, value) in layout.iter() {
+            match key.as_str() {
+                "visibility" => visible = value.as_str() != Some("none"),
+                _ => self.warn(format!("layout property `{}` is not supported", key)),
+            }
+        }
+        visible
+    }
+
+    fn paint(&mut self, kind: &str, paint: &Paint) -> Vec<Command> {
+        let known: &[&str] = match kind {
+            "fill" => &["fill-color", "fill-outline-color", "fill-opacity"],
+            "line" => &["line-color", "line-width", "line-opacity"],
+            _ => &[
+                "circle-radius",
+                "circle-color",
+                "circle-stroke-color",
+                "circle-stroke-width",
+                "circle-opacity",
+            ],
+        };
+        for key in paint.keys().filter(|k| !known.contains(&k.as_str())) {
+            self.warn(format!("paint property `{}` is not supported", key));
+        }
+        match kind {
+            "fill" => {
+                let mut commands = vec![Command::Fill(Fill {
+                    color: self.color(paint, "fill-color"),
+                })];
+                if paint.contains_key("fill-outline-color") {
+                    commands.push(Command::Stroke(Stroke {
+                        color: self.color(paint, "fill-outline-color"),
+                        size: number(1.0),
+                    }));
+                }
+                commands
+            }
+            "line" => vec![Command::Stroke(Stroke {
+                color: self.color(paint, "line-color"),
+                size: self.size(paint, "line-width", 1.0),
+            })],
+            _ => {
+                let mut commands = vec![
+                    Command::Circle(Circle {
+                        radius: self.size(paint, "circle-radius", 5.0),
+                    }),
+                    Command::Fill(Fill {
+                        color: self.color(paint, "circle-color"),
+                    }),
+                ];
+                if paint.contains_key("circle-stroke-width") {
+                    commands.push(Command::Stroke(Stroke {
+                        color: self.color(paint, "circle-stroke-color"),
+                        size: self.size(paint, "circle-stroke-width", 0.0),
+                    }));
+                }
+                commands
+            }
+        }
+    }
+
+    /// Black when missing or not understood.
+    fn color(&mut self, paint: &Paint, key: &str) -> Value {
+        let black = [0, 0, 0];
+        match paint.get(key) {
+            None => rgb(black),
+            Some(JsonValue::String(color)) => match css_color(color) {
+                Some((channels, alpha)) => rgba(channels, alpha),
+                None => {
+                    self.warn(format!("colour `{}` of `{}` is not supported", color, key));
+                    rgb(black)
+                }
+            },
+            Some(_) => {
+                self.warn(format!("`{}` is not a constant colour", key));
+                rgb(black)
+            }
+        }
+    }
+
+    /// A number or a `["get", property]` expression.
+    fn size(&mut self, paint: &Paint, key: &str, default: f64) -> Value {
+        match paint.get(key) {
+            None => number(default),
+            Some(JsonValue::Number(n)) => number(n.as_f64().unwrap_or(default)),
+            Some(value) => match property(value) {
+                Some(name) => self.properties.get(name, DataType::Number),
+                None => {
+                    self.warn(format!("`{}` is not supported, {} is used", key, default));
+                    number(default)
+                }
+            },
+        }
+    }
+
+    fn opacity(&mut self, paint: &Paint, key: &str) -> Option<Num> {
+        match paint.get(key) {
+            None => None,
+            Some(JsonValue::Number(n)) => n.as_f64().filter(|o| *o < 1.0).map(num),
+            Some(_) => {
+                self.warn(format!("`{}` is not a constant", key));
+                None
+            }
+        }
+    }
+
+    /// Both the legacy filters and the expression forms.
+    fn filter(&mut self, filter: &JsonValue) -> Result<PredGroup, String> {
+        let items = match filter {
+            JsonValue::Bool(true) => return Ok(PredGroup::Empty),
+            JsonValue::Array(items) => items,
+            _ => return Err(format!("filter `{}` is not supported", filter)),
+        };
+        let (op, args) = match items.split_first() {
+            Some((JsonValue::String(op), args)) => (op.as_str(), args),
+            _ => return Err(format!("filter `{}` is not supported", filter)),
+        };
+        match op {
+            "all" => args.iter().try_fold(PredGroup::Empty, |acc, f| {
+                self.filter(f).map(|f| and(acc, f))
+            }),
+            "any" | "none" => {
+                let mut groups = args.iter().map(|f| self.filter(f));
+                let first = groups
+                    .next()
+                    .unwrap_or_else(|| Err(format!("an empty `{}` is not supported", op)))?;
+                let any = groups.try_fold(first, |acc, f| f.map(|f| or(acc, f)))?;
+                Ok(match op {
+                    "none" => PredGroup::Not(Box::new(any)),
+                    _ => any,
+                })
+            }
+            "!" => match args {
+                [f] => self.filter(f).map(|f| PredGroup::Not(Box::new(f))),
+                _ => Err(String::from("`!` takes one filter")),
+            },
+            "==" | "!=" | "<" | "<=" | ">" | ">=" => self.comparison(op, args),
+            "in" | "!in" => self.membership(op, args),
+            _ => Err(format!("filter `{}` is not supported", op)),
+        }
+    }
+
+    fn comparison(&mut self, op: &str, args: &[JsonValue]) -> Result<PredGroup, String> {
+        let (left, right) = match args {
+            [left, right] => (left, right),
+            _ => return Err(format!("`{}` takes two operands", op)),
+        };
+        if is_geometry_type(left) {
+            let class = geometry_class(right)?;
+            return match op {
+                "==" => Ok(class),
+                "!=" => Ok(PredGroup::Not(Box::new(class))),
+                _ => Err(format!("`{}` does not apply to the geometry type", op)),
+            };
+        }
+        let right = literal(right)?;
+        let left = self.subject(left, datatype(&right))?;
+        let pair = (left, Value::Lit(right));
+        let predicate = match op {
+            "==" => Predicate::Equal(pair),
+            "!=" => Predicate::NotEqual(pair),
+            "<" => Predicate::LesserThan(pair),
+            "<=" => Predicate::LesserThanOrEqual(pair),
+            ">" => Predicate::GreaterThan(pair),
+            _ => Predicate::GreaterThanOrEqual(pair),
+        };
+        Ok(PredGroup::Pred(predicate))
+    }
+
+    /// `["in", key, v, ...]` or `["in", ["get", key], ["literal", [v, ...]]]`.
+    fn membership(&mut self, op: &str, args: &[JsonValue]) -> Result<PredGroup, String> {
+        let (subject, values) = match args.split_first() {
+            Some((subject @ JsonValue::String(_), values)) => (subject, values.to_vec()),
+            Some((subject, [values])) => match values.as_array().map(|v| v.as_slice()) {
+                Some([JsonValue::String(l), JsonValue::Array(values)]) if l == "literal" => {
+                    (subject, values.clone())
+                }
+                _ => return Err(format!("`{}` expects a literal list", op)),
+            },
+            _ => return Err(format!("`{}` expects a subject and values", op)),
+        };
+        let group = if is_geometry_type(subject) {
+            let mut classes = values.iter().map(geometry_class);
+            let first = classes
+                .next()
+                .unwrap_or_else(|| Err(format!("an empty `{}` is not supported", op)))?;
+            classes.try_fold(first, |acc, c| c.map(|c| or(acc, c)))?
+        } else {
+            let mut values = values
+                .iter()
+                .map(literal)
+                .collect::<Result<Vec<Literal>, String>>()?;
+            let dt = values.first().map_or(DataType::String, datatype);
+            let subject = self.subject(subject, dt)?;
+            let values = match values.len() {
+                1 => values.remove(0),
+                _ => Literal::List(values),
+            };
+            PredGroup::Pred(Predicate::In((subject, Value::Lit(values))))
+        };
+        Ok(match op {
+            "!in" => PredGroup::Not(Box::new(group)),
+            _ => group,
+        })
+    }
+
+    /// A property, named as is in legacy filters.
+    fn subject(&mut self, subject: &JsonValue, datatype: DataType) -> Result<Value, String> {
+        match subject {
+            JsonValue::String(name) if !name.starts_with('$') => {
+                Ok(self.properties.get(name, datatype))
+            }
+            _ => match property(subject) {
+                Some(name) => Ok(self.properties.get(name, datatype)),
+                None => Err(format!("filter operand `{}` is not supported", subject)),
+            },
+        }
+    }
+}
+
+/// The property of a `["get", property]` expression.
+fn property(value: &JsonValue) -> Option<&str> {
+    match value.as_array().map(|v| v.as_slice()) {
+        Some([JsonValue::String(get), JsonValue::String(name)]) if get == "get" => Some(name),
+        _ => None,
+    }
+}
+
+fn is_geometry_type(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::String(key) => key == "$type",
+        JsonValue::Array(items) => items.len() == 1 && items[0] == "geometry-type",
+        _ => false,
+    }
+}
+
+/// A geometry type covers its multi part form.
+fn geometry_class(value: &JsonValue) -> Result<PredGroup, String> {
+    let class = match value.as_str() {
+        Some("Point") | Some("MultiPoint") => GeometryClass::Point,
+        Some("LineString") | Some("MultiLineString") => GeometryClass::Line,
+        Some("Polygon") | Some("MultiPolygon") => GeometryClass::Polygon,
+        _ => return Err(format!("geometry type `{}` is not supported", value)),
+    };
+    Ok(PredGroup::Pred(Predicate::GeometryIs(class)))
+}
+
+fn literal(value: &JsonValue) -> Result<Literal, String> {
+    match value {
+        JsonValue::Null => Ok(Literal::Nil),
+        JsonValue::Bool(b) => Ok(Literal::Boolean(*b)),
+        JsonValue::String(s) => Ok(Literal::String(s.clone())),
+        JsonValue::Number(n) => Ok(Literal::Number(match n.as_i64() {
+            Some(i) => Num::Integer(i),
+            None => Num::Float(n.as_f64().unwrap_or(0.0)),
+        })),
+        _ => Err(format!("filter value `{}` is not supported", value)),
+    }
+}
+
+fn datatype(literal: &Literal) -> DataType {
+    match literal {
+        Literal::Number(_) => DataType::Number,
+        Literal::Boolean(_) => DataType::Boolean,
+        _ => DataType::String,
+    }
+}
+
+#[cfg(test)]
+mod maplibre_test {
+    use super::*;
+    use parser::{format_spec, parse_str};
+
+    const STYLE: &str = r##"{
+        "version": 8,
+        "center": [0, 0],
+        "zoom": 10,
+        "sources": {
+            "parcels": {"type": "geojson", "data": "parcels.geojson"},
+            "tiles": {"type": "vector", "url": "https://example.com/tiles.json"}
+        },
+        "layers": [
+            {"id": "background", "type": "background"},
+            {
+                "id": "parks",
+                "type": "fill",
+                "source": "parcels",
+                "filter": ["all", ["==", "kind", "park"], [">=", ["get", "area"], 100]],
+                "paint": {
+                    "fill-color": "#3c3",
+                    "fill-outline-color": "rgba(0, 0, 0, 0.5)",
+                    "fill-opacity": 0.8,
+                    "fill-antialias": true
+                }
+            },
+            {
+                "id": "paths",
+                "type": "line",
+                "source": "parcels",
+                "minzoom": 12,
+                "layout": {"visibility": "none", "line-cap": "round"},
+                "filter": ["in", "kind", "path", "track"],
+                "paint": {"line-color": "#804000", "line-width": ["get", "width"]}
+            },
+            {
+                "id": "trees",
+                "type": "circle",
+                "source": "parcels",
+                "filter": ["!", ["in", ["get", "kind"], ["literal", ["stump"]]]],
+                "paint": {"circle-radius": 3, "circle-color": "green"}
+            },
+            {"id": "roads", "type": "line", "source": "tiles"},
+            {
+                "id": "odd",
+                "type": "line",
+                "source": "parcels",
+                "filter": ["has", "kind"]
+            }
+        ]
+    }"##;
+
+    #[test]
+    fn import() {
+        let imported = import_style(STYLE).unwrap();
+        let expected = "map
+srid 3857
+extent -39136 -39136 39136 39136
+
+
+layer
+name \"parks\"
+opacity 0.8
+source geojson \"parcels.geojson\" 4326
+data kind select \"kind\" string
+data area select \"area\" number
+sym kind = \"park\"
+  & area >= 100
+  & geometry is polygon
+  -> fill rgb(51, 204, 51)
+  -> stroke rgba(0, 0, 0, 0.5) 1
+
+
+layer
+name \"paths\"
+visible false
+maxscale 68247
+source geojson \"parcels.geojson\" 4326
+data kind select \"kind\" string
+data width select \"width\" number
+sym kind in (\"path\", \"track\") -> stroke rgb(128, 64, 0) width
+
+
+layer
+name \"trees\"
+source geojson \"parcels.geojson\" 4326
+data kind select \"kind\" string
+sym !kind in \"stump\"
+  & geometry is point
+  -> circle 3
+  -> fill rgb(0, 128, 0)
+";
+        let formatted = format_spec(&imported.spec);
+        assert_eq!(formatted, expected);
+        assert!(parse_str(&formatted).is_ok());
+
+        let warnings: Vec<String> = imported.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "source tiles: type `vector` is not supported",
+                "layer background: type `background` is not supported, skipped",
+                "layer parks: paint property `fill-antialias` is not supported",
+                "layer paths: layout property `line-cap` is not supported",
+                "layer roads: source `tiles` is not available, skipped",
+                "layer odd: filter `has` is not supported, skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn geometry_type_filters() {
+        let mut warnings = Vec::new();
+        let layer = serde_json::json!({"id": "l"});
+        let mut import = LayerImport::new(&layer, &mut warnings);
+        let filter = serde_json::json!(["in", "$type", "Point", "LineString"]);
+        let group = import.filter(&filter).unwrap();
+        assert!(matches!(group, PredGroup::Or { .. }));
+        let filter = serde_json::json!(["!=", ["geometry-type"], "Polygon"]);
+        let group = import.filter(&filter).unwrap();
+        assert!(matches!(
+            group,
+            PredGroup::Not(g) if matches!(*g, PredGroup::Pred(Predicate::GeometryIs(GeometryClass::Polygon)))
+        ));
+        assert!(import
+            .filter(&serde_json::json!(["<", "$type", "Point"]))
+            .is_err());
+    }
+}
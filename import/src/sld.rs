@@ -0,0 +1,658 @@
+//! OGC Styled Layer Descriptor 1.0 and Symbology Encoding 1.1 documents,
+//! elements are matched on their local name whatever their namespace.
+//!
+//! Rules without a filter draw every feature, they become an `else`
+//! at the head of the layer, `ElseFilter` rules one at its end.
+
+use parser::ast::{
+    Circle, Command, DataType, Directive, Driver, Else, Fill, LayerBlock, Literal, MapSpec, Name,
+    Num, PredGroup, Predicate, ScaleRange, Source, Square, Stroke, Sym, Value,
+};
+use roxmltree::{Document, Node};
+
+use crate::{
+    build::{and, css_color, num, number, or, rgb, rgba, web_map, Properties, HALF_WORLD},
+    ImportError, ImportResult, Imported, Warning,
+};
+
+/// `source` is the path of the GeoJSON file the layers draw.
+pub fn import_sld(document: &str, source: &str) -> ImportResult<Imported> {
+    let document = Document::parse(document).map_err(|e| ImportError::Xml(e.to_string()))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "StyledLayerDescriptor" {
+        return Err(ImportError::Document(String::from(
+            "an SLD document has a StyledLayerDescriptor root",
+        )));
+    }
+    let mut warnings = vec![Warning {
+        layer: None,
+        message: String::from("an SLD has no extent, the extent is the whole world"),
+    }];
+    let layers = root
+        .children()
+        .filter(|n| is(n, "NamedLayer") || is(n, "UserLayer"))
+        .filter_map(|layer| LayerImport::new(layer, &mut warnings).layer(layer, source))
+        .collect();
+    Ok(Imported {
+        spec: MapSpec {
+            map: web_map((0.0, 0.0), HALF_WORLD),
+            layers,
+        },
+        warnings,
+    })
+}
+
+fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| is(n, name))
+}
+
+fn elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(|n| n.is_element())
+}
+
+fn text(node: Node) -> String {
+    node.text().map(|t| t.trim()).unwrap_or("").to_string()
+}
+
+/// A `CssParameter` (SLD) or `SvgParameter` (SE) by its name.
+fn parameter<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .filter(|n| is(n, "CssParameter") || is(n, "SvgParameter"))
+        .find(|n| n.attribute("name") == Some(name))
+}
+
+/// Numbers stay numbers, anything else is a string.
+fn literal(value: &str) -> Literal {
+    match (value.parse::<i64>(), value.parse::<f64>()) {
+        (Ok(i), _) => Literal::Number(Num::Integer(i)),
+        (_, Ok(f)) => Literal::Number(Num::Float(f)),
+        _ => Literal::String(String::from(value)),
+    }
+}
+
+fn datatype(literal: &Literal) -> DataType {
+    match literal {
+        Literal::Number(_) => DataType::Number,
+        _ => DataType::String,
+    }
+}
+
+/// Pushes `c` as a literal character of a `like` pattern.
+fn push_literal(result: &mut String, c: char) {
+    if matches!(c, '%' | '_' | '\\') {
+        result.push('\\');
+    }
+    result.push(c);
+}
+
+/// An SLD `PropertyIsLike` pattern as a `like` one.
+fn like_pattern(node: Node, pattern: &str) -> Result<String, String> {
+    let wild = node.attribute("wildCard").unwrap_or("*");
+    let single = node.attribute("singleChar").unwrap_or(".");
+    let escape = node
+        .attribute("escapeChar")
+        .or_else(|| node.attribute("escape"))
+        .unwrap_or("\\");
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let s = c.to_string();
+        if s == escape {
+            match chars.next() {
+                Some(c) => push_literal(&mut result, c),
+                None => return Err(format!("like pattern `{}` is not supported", pattern)),
+            }
+        } else if s == wild {
+            result.push('%');
+        } else if s == single {
+            result.push('_');
+        } else {
+            push_literal(&mut result, c);
+        }
+    }
+    Ok(result)
+}
+
+struct LayerImport<'a> {
+    name: String,
+    /// the rule being read, named in warnings
+    rule: Option<String>,
+    warnings: &'a mut Vec<Warning>,
+    properties: Properties,
+}
+
+impl<'a> LayerImport<'a> {
+    fn new(layer: Node, warnings: &'a mut Vec<Warning>) -> Self {
+        let name = child(layer, "Name").map_or(String::from("(unnamed)"), text);
+        LayerImport {
+            name,
+            rule: None,
+            warnings,
+            properties: Properties::default(),
+        }
+    }
+
+    fn warn(&mut self, message: String) {
+        let message = match &self.rule {
+            Some(rule) => format!("rule {}: {}", rule, message),
+            None => message,
+        };
+        self.warnings.push(Warning {
+            layer: Some(self.name.clone()),
+            message,
+        });
+    }
+
+    fn layer(mut self, layer: Node, source: &str) -> Option<LayerBlock> {
+        let mut unfiltered: Vec<Command> = Vec::new();
+        let mut otherwise: Vec<Command> = Vec::new();
+        let mut syms: Vec<Directive> = Vec::new();
+        for rule in layer.descendants().filter(|n| is(n, "Rule")) {
+            self.rule = Some(
+                child(rule, "Name")
+                    .or_else(|| child(rule, "Title"))
+                    .map_or(String::from("(unnamed)"), text),
+            );
+            let scale = ScaleRange {
+                min: self.bound(rule, "MinScaleDenominator"),
+                max: self.bound(rule, "MaxScaleDenominator"),
+            };
+            let filter = child(rule, "Filter").map(|f| elements(f).next().map(|f| self.filter(f)));
+            let commands = self.symbolizers(rule);
+            match (filter, child(rule, "ElseFilter")) {
+                (Some(filter), _) => match filter {
+                    Some(Ok(predicate)) => syms.push(
+                        Sym {
+                            scale,
+                            predicate,
+                            consequent: commands,
+                        }
+                        .into(),
+                    ),
+                    Some(Err(message)) => self.warn(format!("{}, skipped", message)),
+                    None => self.warn(String::from("empty filter, skipped")),
+                },
+                (None, else_filter) => {
+                    if !scale.is_open() {
+                        self.warn(String::from(
+                            "scale denominators are only kept on filtered rules",
+                        ));
+                    }
+                    match else_filter {
+                        Some(_) => otherwise.extend(commands),
+                        None => {
+                            if !syms.is_empty() {
+                                self.warn(String::from(
+                                    "has no filter, it is drawn before the filtered rules",
+                                ));
+                            }
+                            unfiltered.extend(commands);
+                        }
+                    }
+                }
+            }
+        }
+        if unfiltered.is_empty() && otherwise.is_empty() && syms.is_empty() {
+            self.rule = None;
+            self.warn(String::from("no rule to draw, skipped"));
+            return None;
+        }
+
+        let mut directives: Vec<Directive> = vec![
+            Name {
+                value: self.name.clone(),
+            }
+            .into(),
+            Source {
+                driver: Driver::Geojson,
+                path: String::from(source),
+                srid: Some(Num::Integer(4326)),
+            }
+            .into(),
+        ];
+        directives.extend(self.properties.directives());
+        let fallback = |consequent: Vec<Command>| Else { consequent }.into();
+        if !unfiltered.is_empty() {
+            directives.push(fallback(unfiltered));
+        }
+        directives.extend(syms);
+        if !otherwise.is_empty() {
+            directives.push(fallback(otherwise));
+        }
+        Some(LayerBlock {
+            directives,
+            positions: Vec::new(),
+        })
+    }
+
+    fn bound(&mut self, rule: Node, name: &str) -> Option<Num> {
+        let node = child(rule, name)?;
+        match text(node).parse::<f64>() {
+            Ok(n) => Some(num(n)),
+            Err(_) => {
+                self.warn(format!("`{}` is not a number", text(node)));
+                None
+            }
+        }
+    }
+
+    fn symbolizers(&mut self, rule: Node) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for symbolizer in elements(rule) {
+            let kind = symbolizer.tag_name().name();
+            if !kind.ends_with("Symbolizer") {
+                continue;
+            }
+            if child(symbolizer, "Geometry").is_some() {
+                self.warn(String::from("symbolizer geometries are ignored"));
+            }
+            match kind {
+                "PolygonSymbolizer" => {
+                    commands.extend(child(symbolizer, "Fill").map(|f| self.fill(f)));
+                    commands.extend(child(symbolizer, "Stroke").map(|s| self.stroke(s)));
+                }
+                "LineSymbolizer" => {
+                    commands.extend(child(symbolizer, "Stroke").map(|s| self.stroke(s)));
+                }
+                "PointSymbolizer" => commands.extend(self.point(symbolizer)),
+                _ => self.warn(format!("{} is not supported", kind)),
+            }
+        }
+        commands
+    }
+
+    /// Well known marks only, `circle` and `square`.
+    fn point(&mut self, symbolizer: Node) -> Vec<Command> {
+        let graphic = match child(symbolizer, "Graphic") {
+            Some(graphic) => graphic,
+            None => return Vec::new(),
+        };
+        let mark = match child(graphic, "Mark") {
+            Some(mark) => mark,
+            None => {
+                self.warn(String::from("only marks are supported"));
+                return Vec::new();
+            }
+        };
+        let size = child(graphic, "Size").map_or(number(6.0), |s| self.numeric(s, 6.0));
+        let shape = child(mark, "WellKnownName").map_or(String::from("square"), text);
+        let mut commands = vec![match shape.as_str() {
+            "circle" => Command::Circle(Circle {
+                radius: match size {
+                    Value::Lit(Literal::Number(n)) => number(n.as_float() / 2.0),
+                    size => size,
+                },
+            }),
+            "square" => Command::Square(Square { size }),
+            _ => {
+                self.warn(format!(
+                    "mark `{}` is not supported, a square is drawn",
+                    shape
+                ));
+                Command::Square(Square { size })
+            }
+        }];
+        commands.push(match child(mark, "Fill") {
+            Some(fill) => self.fill(fill),
+            None => Command::Fill(Fill {
+                color: rgb([128, 128, 128]),
+            }),
+        });
+        commands.extend(child(mark, "Stroke").map(|s| self.stroke(s)));
+        commands
+    }
+
+    fn fill(&mut self, fill: Node) -> Command {
+        Command::Fill(Fill {
+            color: self.color(fill, "fill", [128, 128, 128]),
+        })
+    }
+
+    fn stroke(&mut self, stroke: Node) -> Command {
+        for unsupported in ["stroke-dasharray", "stroke-linecap", "stroke-linejoin"].iter() {
+            if parameter(stroke, unsupported).is_some() {
+                self.warn(format!("`{}` is not supported", unsupported));
+            }
+        }
+        Command::Stroke(Stroke {
+            color: self.color(stroke, "stroke", [0, 0, 0]),
+            size: parameter(stroke, "stroke-width").map_or(number(1.0), |w| self.numeric(w, 1.0)),
+        })
+    }
+
+    /// `{name}-opacity`, a literal one.
+    fn opacity(&mut self, node: Node, name: &str) -> f64 {
+        let name = format!("{}-opacity", name);
+        match parameter(node, &name).map(|p| text(p).parse::<f64>()) {
+            Some(Ok(o)) => o.clamp(0.0, 1.0),
+            Some(Err(_)) => {
+                self.warn(format!("`{}` is not a number", name));
+                1.0
+            }
+            None => 1.0,
+        }
+    }
+
+    /// The colour `name` with the alpha of `{name}-opacity`.
+    fn color(&mut self, node: Node, name: &str, default: [u8; 3]) -> Value {
+        let opacity = self.opacity(node, name);
+        let value = match parameter(node, name) {
+            Some(p) => text(p),
+            None => return rgba(default, opacity),
+        };
+        match css_color(&value) {
+            Some((channels, alpha)) => rgba(channels, alpha * opacity),
+            None => {
+                self.warn(format!("colour `{}` of `{}` is not supported", value, name));
+                rgba(default, opacity)
+            }
+        }
+    }
+
+    /// A literal number or a `PropertyName`.
+    fn numeric(&mut self, node: Node, default: f64) -> Value {
+        if let Some(property) = child(node, "PropertyName") {
+            return self.properties.get(&text(property), DataType::Number);
+        }
+        let value = child(node, "Literal").map_or_else(|| text(node), text);
+        match value.parse::<f64>() {
+            Ok(n) => number(n),
+            Err(_) => {
+                self.warn(format!("`{}` is not supported, {} is used", value, default));
+                number(default)
+            }
+        }
+    }
+
+    fn filter(&mut self, node: Node) -> Result<PredGroup, String> {
+        let op = node.tag_name().name();
+        match op {
+            "And" | "Or" => {
+                let mut groups = elements(node).map(|n| self.filter(n));
+                let first = groups
+                    .next()
+                    .unwrap_or_else(|| Err(format!("an empty `{}` is not supported", op)))?;
+                groups.try_fold(first, |acc, group| {
+                    group.map(|group| match op {
+                        "And" => and(acc, group),
+                        _ => or(acc, group),
+                    })
+                })
+            }
+            "Not" => match elements(node).next() {
+                Some(n) => self.filter(n).map(|g| PredGroup::Not(Box::new(g))),
+                None => Err(String::from("an empty `Not` is not supported")),
+            },
+            "PropertyIsEqualTo"
+            | "PropertyIsNotEqualTo"
+            | "PropertyIsLessThan"
+            | "PropertyIsLessThanOrEqualTo"
+            | "PropertyIsGreaterThan"
+            | "PropertyIsGreaterThanOrEqualTo" => self.comparison(node),
+            "PropertyIsLike" => self.like(node),
+            "PropertyIsBetween" => self.between(node),
+            _ => Err(format!("filter `{}` is not supported", op)),
+        }
+    }
+
+    fn comparison(&mut self, node: Node) -> Result<PredGroup, String> {
+        let op = node.tag_name().name();
+        let operands: Vec<Node> = elements(node).collect();
+        let (property, value, flipped) = match operands.as_slice() {
+            [p, l] if is(p, "PropertyName") && is(l, "Literal") => (text(*p), text(*l), false),
+            [l, p] if is(p, "PropertyName") && is(l, "Literal") => (text(*p), text(*l), true),
+            _ => return Err(format!("`{}` compares a property to a literal", op)),
+        };
+        if node.attribute("matchCase") == Some("false") {
+            self.warn(format!("`matchCase` of `{}` is ignored", op));
+        }
+        let value = literal(&value);
+        let pair = (
+            self.properties.get(&property, datatype(&value)),
+            Value::Lit(value),
+        );
+        let predicate = match (op, flipped) {
+            ("PropertyIsEqualTo", _) => Predicate::Equal(pair),
+            ("PropertyIsNotEqualTo", _) => Predicate::NotEqual(pair),
+            ("PropertyIsLessThan", false) | ("PropertyIsGreaterThan", true) => {
+                Predicate::LesserThan(pair)
+            }
+            ("PropertyIsLessThanOrEqualTo", false) | ("PropertyIsGreaterThanOrEqualTo", true) => {
+                Predicate::LesserThanOrEqual(pair)
+            }
+            ("PropertyIsGreaterThan", _) | ("PropertyIsLessThan", _) => {
+                Predicate::GreaterThan(pair)
+            }
+            _ => Predicate::GreaterThanOrEqual(pair),
+        };
+        Ok(PredGroup::Pred(predicate))
+    }
+
+    fn like(&mut self, node: Node) -> Result<PredGroup, String> {
+        let (property, pattern) = match (child(node, "PropertyName"), child(node, "Literal")) {
+            (Some(p), Some(l)) => (text(p), text(l)),
+            _ => {
+                return Err(String::from(
+                    "`PropertyIsLike` needs a property and a pattern",
+                ))
+            }
+        };
+        let pattern = like_pattern(node, &pattern)?;
+        let pair = (
+            self.properties.get(&property, DataType::String),
+            Value::Lit(Literal::String(pattern)),
+        );
+        Ok(PredGroup::Pred(match node.attribute("matchCase") {
+            Some("false") => Predicate::ILike(pair),
+            _ => Predicate::Like(pair),
+        }))
+    }
+
+    fn between(&mut self, node: Node) -> Result<PredGroup, String> {
+        let boundary = |name| {
+            child(node, name).map(|b| literal(&child(b, "Literal").map_or_else(|| text(b), text)))
+        };
+        let (property, lower, upper) = match (
+            child(node, "PropertyName"),
+            boundary("LowerBoundary"),
+            boundary("UpperBoundary"),
+        ) {
+            (Some(p), Some(lower), Some(upper)) => (text(p), lower, upper),
+            _ => {
+                return Err(String::from(
+                    "`PropertyIsBetween` needs a property and boundaries",
+                ))
+            }
+        };
+        let subject = self.properties.get(&property, datatype(&lower));
+        Ok(and(
+            PredGroup::Pred(Predicate::GreaterThanOrEqual((
+                subject.clone(),
+                Value::Lit(lower),
+            ))),
+            PredGroup::Pred(Predicate::LesserThanOrEqual((subject, Value::Lit(upper)))),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod sld_test {
+    use super::*;
+    use parser::{format_spec, parse_str};
+
+    const SLD: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<StyledLayerDescriptor version="1.1.0"
+    xmlns="http://www.opengis.net/sld"
+    xmlns:se="http://www.opengis.net/se"
+    xmlns:ogc="http://www.opengis.net/ogc">
+  <NamedLayer>
+    <se:Name>landuse</se:Name>
+    <UserStyle>
+      <se:FeatureTypeStyle>
+        <se:Rule>
+          <se:Name>parks</se:Name>
+          <ogc:Filter>
+            <ogc:And>
+              <ogc:PropertyIsEqualTo>
+                <ogc:PropertyName>kind</ogc:PropertyName>
+                <ogc:Literal>park</ogc:Literal>
+              </ogc:PropertyIsEqualTo>
+              <ogc:Not>
+                <ogc:PropertyIsLike wildCard="*" singleChar="?" escapeChar="!">
+                  <ogc:PropertyName>name</ogc:PropertyName>
+                  <ogc:Literal>Old*</ogc:Literal>
+                </ogc:PropertyIsLike>
+              </ogc:Not>
+            </ogc:And>
+          </ogc:Filter>
+          <se:MaxScaleDenominator>50000</se:MaxScaleDenominator>
+          <se:PolygonSymbolizer>
+            <se:Fill>
+              <se:SvgParameter name="fill">#33cc33</se:SvgParameter>
+              <se:SvgParameter name="fill-opacity">0.5</se:SvgParameter>
+            </se:Fill>
+            <se:Stroke>
+              <se:SvgParameter name="stroke">#006600</se:SvgParameter>
+              <se:SvgParameter name="stroke-width">2</se:SvgParameter>
+            </se:Stroke>
+          </se:PolygonSymbolizer>
+        </se:Rule>
+        <se:Rule>
+          <se:Name>large</se:Name>
+          <ogc:Filter>
+            <ogc:PropertyIsBetween>
+              <ogc:PropertyName>area</ogc:PropertyName>
+              <ogc:LowerBoundary><ogc:Literal>100</ogc:Literal></ogc:LowerBoundary>
+              <ogc:UpperBoundary><ogc:Literal>1000</ogc:Literal></ogc:UpperBoundary>
+            </ogc:PropertyIsBetween>
+          </ogc:Filter>
+          <se:LineSymbolizer>
+            <se:Stroke>
+              <se:SvgParameter name="stroke-width">
+                <ogc:PropertyName>width</ogc:PropertyName>
+              </se:SvgParameter>
+            </se:Stroke>
+          </se:LineSymbolizer>
+        </se:Rule>
+        <se:Rule>
+          <se:Name>rest</se:Name>
+          <se:ElseFilter/>
+          <se:PolygonSymbolizer>
+            <se:Fill><se:SvgParameter name="fill">#eeeeee</se:SvgParameter></se:Fill>
+          </se:PolygonSymbolizer>
+        </se:Rule>
+      </se:FeatureTypeStyle>
+    </UserStyle>
+  </NamedLayer>
+  <NamedLayer>
+    <se:Name>trees</se:Name>
+    <UserStyle>
+      <se:FeatureTypeStyle>
+        <se:Rule>
+          <se:PointSymbolizer>
+            <se:Graphic>
+              <se:Mark>
+                <se:WellKnownName>circle</se:WellKnownName>
+                <se:Fill><se:SvgParameter name="fill">green</se:SvgParameter></se:Fill>
+              </se:Mark>
+              <se:Size>8</se:Size>
+            </se:Graphic>
+          </se:PointSymbolizer>
+          <se:TextSymbolizer/>
+        </se:Rule>
+        <se:Rule>
+          <se:Name>stumps</se:Name>
+          <ogc:Filter>
+            <ogc:PropertyIsGreaterThan>
+              <ogc:Literal>1</ogc:Literal>
+              <ogc:PropertyName>age</ogc:PropertyName>
+            </ogc:PropertyIsGreaterThan>
+          </ogc:Filter>
+          <se:PointSymbolizer>
+            <se:Graphic>
+              <se:Mark><se:WellKnownName>star</se:WellKnownName></se:Mark>
+            </se:Graphic>
+          </se:PointSymbolizer>
+        </se:Rule>
+        <se:Rule>
+          <se:Name>dead</se:Name>
+          <ogc:Filter>
+            <ogc:PropertyIsNull><ogc:PropertyName>age</ogc:PropertyName></ogc:PropertyIsNull>
+          </ogc:Filter>
+        </se:Rule>
+      </se:FeatureTypeStyle>
+    </UserStyle>
+  </NamedLayer>
+</StyledLayerDescriptor>
+"##;
+
+    #[test]
+    fn import() {
+        let imported = import_sld(SLD, "data.geojson").unwrap();
+        let expected = "map
+srid 3857
+extent -20037508 -20037508 20037508 20037508
+
+
+layer
+name \"landuse\"
+source geojson \"data.geojson\" 4326
+data kind select \"kind\" string
+data name select \"name\" string
+data area select \"area\" number
+data width select \"width\" number
+sym maxscale 50000 kind = \"park\"
+  & !name like \"Old%\"
+  -> fill rgba(51, 204, 51, 0.5)
+  -> stroke rgb(0, 102, 0) 2
+sym area >= 100
+  & area <= 1000
+  -> stroke rgb(0, 0, 0) width
+else -> fill rgb(238, 238, 238)
+
+
+layer
+name \"trees\"
+source geojson \"data.geojson\" 4326
+data age select \"age\" number
+else
+  -> circle 4
+  -> fill rgb(0, 128, 0)
+sym age < 1
+  -> square 6
+  -> fill rgb(128, 128, 128)
+";
+        let formatted = format_spec(&imported.spec);
+        assert_eq!(formatted, expected);
+        assert!(parse_str(&formatted).is_ok());
+
+        let warnings: Vec<String> = imported.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "an SLD has no extent, the extent is the whole world",
+                "layer trees: rule (unnamed): TextSymbolizer is not supported",
+                "layer trees: rule stumps: mark `star` is not supported, a square is drawn",
+                "layer trees: rule dead: filter `PropertyIsNull` is not supported, skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn like_patterns() {
+        let document = Document::parse(
+            r#"<PropertyIsLike wildCard="*" singleChar="." escapeChar="\">x</PropertyIsLike>"#,
+        )
+        .unwrap();
+        let node = document.root_element();
+        assert_eq!(like_pattern(node, "a*b.c\\*").unwrap(), "a%b_c*");
+        assert_eq!(like_pattern(node, "100%").unwrap(), "100\\%");
+        assert_eq!(like_pattern(node, "a_b\\.").unwrap(), "a\\_b.");
+        assert_eq!(like_pattern(node, "C:\\\\*").unwrap(), "C:\\\\%");
+        assert!(like_pattern(node, "a\\").is_err());
+    }
+}
@@ -0,0 +1,426 @@
+//! What the server knows about an open map file.
+//!
+//! Diagnostics come from the parser, everything else works on the text
+//! line by line so that it keeps working while the file doesn't parse.
+//! Columns are counted in characters, and in UTF-16 code units, the
+//! protocol's default encoding, in what is sent and received.
+
+use apply::{check::check, function::FunctionRegistry, source::geojson_source::property_names};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Hover, HoverContents,
+    MarkupContent, MarkupKind, Position, Range,
+};
+use parser::{parse_str_with_options, ParseOptions};
+use std::path::PathBuf;
+
+const DIRECTIVES: &[&str] = &[
+    "map", "layer", "name", "visible", "opacity", "srid", "extent", "include", "source", "data",
+    "fn", "sym", "else", "matching", "minscale", "maxscale", "label",
+];
+
+const KEYWORDS: &[&str] = &[
+    "select",
+    "match",
+    "in",
+    "not",
+    "like",
+    "ilike",
+    "first",
+    "all",
+    "minscale",
+    "maxscale",
+    "geometry",
+    "is",
+    "line",
+    "polygon",
+    "multi",
+    "collection",
+    "string",
+    "number",
+    "bool",
+    "true",
+    "false",
+    "geojson",
+    "postgis",
+    "shapefile",
+    "clear",
+    "draw",
+    "circle",
+    "square",
+    "fill",
+    "stroke",
+    "pattern",
+    "label",
+    "text",
+    "anchor",
+    "size",
+    "point",
+    "centroid",
+];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+fn is_block_start(line: &str) -> bool {
+    let line = line.trim_end();
+    line == "map" || line == "layer"
+}
+
+/// `ident` and its start column when `line` is a `data` directive.
+fn data_ident(line: &str) -> Option<(String, usize)> {
+    let rest = line.strip_prefix("data")?;
+    let trimmed = rest.trim_start();
+    if trimmed.len() == rest.len() {
+        return None;
+    }
+    let ident: String = trimmed.chars().take_while(|c| is_ident_char(*c)).collect();
+    if ident.is_empty() {
+        None
+    } else {
+        let start = line.len() - trimmed.len();
+        Some((ident, line[..start].chars().count()))
+    }
+}
+
+/// The UTF-16 column of the character column `chars` of `line`,
+/// columns past its end count one each.
+fn utf16_column(line: &str, chars: usize) -> usize {
+    let units: usize = line.chars().take(chars).map(char::len_utf16).sum();
+    units + chars.saturating_sub(line.chars().count())
+}
+
+/// The character column of the UTF-16 column `units` of `line`.
+fn char_column(line: &str, units: u32) -> usize {
+    let mut seen = 0;
+    line.chars()
+        .take_while(|c| {
+            seen += c.len_utf16();
+            seen <= units as usize
+        })
+        .count()
+}
+
+fn range(line: usize, start: usize, end: usize) -> Range {
+    Range {
+        start: Position::new(line as u32, start as u32),
+        end: Position::new(line as u32, end as u32),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub ident: String,
+    pub line: usize,
+    pub range: Range,
+}
+
+pub struct Document {
+    text: String,
+    path: Option<PathBuf>,
+}
+
+impl Document {
+    pub fn new(text: String, path: Option<PathBuf>) -> Self {
+        Document { text, path }
+    }
+
+    fn lines(&self) -> Vec<&str> {
+        self.text.lines().collect()
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let options = ParseOptions {
+            path: self.path.clone(),
+            ..ParseOptions::default()
+        };
+        match parse_str_with_options(&self.text, options) {
+            Ok(spec) => check(&spec, &FunctionRegistry::new())
+                .into_iter()
+                .map(|err| {
+                    let position = err.position.unwrap_or(self.text.len());
+                    diagnostic(parser::Diagnostic::at(&self.text, position, err.message))
+                })
+                .collect(),
+            Err(err) => vec![diagnostic(parser::Diagnostic::new(&self.text, &err))],
+        }
+    }
+
+    /// First line of the block holding `line`.
+    fn block_start(&self, line: usize) -> usize {
+        let lines = self.lines();
+        (0..=line.min(lines.len().saturating_sub(1)))
+            .rev()
+            .find(|i| is_block_start(lines[*i]))
+            .unwrap_or(0)
+    }
+
+    /// `data` defined before `line`, in the map block and in the
+    /// layer holding `line`, the latest definition comes last.
+    pub fn definitions(&self, line: usize) -> Vec<Definition> {
+        let lines = self.lines();
+        let block = self.block_start(line);
+        let map_end = lines
+            .iter()
+            .position(|l| l.trim_end() == "layer")
+            .unwrap_or(lines.len());
+        let in_scope = |i: &usize| *i < line && (*i < map_end || *i > block);
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| in_scope(i))
+            .filter_map(|(i, l)| {
+                data_ident(l).map(|(ident, start)| {
+                    let end = start + ident.chars().count();
+                    Definition {
+                        range: range(i, utf16_column(l, start), utf16_column(l, end)),
+                        ident,
+                        line: i,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn definition_of(&self, ident: &str, line: usize) -> Option<Definition> {
+        self.definitions(line)
+            .into_iter()
+            .rev()
+            .find(|d| d.ident == ident)
+    }
+
+    /// The identifier under `pos`.
+    pub fn word_at(&self, pos: Position) -> Option<String> {
+        let line = *self.lines().get(pos.line as usize)?;
+        let chars: Vec<char> = line.chars().collect();
+        let at = char_column(line, pos.character);
+        let start = (0..at)
+            .rev()
+            .take_while(|i| is_ident_char(chars[*i]))
+            .last()
+            .unwrap_or(at);
+        let end = (at..chars.len())
+            .take_while(|i| is_ident_char(chars[*i]))
+            .last()
+            .map(|i| i + 1)
+            .unwrap_or(at);
+        if start == end {
+            None
+        } else {
+            Some(chars[start..end].iter().collect())
+        }
+    }
+
+    /// Path of the GeoJSON source of the layer holding `line`,
+    /// relative paths resolve against the document.
+    fn source_path(&self, line: usize) -> Option<PathBuf> {
+        let lines = self.lines();
+        let block = self.block_start(line);
+        let quoted = lines[block..]
+            .iter()
+            .skip(1)
+            .take_while(|l| !is_block_start(l))
+            .find_map(|l| l.strip_prefix("source geojson"))?
+            .trim()
+            .strip_prefix('"')?
+            .split('"')
+            .next()?;
+        let path = PathBuf::from(quoted);
+        match self.path.as_ref().and_then(|p| p.parent()) {
+            Some(dir) if path.is_relative() => Some(dir.join(path)),
+            _ => Some(path),
+        }
+    }
+
+    pub fn completions(&self, pos: Position) -> Vec<CompletionItem> {
+        let line_index = pos.line as usize;
+        let line = self.lines().get(line_index).cloned().unwrap_or("");
+        let prefix: String = line
+            .chars()
+            .take(char_column(line, pos.character))
+            .collect();
+
+        if let Some(select) = prefix.rfind("select \"") {
+            if !prefix[select + 8..].contains('"') {
+                return self
+                    .source_path(line_index)
+                    .and_then(|p| property_names(&p.to_string_lossy()).ok())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|name| item(name, CompletionItemKind::PROPERTY, None))
+                    .collect();
+            }
+        }
+
+        if !prefix.contains(char::is_whitespace) {
+            return DIRECTIVES
+                .iter()
+                .map(|k| item(String::from(*k), CompletionItemKind::KEYWORD, None))
+                .collect();
+        }
+
+        let mut items: Vec<CompletionItem> = Vec::new();
+        for d in self.definitions(line_index).into_iter().rev() {
+            if !items.iter().any(|i| i.label == d.ident) {
+                let detail = self.lines()[d.line].trim().to_string();
+                items.push(item(d.ident, CompletionItemKind::VARIABLE, Some(detail)));
+            }
+        }
+        items.extend(
+            FunctionRegistry::new()
+                .names()
+                .into_iter()
+                .map(|f| item(String::from(f), CompletionItemKind::FUNCTION, None)),
+        );
+        items.extend(
+            KEYWORDS
+                .iter()
+                .map(|k| item(String::from(*k), CompletionItemKind::KEYWORD, None)),
+        );
+        items
+    }
+
+    pub fn hover(&self, pos: Position) -> Option<Hover> {
+        let ident = self.word_at(pos)?;
+        let def = self.definition_of(&ident, pos.line as usize)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```\n{}\n```", self.lines()[def.line].trim_end()),
+            }),
+            range: None,
+        })
+    }
+
+    pub fn definition(&self, pos: Position) -> Option<Range> {
+        let ident = self.word_at(pos)?;
+        self.definition_of(&ident, pos.line as usize)
+            .map(|d| d.range)
+    }
+}
+
+fn diagnostic(d: parser::Diagnostic) -> Diagnostic {
+    let line = &d.source_line;
+    let end = line.chars().count().max(d.column);
+    Diagnostic {
+        range: range(
+            d.line - 1,
+            utf16_column(line, d.column - 1),
+            utf16_column(line, end),
+        ),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some(String::from("map")),
+        message: d.message,
+        ..Diagnostic::default()
+    }
+}
+
+fn item(label: String, kind: CompletionItemKind, detail: Option<String>) -> CompletionItem {
+    CompletionItem {
+        label,
+        kind: Some(kind),
+        detail,
+        ..CompletionItem::default()
+    }
+}
+
+#[cfg(test)]
+mod document_test {
+    use super::*;
+
+    const MAP: &str = "map
+srid 3857
+extent 0 0 100 100
+data blue rgb(0, 0, 255)
+
+
+layer
+source geojson \"first.geojson\"
+data kind select \"symbol\" string
+data blue rgb(0, 0, 200)
+sym kind = \"tree\" -> fill blue
+
+
+layer
+source geojson \"first.geojson\"
+sym true = true -> fill blue
+";
+
+    fn document() -> Document {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../parser/data/map.map");
+        Document::new(String::from(MAP), Some(path))
+    }
+
+    fn labels(items: Vec<CompletionItem>) -> Vec<String> {
+        items.into_iter().map(|i| i.label).collect()
+    }
+
+    #[test]
+    fn diagnostics() {
+        assert!(document().diagnostics().is_empty());
+        let broken = Document::new(MAP.replace("-> fill", "fill"), None);
+        let diagnostics = broken.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start, Position::new(10, 18));
+        assert_eq!(diagnostics[0].message, "expected `->` after predicate");
+
+        let mistyped = Document::new(MAP.replace("fill blue", "fill 12"), None);
+        let diagnostics = mistyped.diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].range.start, Position::new(10, 0));
+        assert_eq!(
+            diagnostics[0].message,
+            "`fill` color expects Color, found Number"
+        );
+    }
+
+    #[test]
+    fn utf16_columns() {
+        let line = "sym kind = \"🌳\" fill blue";
+        assert_eq!(utf16_column(line, 15), 16);
+        assert_eq!(char_column(line, 16), 15);
+        assert_eq!(utf16_column("ab", 4), 4);
+        let broken = Document::new(MAP.replace("\"tree\" -> fill", "\"🌳\" fill"), None);
+        let diagnostics = broken.diagnostics();
+        assert_eq!(diagnostics[0].range.start, Position::new(10, 16));
+        let doc = Document::new(MAP.replace("\"tree\"", "\"🌳\""), None);
+        assert_eq!(doc.word_at(Position::new(10, 23)).as_deref(), Some("fill"));
+    }
+
+    #[test]
+    fn data_in_scope() {
+        let doc = document();
+        let first = labels(doc.completions(Position::new(10, 20)));
+        assert!(first.contains(&String::from("kind")));
+        assert!(first.contains(&String::from("blue")));
+        assert!(first.contains(&String::from("rgb")));
+        assert!(first.contains(&String::from("fill")));
+        let second = labels(doc.completions(Position::new(15, 20)));
+        assert!(!second.contains(&String::from("kind")));
+        assert!(second.contains(&String::from("blue")));
+        assert_eq!(labels(doc.completions(Position::new(10, 1)))[0], "map");
+    }
+
+    #[test]
+    fn properties() {
+        let doc = document();
+        let props = labels(doc.completions(Position::new(8, 19)));
+        assert!(props.contains(&String::from("symbol")));
+        assert!(props.contains(&String::from("text")));
+    }
+
+    #[test]
+    fn definition_and_hover() {
+        let doc = document();
+        // the layer shadows the map block
+        assert_eq!(doc.definition(Position::new(10, 28)), Some(range(9, 5, 9)));
+        assert_eq!(doc.definition(Position::new(15, 27)), Some(range(3, 5, 9)));
+        match doc.hover(Position::new(10, 5)).map(|h| h.contents) {
+            Some(HoverContents::Markup(m)) => {
+                assert_eq!(m.value, "```\ndata kind select \"symbol\" string\n```")
+            }
+            other => panic!("unexpected hover {:?}", other),
+        }
+        assert!(doc.hover(Position::new(10, 0)).is_none());
+    }
+}
@@ -0,0 +1,292 @@
+mod document;
+
+use document::Document;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, LogMessage,
+        Notification as NotificationT, PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, Request as RequestT},
+    CompletionOptions, CompletionParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, HoverParams,
+    HoverProviderCapability, Location, LogMessageParams, MessageType, OneOf, PositionEncodingKind,
+    PublishDiagnosticsParams, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
+    Url,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+type ServerResult<T> = Result<T, Box<dyn Error + Sync + Send>>;
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![String::from(" "), String::from("\"")]),
+            ..CompletionOptions::default()
+        }),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        position_encoding: Some(PositionEncodingKind::UTF16),
+        ..ServerCapabilities::default()
+    }
+}
+
+struct Server {
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn new() -> Self {
+        Server {
+            documents: HashMap::new(),
+        }
+    }
+
+    fn open(&mut self, uri: Url, text: String) -> Notification {
+        let document = Document::new(text, uri.to_file_path().ok());
+        let params = PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics: document.diagnostics(),
+            version: None,
+        };
+        self.documents.insert(uri, document);
+        Notification::new(String::from(PublishDiagnostics::METHOD), params)
+    }
+
+    /// Diagnostics to publish in response to a notification, if any.
+    fn notify(&mut self, notification: Notification) -> ServerResult<Option<Notification>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let doc = params.text_document;
+                Ok(Some(self.open(doc.uri, doc.text)))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                // full sync, the last change holds the whole text
+                match params.content_changes.into_iter().last() {
+                    Some(change) => Ok(Some(self.open(params.text_document.uri, change.text))),
+                    None => Ok(None),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn document(&self, uri: &Url) -> ServerResult<&Document> {
+        self.documents
+            .get(uri)
+            .ok_or_else(|| format!("unknown document {}", uri).into())
+    }
+
+    fn handle(&self, request: Request) -> ServerResult<Response> {
+        let id = request.id.clone();
+        match request.method.as_str() {
+            Completion::METHOD => {
+                let params: CompletionParams = serde_json::from_value(request.params)?;
+                let pos = params.text_document_position;
+                let items = self
+                    .document(&pos.text_document.uri)?
+                    .completions(pos.position);
+                Ok(Response::new_ok(id, items))
+            }
+            HoverRequest::METHOD => {
+                let params: HoverParams = serde_json::from_value(request.params)?;
+                let pos = params.text_document_position_params;
+                let hover = self.document(&pos.text_document.uri)?.hover(pos.position);
+                Ok(Response::new_ok(id, hover))
+            }
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = serde_json::from_value(request.params)?;
+                let pos = params.text_document_position_params;
+                let uri = pos.text_document.uri;
+                let location = self.document(&uri)?.definition(pos.position).map(|range| {
+                    GotoDefinitionResponse::Scalar(Location {
+                        uri: uri.clone(),
+                        range,
+                    })
+                });
+                Ok(Response::new_ok(id, location))
+            }
+            method => Ok(Response::new_err(
+                id,
+                ErrorCode::MethodNotFound as i32,
+                format!("unsupported method {}", method),
+            )),
+        }
+    }
+}
+
+/// Serves until `exit`, the connection is dropped when it returns so
+/// that the io threads of a stdio connection can finish.
+fn main_loop(connection: Connection) -> ServerResult<()> {
+    let mut server = Server::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let id = request.id.clone();
+                let response = server.handle(request).unwrap_or_else(|err| {
+                    Response::new_err(id, ErrorCode::InvalidParams as i32, err.to_string())
+                });
+                connection.sender.send(Message::Response(response))?;
+            }
+            // a notification can't be answered, its errors are logged
+            Message::Notification(notification) => match server.notify(notification) {
+                Ok(Some(diagnostics)) => {
+                    connection.sender.send(Message::Notification(diagnostics))?;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let params = LogMessageParams {
+                        typ: MessageType::ERROR,
+                        message: err.to_string(),
+                    };
+                    let log = Notification::new(String::from(LogMessage::METHOD), params);
+                    connection.sender.send(Message::Notification(log))?;
+                }
+            },
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ServerResult<()> {
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    main_loop(connection)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lsp_server::RequestId;
+    use lsp_types::{
+        CompletionResponse, PartialResultParams, Position, TextDocumentIdentifier,
+        TextDocumentItem, TextDocumentPositionParams, WorkDoneProgressParams,
+    };
+    use serde_json::Value;
+    use std::thread;
+
+    const MAP: &str = "map
+srid 3857
+extent 0 0 100 100
+data blue rgb(0, 0, 255)
+
+
+layer
+source geojson \"a.geojson\"
+sym true = true -> fill
+";
+
+    fn position(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position: Position::new(line, character),
+        }
+    }
+
+    #[test]
+    fn stdio_session() {
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || main_loop(server).unwrap());
+        let uri = Url::parse("file:///tmp/session.map").unwrap();
+
+        let open = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: String::from("map"),
+                version: 1,
+                text: String::from(MAP),
+            },
+        };
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                String::from(DidOpenTextDocument::METHOD),
+                open,
+            )))
+            .unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Notification(n) => {
+                let params: PublishDiagnosticsParams = serde_json::from_value(n.params).unwrap();
+                assert_eq!(params.diagnostics.len(), 1);
+                assert_eq!(params.diagnostics[0].range.start.line, 8);
+            }
+            other => panic!("expected diagnostics, got {:?}", other),
+        }
+
+        // a malformed notification is logged, the server keeps going
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                String::from(DidChangeTextDocument::METHOD),
+                Value::Null,
+            )))
+            .unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Notification(n) => assert_eq!(n.method, LogMessage::METHOD),
+            other => panic!("expected a log message, got {:?}", other),
+        }
+
+        let completion = CompletionParams {
+            text_document_position: position(&uri, 8, 24),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+            context: None,
+        };
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(1),
+                String::from(Completion::METHOD),
+                completion,
+            )))
+            .unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Response(r) => {
+                let items: CompletionResponse = serde_json::from_value(r.result.unwrap()).unwrap();
+                match items {
+                    CompletionResponse::Array(items) => {
+                        assert_eq!(items[0].label, "blue");
+                    }
+                    other => panic!("unexpected completion {:?}", other),
+                }
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(2),
+                String::from("shutdown"),
+                Value::Null,
+            )))
+            .unwrap();
+        assert!(matches!(client.receiver.recv(), Ok(Message::Response(_))));
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                String::from("exit"),
+                Value::Null,
+            )))
+            .unwrap();
+        handle.join().unwrap();
+    }
+}
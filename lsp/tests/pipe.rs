@@ -0,0 +1,122 @@
+//! The server binary over real pipes, nothing but protocol messages
+//! may reach its stdout and `exit` must end the process.
+
+use lsp_server::{Message, Notification, Request, RequestId};
+use lsp_types::{
+    notification::{DidOpenTextDocument, Notification as NotificationT},
+    DidOpenTextDocumentParams, InitializeParams, PublishDiagnosticsParams, TextDocumentItem, Url,
+};
+use serde_json::{to_value, Value};
+use std::io::BufReader;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAP: &str = "map
+srid 3857
+extent 0 0 100 100
+data blue rgb(0, 0, 255)
+
+
+layer
+source geojson \"a.geojson\"
+sym true = true -> fill
+";
+
+/// Kills the server when a test fails, a stuck server would keep
+/// the pipes of the test run open.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn request(id: i32, method: &str, params: Value) -> Message {
+    Message::Request(Request::new(
+        RequestId::from(id),
+        String::from(method),
+        params,
+    ))
+}
+
+fn notification(method: &str, params: Value) -> Message {
+    Message::Notification(Notification::new(String::from(method), params))
+}
+
+#[test]
+fn pipe_session() {
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_malsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut stdin = server.0.stdin.take().unwrap();
+    let mut stdout = BufReader::new(server.0.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = Message::read(&mut stdout) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    request(
+        1,
+        "initialize",
+        to_value(InitializeParams::default()).unwrap(),
+    )
+    .write(&mut stdin)
+    .unwrap();
+    assert!(matches!(
+        receiver.recv_timeout(TIMEOUT),
+        Ok(Message::Response(_))
+    ));
+    notification("initialized", Value::Null)
+        .write(&mut stdin)
+        .unwrap();
+
+    let open = DidOpenTextDocumentParams {
+        text_document: TextDocumentItem {
+            uri: Url::parse("file:///tmp/pipe.map").unwrap(),
+            language_id: String::from("map"),
+            version: 1,
+            text: String::from(MAP),
+        },
+    };
+    notification(DidOpenTextDocument::METHOD, to_value(open).unwrap())
+        .write(&mut stdin)
+        .unwrap();
+    match receiver.recv_timeout(TIMEOUT) {
+        Ok(Message::Notification(n)) => {
+            let params: PublishDiagnosticsParams = serde_json::from_value(n.params).unwrap();
+            assert_eq!(params.diagnostics.len(), 1);
+            assert_eq!(params.diagnostics[0].range.start.line, 8);
+        }
+        other => panic!("expected diagnostics, got {:?}", other),
+    }
+
+    request(2, "shutdown", Value::Null)
+        .write(&mut stdin)
+        .unwrap();
+    assert!(matches!(
+        receiver.recv_timeout(TIMEOUT),
+        Ok(Message::Response(_))
+    ));
+    notification("exit", Value::Null).write(&mut stdin).unwrap();
+
+    let start = Instant::now();
+    while server.0.try_wait().unwrap().is_none() {
+        if start.elapsed() > TIMEOUT {
+            panic!("the server did not exit");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,27 @@
+use parser::{format_spec, parse_str_with_options, Diagnostic, ParseOptions};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+/// Rewrites a map file in place in its canonical form, comments included.
+/// A comment within a directive moves to its own line after the directive.
+pub fn format_file(map_path: &Path) -> Result<(), String> {
+    let content = read_to_string(map_path)
+        .map_err(|e| format!("Failed to read {}: {}", map_path.display(), e))?;
+    let options = ParseOptions {
+        keep_comments: true,
+        path: Some(map_path.to_path_buf()),
+    };
+    let spec = parse_str_with_options(&content, options).map_err(|err| {
+        format!(
+            "Failed to parse {}\n{}",
+            map_path.display(),
+            Diagnostic::new(&content, &err)
+        )
+    })?;
+    let formatted = format_spec(&spec);
+    if formatted != content {
+        write(map_path, formatted)
+            .map_err(|e| format!("Failed to write {}: {}", map_path.display(), e))?;
+    }
+    Ok(())
+}
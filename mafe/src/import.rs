@@ -0,0 +1,31 @@
+use import::{maplibre::import_style, sld::import_sld, Imported};
+use parser::format_spec;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Prints the map file of a MapLibre style.
+pub fn import_file(style_path: &Path) -> Result<(), String> {
+    let content = read_to_string(style_path)
+        .map_err(|e| format!("Failed to read {}: {}", style_path.display(), e))?;
+    let imported = import_style(&content)
+        .map_err(|e| format!("Failed to import {}: {}", style_path.display(), e))?;
+    print_imported(&imported);
+    Ok(())
+}
+
+/// Prints the map file of an SLD style drawing `source`.
+pub fn import_sld_file(style_path: &Path, source: &str) -> Result<(), String> {
+    let content = read_to_string(style_path)
+        .map_err(|e| format!("Failed to read {}: {}", style_path.display(), e))?;
+    let imported = import_sld(&content, source)
+        .map_err(|e| format!("Failed to import {}: {}", style_path.display(), e))?;
+    print_imported(&imported);
+    Ok(())
+}
+
+fn print_imported(imported: &Imported) {
+    for warning in imported.warnings.iter() {
+        eprintln!("Warning: {}", warning);
+    }
+    print!("{}", format_spec(&imported.spec));
+}
@@ -1,10 +1,15 @@
+mod fmt;
+mod import;
 mod piet_cairo;
 mod render;
 
-use apply::{op::OpList, run_map};
+use apply::{check::check, function::FunctionRegistry, map_extent, op::OpList, run_map};
 use cairo::{Context, Format, ImageSurface, IoError};
-use clap::{App, Arg, ArgMatches};
-use parser::parse_str;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use parser::{
+    ast::{Extent, LayerBlock, MapSpec},
+    parse_str_with_options, Diagnostic, ParseOptions,
+};
 use piet::{
     kurbo::{Affine, Vec2},
     RenderContext,
@@ -56,23 +61,80 @@ fn render_png(args: Arguments, ops: &OpList) {
     }
 }
 
+fn run_spec(args: Arguments, mut spec: MapSpec) {
+    match map_extent(&spec) {
+        Ok(extent) => {
+            // the scale is known before any rule is evaluated
+            let args = args.with_extent(&extent);
+            for name in args.unknown_layers(&spec.layers) {
+                println!("No layer named {}", name);
+            }
+            spec.layers.retain(|layer| args.keep_layer(layer));
+            match run_map(spec, args.scale_denominator(), &FunctionRegistry::new()) {
+                Ok(output) => {
+                    // for op in output.ops {
+                    //     println!("op> {}", op);
+                    // }
+                    for warning in output.warnings.iter() {
+                        eprintln!("Skipped: {}", warning);
+                    }
+                    render_png(args, &output.ops);
+                }
+                Err(err) => println!("run_map failed: {}", err),
+            }
+        }
+        Err(err) => println!("run_map failed: {}", err),
+    }
+}
+
+/// A `.map.json` spec is the serialized AST, it has no
+/// positions to report type errors at.
+fn run_json(args: Arguments, content: &str) {
+    match serde_json::from_str::<MapSpec>(content) {
+        Ok(spec) => {
+            let errors = check(&spec, &FunctionRegistry::new());
+            for err in errors.iter() {
+                println!("Type error in {}: {}", args.mapfile, err.message);
+            }
+            if errors.is_empty() {
+                run_spec(args, spec);
+            }
+        }
+        Err(err) => println!("Failed to read {}: {}", args.mapfile, err),
+    }
+}
+
 fn run_main(args: Arguments) {
     let map_path = Path::new(args.mapfile.as_str());
     match read_to_string(&map_path) {
         Err(e) => println!("Failed to read {}: {}", map_path.display(), e),
+        Ok(content) if args.mapfile.ends_with(".map.json") => run_json(args, &content),
         Ok(content) => {
-            if let Ok(spec) = parse_str(&content) {
+            let options = ParseOptions {
+                path: Some(map_path.to_path_buf()),
+                ..ParseOptions::default()
+            };
+            match parse_str_with_options(&content, options) {
                 // println!("<map\n {:?} \n/>", spec);
-                if let Ok(ops) = run_map(spec) {
-                    // for op in ops {
-                    //     println!("op> {}", op);
-                    // }
-                    render_png(args, &ops);
-                } else {
-                    println!("run_map failed");
+                Ok(spec) => {
+                    let errors = check(&spec, &FunctionRegistry::new());
+                    for err in errors.iter() {
+                        let position = err.position.unwrap_or(content.len());
+                        println!(
+                            "Type error in {}\n{}",
+                            map_path.display(),
+                            Diagnostic::at(&content, position, err.message.clone())
+                        );
+                    }
+                    if errors.is_empty() {
+                        run_spec(args, spec);
+                    }
                 }
-            } else {
-                println!("parse_str failed");
+                Err(err) => println!(
+                    "Failed to parse {}\n{}",
+                    map_path.display(),
+                    Diagnostic::new(&content, &err)
+                ),
             }
         }
     }
@@ -81,34 +143,103 @@ fn run_main(args: Arguments) {
 #[derive(Debug)]
 struct Arguments {
     extent: [f64; 4],
+    extent_override: [Option<f64>; 4],
     size: [f64; 2],
+    dpi: Option<f64>,
+    layers: Option<Vec<String>>,
+    exclude_layers: Vec<String>,
     mapfile: String,
 }
 
+fn layer_names(value: Option<&str>) -> Option<Vec<String>> {
+    value.map(|v| v.split(',').map(|n| String::from(n.trim())).collect())
+}
+
+/// OGC standardized rendering pixel size, in meters.
+const STANDARD_PIXEL_SIZE: f64 = 0.00028;
+
 impl Arguments {
     fn from_matches(matches: ArgMatches) -> Result<Self, &str> {
         let mapfile = matches.value_of("map_file").ok_or("missing map file")?;
         let width = matches.value_of("width").ok_or("missing width")?;
         let height = matches.value_of("height").ok_or("missing height")?;
-        let east = matches.value_of("east").ok_or("missing east")?;
-        let north = matches.value_of("north").ok_or("missing north")?;
-        let west = matches.value_of("west").ok_or("missing west")?;
-        let south = matches.value_of("south").ok_or("missing south")?;
+        let east = matches.value_of("east");
+        let north = matches.value_of("north");
+        let west = matches.value_of("west");
+        let south = matches.value_of("south");
+        let dpi = matches.value_of("dpi");
 
         let width: f64 = width.parse().map_err(|_| "failed to parse width")?;
         let height: f64 = height.parse().map_err(|_| "failed to parse height")?;
-        let east: f64 = east.parse().map_err(|_| "failed to parse east")?;
-        let north: f64 = north.parse().map_err(|_| "failed to parse north")?;
-        let west: f64 = west.parse().map_err(|_| "failed to parse west")?;
-        let south: f64 = south.parse().map_err(|_| "failed to parse south")?;
+        let east = parse_opt(east).map_err(|_| "failed to parse east")?;
+        let north = parse_opt(north).map_err(|_| "failed to parse north")?;
+        let west = parse_opt(west).map_err(|_| "failed to parse west")?;
+        let south = parse_opt(south).map_err(|_| "failed to parse south")?;
+        let dpi = parse_opt(dpi).map_err(|_| "failed to parse dpi")?;
+        let layers = layer_names(matches.value_of("layers"));
+        let exclude_layers = layer_names(matches.value_of("exclude_layers")).unwrap_or_default();
 
         Ok(dbg!(Arguments {
-            extent: [west, south, east, north],
+            extent: [0.0; 4],
+            extent_override: [west, south, east, north],
             size: [width, height],
+            dpi,
+            layers,
+            exclude_layers,
             mapfile: String::from(mapfile),
         }))
     }
 
+    /// The map extent is the default viewport, each bound
+    /// given on the command line overrides its counterpart.
+    fn with_extent(self, extent: &Extent) -> Self {
+        let from_map = [
+            extent.minx.as_float(),
+            extent.miny.as_float(),
+            extent.maxx.as_float(),
+            extent.maxy.as_float(),
+        ];
+        let mut extent = [0.0; 4];
+        for (i, bound) in extent.iter_mut().enumerate() {
+            *bound = self.extent_override[i].unwrap_or(from_map[i]);
+        }
+        Arguments { extent, ..self }
+    }
+
+    /// With `--layers` only the named layers are kept,
+    /// `--exclude-layers` then drops some of them.
+    fn keep_layer(&self, layer: &LayerBlock) -> bool {
+        let name = layer.name();
+        let included = match (&self.layers, &name) {
+            (None, _) => true,
+            (Some(names), Some(name)) => names.contains(name),
+            (Some(_), None) => false,
+        };
+        included && !name.is_some_and(|n| self.exclude_layers.contains(&n))
+    }
+
+    /// Names given on the command line that no layer has.
+    fn unknown_layers(&self, layers: &[LayerBlock]) -> Vec<String> {
+        let known: Vec<String> = layers.iter().filter_map(|l| l.name()).collect();
+        self.layers
+            .iter()
+            .flatten()
+            .chain(self.exclude_layers.iter())
+            .filter(|n| !known.contains(n))
+            .cloned()
+            .collect()
+    }
+
+    /// Size of a pixel in meters, from the DPI when given.
+    fn pixel_size(&self) -> f64 {
+        self.dpi.map_or(STANDARD_PIXEL_SIZE, |dpi| 0.0254 / dpi)
+    }
+
+    /// Scale denominator of the output, map units are taken as meters.
+    fn scale_denominator(&self) -> f64 {
+        self.extent_width() / (self.width() * self.pixel_size())
+    }
+
     fn width(&self) -> f64 {
         self.size[0]
     }
@@ -139,16 +270,21 @@ impl Arguments {
     }
 }
 
+fn parse_opt(value: Option<&str>) -> Result<Option<f64>, std::num::ParseFloatError> {
+    value.map(|v| v.parse()).transpose()
+}
+
 fn main() -> Result<(), &'static str> {
     let matches = App::new("Mafe")
         .version("0.1")
         .about("A convivial map processor")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("map_file")
                 .short("f")
                 .long("map_file")
                 .value_name("MAP FILE")
-                .help("The map file to process")
+                .help("The map file to process, a .map.json file holds a serialized spec")
                 .required(true)
                 .takes_value(true),
         )
@@ -169,33 +305,120 @@ fn main() -> Result<(), &'static str> {
         .arg(
             Arg::with_name("east")
                 .long("east")
-                .help("east")
-                .required(true)
+                .help("east, overrides the map extent")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("north")
                 .long("north")
-                .help("north")
-                .required(true)
+                .help("north, overrides the map extent")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("west")
                 .long("west")
-                .help("west")
-                .required(true)
+                .help("west, overrides the map extent")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("south")
                 .long("south")
-                .help("south")
-                .required(true)
+                .help("south, overrides the map extent")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dpi")
+                .long("dpi")
+                .help("output resolution, defaults to the OGC 0.28mm pixel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("layers")
+                .long("layers")
+                .value_name("NAMES")
+                .help("comma separated names of the layers to render")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude_layers")
+                .long("exclude-layers")
+                .value_name("NAMES")
+                .help("comma separated names of the layers to leave out")
                 .takes_value(true),
         )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Rewrites map files in place in their canonical form")
+                .arg(
+                    Arg::with_name("files")
+                        .value_name("MAP FILE")
+                        .help("The map files to format")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Prints the map file of a MapLibre style, warnings go to stderr")
+                .arg(
+                    Arg::with_name("style")
+                        .value_name("STYLE")
+                        .help("The style document to convert")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-sld")
+                .about("Prints the map file of an SLD style, warnings go to stderr")
+                .arg(
+                    Arg::with_name("style")
+                        .value_name("STYLE")
+                        .help("The SLD document to convert")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .value_name("GEOJSON")
+                        .help("The GeoJSON file the layers draw")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        let mut failed = false;
+        for file in matches.values_of("files").into_iter().flatten() {
+            if let Err(err) = fmt::format_file(Path::new(file)) {
+                println!("{}", err);
+                failed = true;
+            }
+        }
+        return if failed {
+            Err("some files could not be formatted")
+        } else {
+            Ok(())
+        };
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let style = matches.value_of("style").ok_or("missing style")?;
+        return import::import_file(Path::new(style)).map_err(|err| {
+            println!("{}", err);
+            "the style could not be imported"
+        });
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-sld") {
+        let style = matches.value_of("style").ok_or("missing style")?;
+        let source = matches.value_of("source").ok_or("missing source")?;
+        return import::import_sld_file(Path::new(style), source).map_err(|err| {
+            println!("{}", err);
+            "the style could not be imported"
+        });
+    }
+
     let args = Arguments::from_matches(matches)?;
     run_main(args);
     Ok(())
@@ -207,11 +430,26 @@ mod test {
 
     use super::*;
     // use crate::ast::*;
+
+    /// Source of a test map, `map` holds the directives following its
+    /// header and each of `layers` the lines of a layer, newline ended.
+    fn map_source(map: &str, layers: &[&str]) -> String {
+        let mut source = format!("map\nsrid 3857\nextent 0 0 1 1\n{}", map);
+        for layer in layers {
+            source.push_str(&format!("\n\nlayer\n{}", layer));
+        }
+        source
+    }
+
     #[test]
     fn initial_transform() {
         let args = Arguments {
             extent: [148284.9, 170598.2, 148957.2, 170993.6],
+            extent_override: [None; 4],
             size: [1000.0, 1000.0],
+            dpi: None,
+            layers: None,
+            exclude_layers: Vec::new(),
             mapfile: String::from("parser/data/map-format-geojson"),
         };
 
@@ -220,4 +458,74 @@ mod test {
         let to = initial * origin;
         assert_eq!(to, kurbo::Point::new(0.0, 0.0));
     }
+
+    #[test]
+    fn extent_override() {
+        let args = Arguments {
+            extent: [0.0; 4],
+            extent_override: [None, Some(170000.0), None, None],
+            size: [1000.0, 1000.0],
+            dpi: None,
+            layers: None,
+            exclude_layers: Vec::new(),
+            mapfile: String::from("parser/data/map-format-geojson"),
+        };
+        let extent = parser::ast::Extent {
+            minx: parser::ast::Num::Float(148284.9),
+            miny: parser::ast::Num::Float(170598.2),
+            maxx: parser::ast::Num::Integer(148957),
+            maxy: parser::ast::Num::Float(170993.6),
+        };
+
+        let args = args.with_extent(&extent);
+        assert_eq!(args.extent, [148284.9, 170000.0, 148957.0, 170993.6]);
+    }
+
+    #[test]
+    fn scale_denominator() {
+        let mut args = Arguments {
+            extent: [0.0, 0.0, 2800.0, 2800.0],
+            extent_override: [None; 4],
+            size: [1000.0, 1000.0],
+            dpi: None,
+            layers: None,
+            exclude_layers: Vec::new(),
+            mapfile: String::from("parser/data/map-format-geojson"),
+        };
+        assert!((args.scale_denominator() - 10000.0).abs() < 1e-6);
+        args.dpi = Some(254.0);
+        assert!((args.scale_denominator() - 28000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn layer_selection() {
+        let map_str = map_source(
+            "",
+            &[
+                "name \"roads\"\nsource geojson \"a\"\n",
+                "name \"parks\"\nsource geojson \"b\"\n",
+                "source geojson \"c\"\n",
+            ],
+        );
+        let spec = parser::parse_str(&map_str).unwrap();
+        let mut args = Arguments {
+            extent: [0.0; 4],
+            extent_override: [None; 4],
+            size: [1000.0, 1000.0],
+            dpi: None,
+            layers: None,
+            exclude_layers: vec![String::from("parks")],
+            mapfile: String::from("parser/data/map-format-geojson"),
+        };
+        let kept = |args: &Arguments| -> Vec<bool> {
+            spec.layers.iter().map(|l| args.keep_layer(l)).collect()
+        };
+        assert_eq!(kept(&args), vec![true, false, true]);
+        args.layers = layer_names(Some("roads, parks, rivers"));
+        assert_eq!(kept(&args), vec![true, false, false]);
+        assert_eq!(
+            args.unknown_layers(&spec.layers),
+            vec![String::from("rivers")]
+        );
+    }
 }
@@ -8,6 +8,8 @@ use std::{borrow::Cow, ops::RangeBounds};
 
 use cairo::{Context, Filter, Format, ImageSurface, Matrix, SurfacePattern};
 
+use crate::render::GroupContext;
+
 use piet::{
     kurbo::{Affine, PathEl, Point, QuadBez, Rect, Shape, Size},
     FontFamily, HitTestPosition, LineMetric, TextAlignment, TextAttribute, TextStorage,
@@ -126,6 +128,17 @@ pub struct CairoRenderContext<'a> {
 
 impl<'a> CairoRenderContext<'a> {}
 
+impl<'a> GroupContext for CairoRenderContext<'a> {
+    fn push_group(&mut self) {
+        self.ctx.push_group();
+    }
+
+    fn pop_group(&mut self, opacity: f64) {
+        self.ctx.pop_group_to_source();
+        self.ctx.paint_with_alpha(opacity);
+    }
+}
+
 #[derive(Clone)]
 pub enum Brush {
     Solid(u32),
@@ -8,9 +8,16 @@ fn kpoint(p: &apply::geom::Point) -> Point {
     Point { x: p.x(), y: p.y() }
 }
 
+/// Offscreen drawing, piet has no notion of it.
+pub trait GroupContext {
+    fn push_group(&mut self);
+    /// Composites the current group with `opacity` and drops it.
+    fn pop_group(&mut self, opacity: f64);
+}
+
 pub fn render<Ctx>(ctx: &mut Ctx, ops: &OpList) -> Result<(), Error>
 where
-    Ctx: RenderContext,
+    Ctx: RenderContext + GroupContext,
 {
     let mut path: Vec<PathEl> = Vec::new();
     for op in ops {
@@ -45,6 +52,8 @@ where
             Op::Transform((a, b, c, d, e, f)) => {
                 ctx.transform(Affine::new([*a, *b, *c, *d, *e, *f]))
             }
+            Op::PushGroup => ctx.push_group(),
+            Op::PopGroup(opacity) => ctx.pop_group(*opacity),
             _ => {}
         }
     }
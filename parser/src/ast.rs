@@ -1,5 +1,9 @@
 use std::{convert::TryFrom, fmt};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(untagged))]
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Num {
     Integer(i64),
@@ -34,12 +38,18 @@ impl Num {
 //     }
 // }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Literal {
     Nil,
     Number(Num),
     String(String),
     Boolean(bool),
+    List(Vec<Literal>),
 }
 
 impl fmt::Display for Literal {
@@ -49,6 +59,10 @@ impl fmt::Display for Literal {
             Literal::Number(n) => write!(f, "{}", n),
             Literal::String(s) => write!(f, "{}", s),
             Literal::Boolean(b) => write!(f, "{}", if *b { "true" } else { "false" }),
+            Literal::List(items) => {
+                let items: Vec<String> = items.iter().map(|i| format!("{}", i)).collect();
+                write!(f, "({})", items.join(", "))
+            }
         }
     }
 }
@@ -135,17 +149,39 @@ impl TryFrom<Literal> for bool {
 //     }
 // }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct FunctionCall {
     pub name: String,
     pub args: ValueList,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Srid {
     pub value: i64,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Name {
+    pub value: String,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Visible {
+    pub value: bool,
+}
+
+/// Applies to the layer as a whole, between 0 and 1.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Opacity {
+    pub value: Num,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Extent {
     pub minx: Num,
@@ -154,36 +190,103 @@ pub struct Extent {
     pub maxy: Num,
 }
 
-#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DataType {
     String,
     Number,
     Boolean,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Select {
     pub selector: String,
     pub datatype: DataType,
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Constructor {
     Select(Select),
     Val(Value),
+    /// a parameter of the enclosing `fn`, by position
+    Param(usize),
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Data {
     pub ident: String,
     pub constructor: Box<Constructor>,
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Value {
     Lit(Literal),
     Data(Data),
     Fn(FunctionCall),
+    /// a call to a function declared with `fn`
+    Call(FunctionCall),
+    Expr(Box<Expr>),
+    Match(Box<Match>),
+}
+
+impl Value {
+    /// The type known without looking at a feature, if any.
+    pub fn datatype(&self) -> Option<DataType> {
+        match self {
+            Value::Lit(Literal::Number(_)) | Value::Expr(_) => Some(DataType::Number),
+            Value::Lit(Literal::String(_)) => Some(DataType::String),
+            Value::Lit(Literal::Boolean(_)) => Some(DataType::Boolean),
+            Value::Lit(_) | Value::Fn(_) | Value::Call(_) => None,
+            Value::Data(d) => match d.constructor.as_ref() {
+                Constructor::Val(v) => v.datatype(),
+                Constructor::Select(s) => Some(s.datatype),
+                Constructor::Param(_) => None,
+            },
+            Value::Match(m) => m.default.datatype(),
+        }
+    }
+}
+
+/// `match(subject, case, value, ..., default)`, a list case
+/// matches any of its items.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub subject: Value,
+    pub arms: Vec<ValuePair>,
+    pub default: Value,
+}
+
+impl From<Match> for Value {
+    fn from(arg: Match) -> Self {
+        Value::Match(Box::new(arg))
+    }
+}
+
+/// `fn name(a, b) = body`, parameters are `Constructor::Param`
+/// data in the body.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct FunctionDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Value,
 }
 
 pub type ValuePair = (Value, Value);
@@ -192,8 +295,50 @@ pub fn pair(left: Value, right: Value) -> ValuePair {
     (left, right)
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Neg(Value),
+    Add(ValuePair),
+    Sub(ValuePair),
+    Mul(ValuePair),
+    Div(ValuePair),
+    Rem(ValuePair),
+}
+
+impl From<Expr> for Value {
+    fn from(arg: Expr) -> Self {
+        Value::Expr(Box::new(arg))
+    }
+}
+
 pub type ValueList = Vec<Value>;
 
+/// Geometry classes for `geometry is`, a class covers the single
+/// and multi part forms, `multi` only the multi part ones.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeometryClass {
+    Point,
+    Line,
+    Polygon,
+    Multi,
+    Collection,
+}
+
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Predicate {
     Equal(ValuePair),
@@ -202,12 +347,51 @@ pub enum Predicate {
     GreaterThanOrEqual(ValuePair),
     LesserThan(ValuePair),
     LesserThanOrEqual(ValuePair),
+    /// membership, a right side that is not a list is a list of one
+    In(ValuePair),
+    NotIn(ValuePair),
+    /// SQL like, `%` and `_` are wildcards
+    Like(ValuePair),
+    ILike(ValuePair),
+    /// regular expression search
+    Match(ValuePair),
+    IMatch(ValuePair),
+    StartsWith(ValuePair),
+    GeometryIs(GeometryClass),
+}
+
+impl Predicate {
+    /// Both sides of a comparison, `None` for a geometry class test.
+    pub fn operands(&self) -> Option<&ValuePair> {
+        match self {
+            Predicate::Equal(p)
+            | Predicate::NotEqual(p)
+            | Predicate::GreaterThan(p)
+            | Predicate::GreaterThanOrEqual(p)
+            | Predicate::LesserThan(p)
+            | Predicate::LesserThanOrEqual(p)
+            | Predicate::In(p)
+            | Predicate::NotIn(p)
+            | Predicate::Like(p)
+            | Predicate::ILike(p)
+            | Predicate::Match(p)
+            | Predicate::IMatch(p)
+            | Predicate::StartsWith(p) => Some(p),
+            Predicate::GeometryIs(_) => None,
+        }
+    }
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum PredGroup {
     Empty,
     Pred(Predicate),
+    Not(Box<PredGroup>),
     Or {
         left: Box<PredGroup>,
         right: Box<PredGroup>,
@@ -218,41 +402,55 @@ pub enum PredGroup {
     },
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Circle {
     pub radius: Value,
 }
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Square {
     pub size: Value,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Color;
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Fill {
     pub color: Value,
 }
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Stroke {
     pub color: Value,
     pub size: Value,
 }
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Pattern {
     pub path: Value,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Text {
     pub content: Value,
 }
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Clear;
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct DrawGeometry;
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Command {
     Clear(Clear),
@@ -265,17 +463,28 @@ pub enum Command {
     Text(Text),
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Anchor {
     Point,
     Centroid,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Size {
     pub size: Value,
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Intent {
     Anchor(Anchor),
@@ -283,18 +492,69 @@ pub enum Intent {
     Size(Size),
 }
 
+/// Scale denominators a layer or a rule applies to, `minscale` is
+/// inclusive and `maxscale` exclusive, a missing bound is open.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScaleRange {
+    pub min: Option<Num>,
+    pub max: Option<Num>,
+}
+
+impl ScaleRange {
+    pub fn is_open(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    pub fn contains(&self, scale: f64) -> bool {
+        self.min.as_ref().is_none_or(|min| scale >= min.as_float())
+            && self.max.as_ref().is_none_or(|max| scale < max.as_float())
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Sym {
+    pub scale: ScaleRange,
     pub predicate: PredGroup,
     pub consequent: Vec<Command>,
 }
 
+/// Applies to features no earlier `sym` of the layer matched,
+/// written `else -> ...` or `sym * -> ...`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Else {
+    pub consequent: Vec<Command>,
+}
+
+/// How the rules of a layer share features.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Matching {
+    /// every matching `sym` draws, the default
+    All,
+    /// a feature is drawn by the first `sym` it matches only
+    First,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Label {
+    pub scale: ScaleRange,
     pub predicate: PredGroup,
     pub consequent: Vec<Intent>,
 }
 
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Driver {
     Geojson,
@@ -302,6 +562,7 @@ pub enum Driver {
     Shapefile,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Source {
     pub driver: Driver,
@@ -309,14 +570,53 @@ pub struct Source {
     pub srid: Option<Num>,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Comment {
+    /// raw text, markers included
+    pub text: String,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct Include {
+    pub path: String,
+    pub directives: Vec<Directive>,
+}
+
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
 #[derive(Debug, Clone)]
 pub enum Directive {
     Srid(Srid),
-    // Extent(Extent),
+    Extent(Extent),
+    Name(Name),
+    Visible(Visible),
+    Opacity(Opacity),
     Data(Data),
+    Fn(FunctionDef),
     Sym(Sym),
+    Else(Else),
+    Matching(Matching),
+    Scale(ScaleRange),
     Label(Label),
     Source(Source),
+    Comment(Comment),
+    Include(Include),
+}
+
+/// Directives with each include replaced by the directives it brings in.
+pub fn splice(directives: &[Directive]) -> Vec<Directive> {
+    directives
+        .iter()
+        .flat_map(|d| match d {
+            Directive::Include(include) => splice(&include.directives),
+            _ => vec![d.clone()],
+        })
+        .collect()
 }
 
 impl From<Srid> for Directive {
@@ -325,11 +625,29 @@ impl From<Srid> for Directive {
     }
 }
 
-// impl From<Extent> for Directive {
-//     fn from(arg: Extent) -> Self {
-//         Directive::Extent(arg)
-//     }
-// }
+impl From<Extent> for Directive {
+    fn from(arg: Extent) -> Self {
+        Directive::Extent(arg)
+    }
+}
+
+impl From<Name> for Directive {
+    fn from(arg: Name) -> Self {
+        Directive::Name(arg)
+    }
+}
+
+impl From<Visible> for Directive {
+    fn from(arg: Visible) -> Self {
+        Directive::Visible(arg)
+    }
+}
+
+impl From<Opacity> for Directive {
+    fn from(arg: Opacity) -> Self {
+        Directive::Opacity(arg)
+    }
+}
 
 impl From<Data> for Directive {
     fn from(arg: Data) -> Self {
@@ -337,12 +655,36 @@ impl From<Data> for Directive {
     }
 }
 
+impl From<FunctionDef> for Directive {
+    fn from(arg: FunctionDef) -> Self {
+        Directive::Fn(arg)
+    }
+}
+
 impl From<Sym> for Directive {
     fn from(arg: Sym) -> Self {
         Directive::Sym(arg)
     }
 }
 
+impl From<Else> for Directive {
+    fn from(arg: Else) -> Self {
+        Directive::Else(arg)
+    }
+}
+
+impl From<Matching> for Directive {
+    fn from(arg: Matching) -> Self {
+        Directive::Matching(arg)
+    }
+}
+
+impl From<ScaleRange> for Directive {
+    fn from(arg: ScaleRange) -> Self {
+        Directive::Scale(arg)
+    }
+}
+
 impl From<Label> for Directive {
     fn from(arg: Label) -> Self {
         Directive::Label(arg)
@@ -355,16 +697,66 @@ impl From<Source> for Directive {
     }
 }
 
+impl From<Include> for Directive {
+    fn from(arg: Include) -> Self {
+        Directive::Include(arg)
+    }
+}
+
+impl From<Comment> for Directive {
+    fn from(arg: Comment) -> Self {
+        Directive::Comment(arg)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct MapBlock {
     pub directives: Vec<Directive>,
+    /// byte offset of each directive in the map file,
+    /// empty when the block was not parsed from text
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub positions: Vec<usize>,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct LayerBlock {
     pub directives: Vec<Directive>,
+    /// as for `MapBlock`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub positions: Vec<usize>,
+}
+
+impl LayerBlock {
+    pub fn name(&self) -> Option<String> {
+        splice(&self.directives).into_iter().find_map(|d| match d {
+            Directive::Name(n) => Some(n.value),
+            _ => None,
+        })
+    }
+
+    /// `true` unless the layer says `visible false`.
+    pub fn visible(&self) -> bool {
+        splice(&self.directives).iter().all(|d| match d {
+            Directive::Visible(v) => v.value,
+            _ => true,
+        })
+    }
+
+    /// `1.0` unless the layer sets an opacity.
+    pub fn opacity(&self) -> f64 {
+        splice(&self.directives)
+            .iter()
+            .find_map(|d| match d {
+                Directive::Opacity(o) => Some(o.value.as_float()),
+                _ => None,
+            })
+            .unwrap_or(1.0)
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct MapSpec {
     pub map: MapBlock,
@@ -0,0 +1,119 @@
+use crate::ast::{Comment, Directive, MapSpec};
+
+const KEYWORD_LAYER: &[u8] = b"layer";
+
+/// What a scan of the source finds outside strings.
+struct Scan {
+    /// byte offset and text of every comment
+    comments: Vec<(usize, String)>,
+    /// byte offset of every `layer` keyword opening a block
+    layers: Vec<usize>,
+}
+
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn scan(source: &str) -> Scan {
+    let bytes = source.as_bytes();
+    let mut scan = Scan {
+        comments: Vec::new(),
+        layers: Vec::new(),
+    };
+    let mut i = 0;
+    while i < bytes.len() {
+        let (end, comment) = match bytes[i] {
+            b'"' => {
+                let mut j = i + 1;
+                while j < bytes.len() && bytes[j] != b'"' {
+                    j += if bytes[j] == b'\\' { 2 } else { 1 };
+                }
+                (j + 1, false)
+            }
+            b'#' => {
+                let end = bytes[i..].iter().position(|c| *c == b'\n');
+                (end.map_or(bytes.len(), |n| i + n), true)
+            }
+            b'/' if bytes[i..].starts_with(b"/*") => {
+                let end = source[i + 2..].find("*/");
+                (end.map_or(bytes.len(), |n| i + 2 + n + 2), true)
+            }
+            _ => {
+                let line_start = i == 0 || bytes[i - 1] == b'\n';
+                let after = i + KEYWORD_LAYER.len();
+                if line_start
+                    && bytes[i..].starts_with(KEYWORD_LAYER)
+                    && !bytes.get(after).copied().is_some_and(is_ident)
+                {
+                    scan.layers.push(i);
+                }
+                (i + 1, false)
+            }
+        };
+        let end = end.min(bytes.len());
+        if comment {
+            scan.comments.push((i, String::from(&source[i..end])));
+        }
+        i = end;
+    }
+    scan
+}
+
+/// A comment at `position` that is already kept as a whole line
+/// comment, the directive starts with the indent before it.
+fn is_kept(source: &str, directives: &[Directive], positions: &[usize], position: usize) -> bool {
+    directives
+        .iter()
+        .zip(positions.iter())
+        .any(|(directive, start)| {
+            matches!(directive, Directive::Comment(_))
+                && *start <= position
+                && source[*start..position]
+                    .bytes()
+                    .all(|c| c == b' ' || c == b'\t')
+        })
+}
+
+fn insert(directives: &mut Vec<Directive>, positions: &mut Vec<usize>, at: usize, text: String) {
+    let index = positions.partition_point(|p| *p <= at);
+    directives.insert(index, Comment { text }.into());
+    positions.insert(index, at);
+}
+
+/// Keeps the comments the grammar reads as blanks, trailing a directive,
+/// inside a predicate or between commands. Each is added to its block
+/// as a whole line comment following the directive it was found in.
+pub(crate) fn keep_inner_comments(source: &str, spec: &mut MapSpec) {
+    let scan = scan(source);
+    for (position, text) in scan.comments {
+        let layer = scan
+            .layers
+            .iter()
+            .filter(|start| **start <= position)
+            .count();
+        let (directives, positions) = match layer {
+            0 => (&mut spec.map.directives, &mut spec.map.positions),
+            n => match spec.layers.get_mut(n - 1) {
+                Some(block) => (&mut block.directives, &mut block.positions),
+                None => continue,
+            },
+        };
+        if !is_kept(source, directives, positions, position) {
+            insert(directives, positions, position, text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod comment_test {
+    use super::*;
+
+    #[test]
+    fn scan_skips_strings() {
+        let source = "map\ndata a \"# not /* a */ comment\" # one\nlayer /* two */\n# three\n";
+        let scan = scan(source);
+        let texts: Vec<&str> = scan.comments.iter().map(|(_, t)| t.as_str()).collect();
+        assert_eq!(texts, vec!["# one", "/* two */", "# three"]);
+        assert_eq!(scan.layers, vec![source.find("layer").unwrap()]);
+    }
+}
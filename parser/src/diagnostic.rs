@@ -0,0 +1,125 @@
+use std::fmt;
+
+use crate::parser::ParseError;
+
+/// A `ParseError` located in the source it came from.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, counted in characters
+    pub column: usize,
+    pub source_line: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(source: &str, error: &ParseError) -> Self {
+        // errors without a position are reported where the input ends
+        let position = error.position().unwrap_or(source.len());
+        Diagnostic::at(source, position, format!("{}", error))
+    }
+
+    /// A message about the byte offset `position` of `source`.
+    pub fn at(source: &str, position: usize, message: String) -> Self {
+        let position = position.min(source.len());
+        let before = &source.as_bytes()[..position];
+        let line_start = before
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map(|i| line_start + i)
+            .unwrap_or(source.len());
+        let line = before.iter().filter(|c| **c == b'\n').count() + 1;
+        let column = String::from_utf8_lossy(&before[line_start..])
+            .chars()
+            .count()
+            + 1;
+
+        Diagnostic {
+            line,
+            column,
+            source_line: String::from(&source[line_start..line_end]),
+            message,
+        }
+    }
+
+    /// The source line followed by a caret under the error column.
+    pub fn snippet(&self) -> String {
+        let padding: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!("{}\n{}^", self.source_line, padding)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = format!("{}", self.line);
+        let blank = " ".repeat(gutter.len());
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{}--> {}:{}", blank, self.line, self.column)?;
+        writeln!(f, "{} |", blank)?;
+        let mut snippet = self.snippet().lines().map(String::from).collect::<Vec<_>>();
+        let caret = snippet.pop().unwrap_or_default();
+        for source_line in snippet {
+            writeln!(f, "{} | {}", gutter, source_line)?;
+        }
+        write!(f, "{} | {}", blank, caret)
+    }
+}
+
+#[cfg(test)]
+mod diagnostic_test {
+    use super::*;
+    use crate::parse_str;
+
+    const HEAD: &str = "map
+srid 3857
+extent 0 0 100 100
+
+
+layer
+source geojson \"a.geojson\"
+";
+
+    fn diagnostic(body: &str) -> Diagnostic {
+        let source = format!("{}{}", HEAD, body);
+        match parse_str(&source) {
+            Ok(_) => panic!("parse should fail"),
+            Err(err) => Diagnostic::new(&source, &err),
+        }
+    }
+
+    #[test]
+    fn missing_arrow() {
+        let d = diagnostic("data prop select \"col\" number\nsym prop < 10 fill red\n");
+        assert_eq!(d.line, 9);
+        assert_eq!(d.column, 15);
+        assert_eq!(d.source_line, "sym prop < 10 fill red");
+        assert_eq!(d.message, "expected `->` after predicate");
+    }
+
+    #[test]
+    fn data_not_in_scope() {
+        let d = diagnostic("data prop select \"col\" number\nsym prop < truc -> circle 2\n");
+        assert_eq!(d.line, 9);
+        assert_eq!(d.column, 12);
+        assert_eq!(d.message, "data `truc` not in scope");
+        assert_eq!(d.snippet(), "sym prop < truc -> circle 2\n           ^");
+    }
+
+    #[test]
+    fn unknown_directive() {
+        let d = diagnostic("data prop select \"col\" number\nsymbol prop < 10 -> circle 2\n");
+        assert_eq!(d.line, 9);
+        assert_eq!(d.column, 1);
+        assert_eq!(d.message, "expected a directive");
+    }
+}
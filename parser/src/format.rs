@@ -0,0 +1,531 @@
+//! Prints a `MapSpec` back to canonical map syntax.
+//!
+//! Blocks are separated by two blank lines. A `sym` or `label` with a
+//! single predicate and a single consequent stays on one line, otherwise
+//! each top level `|` or `&` and each `->` goes on its own line,
+//! indented by two spaces.
+
+use crate::ast::{
+    Anchor, Command, Constructor, DataType, Directive, Driver, Expr, GeometryClass, Intent,
+    LayerBlock, Literal, MapSpec, Matching, Num, PredGroup, Predicate, ScaleRange, Value,
+};
+
+const INDENT: &str = "  ";
+const BLOCK_SEP: &str = "\n\n\n";
+
+pub fn format_num(n: &Num) -> String {
+    match n {
+        Num::Integer(i) => format!("{}", i),
+        // keep the decimal point, `4.0` must not come back as an integer
+        Num::Float(f) => format!("{:?}", f),
+    }
+}
+
+pub fn format_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x08' => out.push_str("\\b"),
+            '\x0C' => out.push_str("\\f"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn format_literal(lit: &Literal) -> String {
+    match lit {
+        Literal::Nil => String::from("nil"),
+        Literal::Number(n) => format_num(n),
+        Literal::String(s) => format_string(s),
+        Literal::Boolean(b) => format!("{}", b),
+        Literal::List(items) => format!(
+            "({})",
+            items
+                .iter()
+                .map(format_literal)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn precedence(value: &Value) -> u8 {
+    match value {
+        Value::Expr(e) => match e.as_ref() {
+            Expr::Add(_) | Expr::Sub(_) => 1,
+            Expr::Mul(_) | Expr::Div(_) | Expr::Rem(_) => 2,
+            Expr::Neg(_) => 3,
+        },
+        _ => 4,
+    }
+}
+
+fn operand(value: &Value, parenthesize: bool) -> String {
+    if parenthesize {
+        format!("({})", format_value(value))
+    } else {
+        format_value(value)
+    }
+}
+
+fn binary(op: &str, level: u8, (left, right): &(Value, Value)) -> String {
+    // operators are left associative
+    format!(
+        "{} {} {}",
+        operand(left, precedence(left) < level),
+        op,
+        operand(right, precedence(right) <= level)
+    )
+}
+
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Lit(l) => format_literal(l),
+        Value::Data(d) => d.ident.clone(),
+        Value::Fn(f) | Value::Call(f) => format!(
+            "{}({})",
+            f.name,
+            f.args
+                .iter()
+                .map(format_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Match(m) => {
+            let mut args = vec![format_value(&m.subject)];
+            for (case, value) in m.arms.iter() {
+                args.push(format_value(case));
+                args.push(format_value(value));
+            }
+            args.push(format_value(&m.default));
+            format!("match({})", args.join(", "))
+        }
+        Value::Expr(e) => match e.as_ref() {
+            Expr::Neg(v) => format!("-{}", operand(v, precedence(v) < 3)),
+            Expr::Add(p) => binary("+", 1, p),
+            Expr::Sub(p) => binary("-", 1, p),
+            Expr::Mul(p) => binary("*", 2, p),
+            Expr::Div(p) => binary("/", 2, p),
+            Expr::Rem(p) => binary("%", 2, p),
+        },
+    }
+}
+
+pub fn format_predicate(pred: &Predicate) -> String {
+    let (op, (left, right)) = match pred {
+        Predicate::GeometryIs(class) => {
+            let class = match class {
+                GeometryClass::Point => "point",
+                GeometryClass::Line => "line",
+                GeometryClass::Polygon => "polygon",
+                GeometryClass::Multi => "multi",
+                GeometryClass::Collection => "collection",
+            };
+            return format!("geometry is {}", class);
+        }
+        Predicate::Equal(p) => ("=", p),
+        Predicate::NotEqual(p) => ("!=", p),
+        Predicate::GreaterThan(p) => (">", p),
+        Predicate::GreaterThanOrEqual(p) => (">=", p),
+        Predicate::LesserThan(p) => ("<", p),
+        Predicate::LesserThanOrEqual(p) => ("<=", p),
+        Predicate::In(p) => ("in", p),
+        Predicate::NotIn(p) => ("not in", p),
+        Predicate::Like(p) => ("like", p),
+        Predicate::ILike(p) => ("ilike", p),
+        Predicate::Match(p) => ("~", p),
+        Predicate::IMatch(p) => ("~*", p),
+        Predicate::StartsWith(p) => ("^=", p),
+    };
+    format!("{} {} {}", format_value(left), op, format_value(right))
+}
+
+/// Flattens the left deep chain the parser builds out of `|` and `&`.
+fn chain(group: &PredGroup) -> Vec<(Option<&'static str>, &PredGroup)> {
+    match group {
+        PredGroup::Or { left, right } => {
+            let mut c = chain(left);
+            c.push((Some("|"), right));
+            c
+        }
+        PredGroup::And { left, right } => {
+            let mut c = chain(left);
+            c.push((Some("&"), right));
+            c
+        }
+        _ => vec![(None, group)],
+    }
+}
+
+fn pred_operand(group: &PredGroup) -> String {
+    match group {
+        PredGroup::Empty => String::new(),
+        PredGroup::Pred(p) => format_predicate(p),
+        PredGroup::Not(inner) => format!("!{}", pred_operand(inner)),
+        PredGroup::Or { .. } | PredGroup::And { .. } => format!("({})", format_pred_group(group)),
+    }
+}
+
+fn join_chain(links: &[(Option<&'static str>, &PredGroup)], sep: &str) -> String {
+    links
+        .iter()
+        .map(|(op, group)| match op {
+            Some(op) => format!("{}{} {}", sep, op, pred_operand(group)),
+            None => pred_operand(group),
+        })
+        .collect()
+}
+
+/// A predicate group on a single line.
+pub fn format_pred_group(group: &PredGroup) -> String {
+    join_chain(&chain(group), " ")
+}
+
+pub fn format_command(command: &Command) -> String {
+    match command {
+        Command::Clear(_) => String::from("clear"),
+        Command::DrawGeometry(_) => String::from("draw"),
+        Command::Circle(c) => format!("circle {}", format_value(&c.radius)),
+        Command::Square(s) => format!("square {}", format_value(&s.size)),
+        Command::Fill(f) => format!("fill {}", format_value(&f.color)),
+        Command::Stroke(s) => format!(
+            "stroke {} {}",
+            format_value(&s.color),
+            format_value(&s.size)
+        ),
+        Command::Pattern(p) => format!("pattern {}", format_value(&p.path)),
+        Command::Text(t) => format!("label {}", format_value(&t.content)),
+    }
+}
+
+pub fn format_intent(intent: &Intent) -> String {
+    match intent {
+        Intent::Anchor(Anchor::Point) => String::from("anchor point"),
+        Intent::Anchor(Anchor::Centroid) => String::from("anchor centroid"),
+        Intent::Text(t) => format!("text {}", format_value(&t.content)),
+        Intent::Size(s) => format!("size {}", format_value(&s.size)),
+    }
+}
+
+fn arrows(head: String, consequent: Vec<String>, one_line: bool) -> String {
+    if one_line && consequent.len() == 1 {
+        format!("{} -> {}", head, consequent[0])
+    } else {
+        let consequent: String = consequent
+            .iter()
+            .map(|c| format!("\n{}-> {}", INDENT, c))
+            .collect();
+        format!("{}{}", head, consequent)
+    }
+}
+
+fn rule(keyword: &str, predicate: &PredGroup, consequent: Vec<String>) -> String {
+    let links = chain(predicate);
+    let one_line = links.len() == 1;
+    let sep = if one_line {
+        String::from(" ")
+    } else {
+        format!("\n{}", INDENT)
+    };
+    let head = format!("{} {}", keyword, join_chain(&links, &sep));
+    arrows(head, consequent, one_line)
+}
+
+fn scale_range(range: &ScaleRange) -> String {
+    let min = range
+        .min
+        .as_ref()
+        .map(|n| format!("minscale {}", format_num(n)));
+    let max = range
+        .max
+        .as_ref()
+        .map(|n| format!("maxscale {}", format_num(n)));
+    let bounds: Vec<String> = min.into_iter().chain(max).collect();
+    bounds.join(" ")
+}
+
+/// A rule keyword followed by its scale range, if any.
+fn rule_keyword(keyword: &str, range: &ScaleRange) -> String {
+    match range.is_open() {
+        true => String::from(keyword),
+        false => format!("{} {}", keyword, scale_range(range)),
+    }
+}
+
+fn datatype(dt: &DataType) -> &'static str {
+    match dt {
+        DataType::String => "string",
+        DataType::Number => "number",
+        DataType::Boolean => "bool",
+    }
+}
+
+pub fn format_directive(directive: &Directive) -> String {
+    match directive {
+        Directive::Srid(s) => format!("srid {}", s.value),
+        Directive::Extent(e) => format!(
+            "extent {} {} {} {}",
+            format_num(&e.minx),
+            format_num(&e.miny),
+            format_num(&e.maxx),
+            format_num(&e.maxy)
+        ),
+        Directive::Name(n) => format!("name {}", format_string(&n.value)),
+        Directive::Visible(v) => format!("visible {}", v.value),
+        Directive::Opacity(o) => format!("opacity {}", format_num(&o.value)),
+        Directive::Data(d) => match d.constructor.as_ref() {
+            Constructor::Val(v) => format!("data {} {}", d.ident, format_value(v)),
+            Constructor::Select(s) => format!(
+                "data {} select {} {}",
+                d.ident,
+                format_string(&s.selector),
+                datatype(&s.datatype)
+            ),
+            // parameters only live in function bodies
+            Constructor::Param(_) => format!("data {}", d.ident),
+        },
+        Directive::Fn(f) => format!(
+            "fn {}({}) = {}",
+            f.name,
+            f.params.join(", "),
+            format_value(&f.body)
+        ),
+        Directive::Sym(s) => rule(
+            &rule_keyword("sym", &s.scale),
+            &s.predicate,
+            s.consequent.iter().map(format_command).collect(),
+        ),
+        Directive::Else(e) => arrows(
+            String::from("else"),
+            e.consequent.iter().map(format_command).collect(),
+            true,
+        ),
+        Directive::Matching(Matching::First) => String::from("matching first"),
+        Directive::Matching(Matching::All) => String::from("matching all"),
+        Directive::Scale(range) => scale_range(range),
+        Directive::Label(l) => rule(
+            &rule_keyword("label", &l.scale),
+            &l.predicate,
+            l.consequent.iter().map(format_intent).collect(),
+        ),
+        Directive::Source(s) => {
+            let driver = match s.driver {
+                Driver::Geojson => "geojson",
+                Driver::Postgis => "postgis",
+                Driver::Shapefile => "shapefile",
+            };
+            match &s.srid {
+                Some(srid) => format!(
+                    "source {} {} {}",
+                    driver,
+                    format_string(&s.path),
+                    format_num(srid)
+                ),
+                None => format!("source {} {}", driver, format_string(&s.path)),
+            }
+        }
+        Directive::Comment(c) => c.text.clone(),
+        Directive::Include(i) => format!("include {}", format_string(&i.path)),
+    }
+}
+
+fn directives(directives: &[Directive]) -> String {
+    directives
+        .iter()
+        .map(|d| format!("{}\n", format_directive(d)))
+        .collect()
+}
+
+fn layer(block: &LayerBlock) -> String {
+    format!("layer\n{}", directives(&block.directives))
+}
+
+/// Canonical text for a whole map, comments leading the map block
+/// are printed above the `map` keyword.
+pub fn format_spec(spec: &MapSpec) -> String {
+    let map_directives = &spec.map.directives;
+    let header = map_directives
+        .iter()
+        .take_while(|d| matches!(d, Directive::Comment(_)))
+        .count();
+    let mut blocks = vec![format!(
+        "{}map\n{}",
+        directives(&map_directives[..header]),
+        directives(&map_directives[header..])
+    )];
+    blocks.extend(spec.layers.iter().map(layer));
+    blocks
+        .iter()
+        .map(|b| String::from(b.trim_end_matches('\n')))
+        .collect::<Vec<_>>()
+        .join(BLOCK_SEP)
+        + "\n"
+}
+
+#[cfg(test)]
+mod format_test {
+    use super::*;
+    use crate::parser::{parse_str, parse_str_with_options, ParseOptions};
+
+    /// Positions move with the layout, the rest must not.
+    fn unlocated(mut spec: MapSpec) -> MapSpec {
+        spec.map.positions.clear();
+        for layer in spec.layers.iter_mut() {
+            layer.positions.clear();
+        }
+        spec
+    }
+
+    fn round_trip(map_str: &str) {
+        let spec = parse_str(map_str).unwrap();
+        let formatted = format_spec(&spec);
+        let reparsed = match parse_str(&formatted) {
+            Ok(spec) => spec,
+            Err(err) => panic!("\n{}\n{}", formatted, err),
+        };
+        assert_eq!(
+            format!("{:?}", unlocated(spec)),
+            format!("{:?}", unlocated(reparsed.clone()))
+        );
+        assert_eq!(formatted, format_spec(&reparsed));
+    }
+
+    #[test]
+    fn round_trip_samples() {
+        round_trip(include_str!("../data/map-format-basic"));
+        round_trip(include_str!("../data/map-format-multiline"));
+        round_trip(include_str!("../data/map-format-pred-group"));
+        round_trip(include_str!("../data/map-format-pred-nested"));
+        round_trip(include_str!("../data/map-format-comments"));
+        round_trip(include_str!("../data/map-format-arith"));
+        round_trip(include_str!("../data/map-format-pred-in"));
+        round_trip(include_str!("../data/map-format-pred-not"));
+        round_trip(include_str!("../data/map-format-else"));
+        round_trip(include_str!("../data/map-format-match"));
+        round_trip(include_str!("../data/map-format-geometry"));
+        round_trip(include_str!("../data/map-format-scale"));
+        round_trip(include_str!("../data/map-format-layer-meta"));
+        round_trip(include_str!("../data/map-format-fn"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let samples = [
+            include_str!("../data/map-format-basic"),
+            include_str!("../data/map-format-arith"),
+            include_str!("../data/map-format-pred-group"),
+            include_str!("../data/map-format-match"),
+            include_str!("../data/map-format-scale"),
+            include_str!("../data/map-format-layer-meta"),
+        ];
+        for map_str in samples.iter() {
+            let spec = parse_str(map_str).unwrap();
+            let json = serde_json::to_string(&spec).unwrap();
+            let read: MapSpec = serde_json::from_str(&json).unwrap();
+            assert!(read.map.positions.is_empty());
+            assert_eq!(format_spec(&spec), format_spec(&read));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_schema() {
+        let spec = parse_str(
+            "map
+srid 3857
+extent 0 0 1.5 1
+
+layer
+name \"roads\"
+data kind select \"kind\" string
+sym kind = \"main\" -> stroke rgb(0, 0, 0) 2
+",
+        )
+        .unwrap();
+        let expected = r#"{"map":{"directives":[{"srid":{"value":3857}},{"extent":{"minx":0,"miny":0,"maxx":1.5,"maxy":1}}]},"layers":[{"directives":[{"name":{"value":"roads"}},{"data":{"ident":"kind","constructor":{"select":{"selector":"kind","datatype":"string"}}}},{"sym":{"scale":{"min":null,"max":null},"predicate":{"pred":{"equal":[{"data":{"ident":"kind","constructor":{"select":{"selector":"kind","datatype":"string"}}}},{"lit":{"string":"main"}}]}},"consequent":[{"stroke":{"color":{"fn":{"name":"rgb","args":[{"lit":{"number":0}},{"lit":{"number":0}},{"lit":{"number":0}}]}},"size":{"lit":{"number":2}}}}]}}]}]}"#;
+        assert_eq!(serde_json::to_string(&spec).unwrap(), expected);
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let map_str = "map
+srid 3857
+extent 0 0 1.0 1e3
+data blue  rgb( 0,0,255 )
+data s \"say \\\"hi\\\"\"
+
+
+layer
+source geojson \"a.geojson\"
+data prop select \"col\" number
+sym prop < 10   ->  fill blue
+sym prop >= 10 | prop = 0 -> fill blue -> stroke blue (prop - 1) * 2
+";
+        let expected = "map
+srid 3857
+extent 0 0 1.0 1000.0
+data blue rgb(0, 0, 255)
+data s \"say \\\"hi\\\"\"
+
+
+layer
+source geojson \"a.geojson\"
+data prop select \"col\" number
+sym prop < 10 -> fill blue
+sym prop >= 10
+  | prop = 0
+  -> fill blue
+  -> stroke blue (prop - 1) * 2
+";
+        assert_eq!(format_spec(&parse_str(map_str).unwrap()), expected);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let map_str = include_str!("../data/map-format-comments");
+        let options = ParseOptions {
+            keep_comments: true,
+            ..ParseOptions::default()
+        };
+        let spec = parse_str_with_options(map_str, options).unwrap();
+        let formatted = format_spec(&spec);
+        assert!(formatted.starts_with("# comments are allowed anywhere a blank is\n"));
+        assert!(formatted.contains("\n# a palette\ndata blue rgb(0, 0, 255)\n"));
+        assert!(formatted.contains("\n# sym prop1 = \"disabled\" -> fill blue\n"));
+    }
+
+    #[test]
+    fn keeps_every_comment() {
+        let map_str = include_str!("../data/map-format-comments");
+        let options = || ParseOptions {
+            keep_comments: true,
+            ..ParseOptions::default()
+        };
+        let formatted = format_spec(&parse_str_with_options(map_str, options()).unwrap());
+        let comments = [
+            "# comments are allowed anywhere a blank is",
+            "/* including block comments,\n   spanning several lines */",
+            "# web mercator",
+            "# a palette",
+            "# sym prop1 = \"disabled\" -> fill blue",
+            "# seats",
+            "/* inline */",
+            "# radius in pixels",
+            "# then colour it",
+        ];
+        for comment in comments.iter() {
+            assert!(formatted.contains(comment), "lost {}", comment);
+        }
+        assert!(formatted.contains("\nsrid 3857\n# web mercator\n"));
+        let reparsed = parse_str_with_options(&formatted, options()).unwrap();
+        assert_eq!(format_spec(&reparsed), formatted);
+    }
+}
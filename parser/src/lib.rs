@@ -1,4 +1,9 @@
 pub mod ast;
+mod comment;
+pub mod diagnostic;
+pub mod format;
 pub mod parser;
 
-pub use parser::parse_str;
+pub use diagnostic::Diagnostic;
+pub use format::format_spec;
+pub use parser::{parse_str, parse_str_with_options, ParseOptions};
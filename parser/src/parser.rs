@@ -1,31 +1,60 @@
-use pom::parser::{call, list, none_of, one_of, seq, sym, Parser};
+use pom::parser::{any, call, empty, end, list, none_of, one_of, seq, sym, Parser};
 use pom::Error as PomError;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
+use std::fs::read_to_string;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::{self, FromStr};
 
 use crate::ast::{
-    pair, Anchor, Circle, Clear, Command, Constructor, Data, DataType, Directive, DrawGeometry,
-    Driver, Fill, FunctionCall, Intent, Label, LayerBlock, Literal, MapBlock, MapSpec, Num,
-    Pattern, PredGroup, Predicate, Select, Size, Source, Square, Srid, Stroke, Sym, Text, Value,
+    pair, Anchor, Circle, Clear, Command, Comment, Constructor, Data, DataType, Directive,
+    DrawGeometry, Driver, Else, Expr, Extent, Fill, FunctionCall, FunctionDef, GeometryClass,
+    Include, Intent, Label, LayerBlock, Literal, MapBlock, MapSpec, Match, Matching, Name, Num,
+    Opacity, Pattern, PredGroup, Predicate, ScaleRange, Select, Size, Source, Square, Srid, Stroke,
+    Sym, Text, Value, ValuePair, Visible,
 };
+use crate::comment::keep_inner_comments;
+use crate::diagnostic::Diagnostic;
 
 const KEYWORD_MAP: &[u8] = b"map";
 const KEYWORD_LAYER: &[u8] = b"layer";
 const KEYWORD_SOURCE: &[u8] = b"source";
 const KEYWORD_SRID: &[u8] = b"srid";
+const KEYWORD_EXTENT: &[u8] = b"extent";
+const KEYWORD_NAME: &[u8] = b"name";
+const KEYWORD_VISIBLE: &[u8] = b"visible";
+const KEYWORD_OPACITY: &[u8] = b"opacity";
+const KEYWORD_INCLUDE: &[u8] = b"include";
 const KEYWORD_DATA: &[u8] = b"data";
+const KEYWORD_FN: &[u8] = b"fn";
 const KEYWORD_SYM: &[u8] = b"sym";
 const KEYWORD_LABEL: &[u8] = b"label";
+const KEYWORD_ELSE: &[u8] = b"else";
+const KEYWORD_MATCHING: &[u8] = b"matching";
+const KEYWORD_MINSCALE: &[u8] = b"minscale";
+const KEYWORD_MAXSCALE: &[u8] = b"maxscale";
+const MATCHING_FIRST: &[u8] = b"first";
+const MATCHING_ALL: &[u8] = b"all";
 const KEYWORD_SELECT: &[u8] = b"select";
 const KEYWORD_COMMAND: &[u8] = b"->";
 const KEYWORD_INTENT: &[u8] = b"->";
 const KEYWORD_OR: &[u8] = b"|";
 const KEYWORD_AND: &[u8] = b"&";
+const KEYWORD_NOT: &[u8] = b"not";
+const KEYWORD_BANG: &[u8] = b"!";
 const KEYWORD_TRUE: &[u8] = b"true";
 const KEYWORD_FALSE: &[u8] = b"false";
+const KEYWORD_MATCH: &[u8] = b"match";
+const KEYWORD_GEOMETRY: &[u8] = b"geometry";
+const KEYWORD_IS: &[u8] = b"is";
+
+const GEOMETRY_POINT: &[u8] = b"point";
+const GEOMETRY_LINE: &[u8] = b"line";
+const GEOMETRY_POLYGON: &[u8] = b"polygon";
+const GEOMETRY_MULTI: &[u8] = b"multi";
+const GEOMETRY_COLLECTION: &[u8] = b"collection";
 
 const COMMAND_DRAW_GEOM: &[u8] = b"draw";
 const COMMAND_CLEAR: &[u8] = b"clear";
@@ -51,20 +80,66 @@ const DATATYPE_STRING: &[u8] = b"string";
 const DATATYPE_NUMBER: &[u8] = b"number";
 const DATATYPE_BOOLEAN: &[u8] = b"bool";
 
+const ARITH_ADD: u8 = b'+';
+const ARITH_SUB: u8 = b'-';
+const ARITH_MUL: u8 = b'*';
+const ARITH_DIV: u8 = b'/';
+const ARITH_REM: u8 = b'%';
+
 const PRED_OP_NOTEQ: &[u8] = b"!=";
 const PRED_OP_LTE: &[u8] = b"<=";
 const PRED_OP_GTE: &[u8] = b">=";
 const PRED_OP_EQ: &[u8] = b"=";
 const PRED_OP_GT: &[u8] = b">";
 const PRED_OP_LT: &[u8] = b"<";
+const PRED_OP_IN: &[u8] = b"in";
+const PRED_OP_NOTIN: &[u8] = b"not in";
+const PRED_OP_LIKE: &[u8] = b"like";
+const PRED_OP_ILIKE: &[u8] = b"ilike";
+const PRED_OP_IMATCH: &[u8] = b"~*";
+const PRED_OP_MATCH: &[u8] = b"~";
+const PRED_OP_PREFIX: &[u8] = b"^=";
 
 #[derive(Debug, Clone)]
 pub enum ParseError {
     Mysterious,
     Wrap(PomError),
-    DataNotInScope(String),
-    UnknownPredicate(String),
-    UnknownPredicateGrouping(String),
+    DataNotInScope(String, usize),
+    UnknownPredicate(String, usize),
+    UnknownPredicateGrouping(String, usize),
+    Expected(String, usize),
+    IncludeNotFound(String, usize),
+    IncludeCycle(String, usize),
+    Included(String, Box<Diagnostic>, usize),
+    Match(String, usize),
+    FunctionDeclared(String, usize),
+    Arity(String, usize, usize),
+}
+
+impl ParseError {
+    /// Byte offset in the source where the error occured, if known.
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            Self::Mysterious => None,
+            Self::Wrap(e) => match e {
+                PomError::Incomplete => None,
+                PomError::Mismatch { position, .. }
+                | PomError::Conversion { position, .. }
+                | PomError::Expect { position, .. }
+                | PomError::Custom { position, .. } => Some(*position),
+            },
+            Self::DataNotInScope(_, position)
+            | Self::UnknownPredicate(_, position)
+            | Self::UnknownPredicateGrouping(_, position)
+            | Self::Expected(_, position)
+            | Self::IncludeNotFound(_, position)
+            | Self::IncludeCycle(_, position)
+            | Self::Included(_, _, position)
+            | Self::Match(_, position)
+            | Self::FunctionDeclared(_, position)
+            | Self::Arity(_, _, position) => Some(*position),
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
@@ -72,9 +147,20 @@ impl fmt::Display for ParseError {
         match self {
             Self::Mysterious => write!(f, "Something bad happened..."),
             Self::Wrap(e) => write!(f, "PomError: {}", e),
-            Self::DataNotInScope(e) => write!(f, "Data Not In Scope: \"{}\"", e),
-            Self::UnknownPredicate(e) => write!(f, "Unknown Predicate: \"{}\"", e),
-            Self::UnknownPredicateGrouping(e) => write!(f, "Unknown Predicate Grouping: \"{}\"", e),
+            Self::DataNotInScope(e, _) => write!(f, "data `{}` not in scope", e),
+            Self::UnknownPredicate(e, _) => write!(f, "unknown predicate `{}`", e),
+            Self::UnknownPredicateGrouping(e, _) => {
+                write!(f, "unknown predicate grouping `{}`", e)
+            }
+            Self::Expected(e, _) => write!(f, "expected {}", e),
+            Self::IncludeNotFound(e, _) => write!(f, "cannot include {}", e),
+            Self::IncludeCycle(e, _) => write!(f, "include cycle: {}", e),
+            Self::Included(path, d, _) => {
+                write!(f, "in `{}` at {}:{}: {}", path, d.line, d.column, d.message)
+            }
+            Self::Match(e, _) => write!(f, "match {}", e),
+            Self::FunctionDeclared(e, _) => write!(f, "function `{}` is already declared", e),
+            Self::Arity(e, n, _) => write!(f, "function `{}` takes {} arguments", e, n),
         }
     }
 }
@@ -84,12 +170,15 @@ impl fmt::Display for ParseError {
 #[derive(Clone)]
 pub struct Scope {
     values: HashMap<String, Data>,
+    // arity of the functions declared with `fn`
+    functions: HashMap<String, usize>,
 }
 
 impl Scope {
     pub fn new() -> Self {
         Scope {
             values: HashMap::new(),
+            functions: HashMap::new(),
         }
     }
 
@@ -109,16 +198,56 @@ impl Scope {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Keep comments as `Directive::Comment`, for tools that rewrite
+    /// map files. A comment within a directive, trailing it or inside
+    /// a predicate or a chain of commands, is kept as a whole line
+    /// comment after that directive. Included files keep only their
+    /// whole line comments.
+    pub keep_comments: bool,
+    /// Where the source comes from, includes are resolved relative to it.
+    pub path: Option<PathBuf>,
+}
+
 pub struct Context {
     scopes: Vec<Scope>,
     depth: usize,
+    failure: Option<ParseError>,
+    options: ParseOptions,
+    // files being parsed, outermost first
+    files: Vec<PathBuf>,
 }
 
 impl Context {
-    fn new() -> Self {
+    fn new(options: ParseOptions) -> Self {
+        let files = options
+            .path
+            .iter()
+            .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+            .collect();
         Context {
             scopes: Vec::new(),
             depth: 0,
+            failure: None,
+            options,
+            files,
+        }
+    }
+
+    /// Keeps the failure that went the furthest in the input,
+    /// it's the one that best explains why a parse stopped.
+    fn record_failure(&mut self, error: ParseError) {
+        let further = match (&self.failure, error.position()) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(current), Some(position)) => current
+                .position()
+                .map(|current| position > current)
+                .unwrap_or(true),
+        };
+        if further {
+            self.failure = Some(error);
         }
     }
 
@@ -144,6 +273,19 @@ impl Context {
             .map(|scope| scope.values.insert(name, value));
     }
 
+    fn put_function(&mut self, name: String, arity: usize) {
+        self.scopes
+            .last_mut()
+            .map(|scope| scope.functions.insert(name, arity));
+    }
+
+    fn get_function(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.functions.get(name).copied())
+    }
+
     fn get_data(&self, name: String) -> Option<Data> {
         let default_return: Option<Data> = None;
         self.scopes
@@ -158,7 +300,40 @@ impl Context {
 pub type SharedContext = Rc<RefCell<Context>>;
 
 pub fn new_context() -> SharedContext {
-    Rc::new(RefCell::new(Context::new()))
+    new_context_with_options(ParseOptions::default())
+}
+
+pub fn new_context_with_options(options: ParseOptions) -> SharedContext {
+    Rc::new(RefCell::new(Context::new(options)))
+}
+
+pub fn keep_comments(ctx: &SharedContext) -> bool {
+    ctx.try_borrow()
+        .map(|c| c.options.keep_comments)
+        .unwrap_or(false)
+}
+
+/// Splits directives from their positions, dropping comments unless kept.
+fn locate_directives(
+    ctx: &SharedContext,
+    located: Vec<(usize, Directive)>,
+) -> (Vec<usize>, Vec<Directive>) {
+    located
+        .into_iter()
+        .filter(|(_, d)| keep_comments(ctx) || !matches!(d, Directive::Comment(_)))
+        .unzip()
+}
+
+/// Drops comments from a list of directives, unless asked to keep them.
+fn filter_comments(ctx: &SharedContext, directives: Vec<Directive>) -> Vec<Directive> {
+    if keep_comments(ctx) {
+        directives
+    } else {
+        directives
+            .into_iter()
+            .filter(|d| !matches!(d, Directive::Comment(_)))
+            .collect()
+    }
 }
 
 pub fn inc_depth(ctx: &SharedContext) {
@@ -189,8 +364,21 @@ pub fn get_data(ctx: &SharedContext, name: String) -> Option<Data> {
     ctx.try_borrow().ok().and_then(|c| c.get_data(name))
 }
 
+pub fn get_function(ctx: &SharedContext, name: &str) -> Option<usize> {
+    ctx.try_borrow().ok().and_then(|c| c.get_function(name))
+}
+
+pub fn record_failure(ctx: &SharedContext, error: ParseError) {
+    let _ = ctx
+        .try_borrow_mut()
+        .map(|mut ctx| ctx.record_failure(error));
+}
+
+pub fn take_failure(ctx: &SharedContext) -> Option<ParseError> {
+    ctx.try_borrow_mut().ok().and_then(|mut c| c.failure.take())
+}
+
 pub fn put_data(ctx: &SharedContext, name: String, value: Data) {
-    println!("PUT DATA: '{}'", name.clone());
     let _ = ctx
         .try_borrow_mut()
         .map(|mut ctx| ctx.put_data(name, value));
@@ -221,13 +409,36 @@ where
     })
 }
 
+/// Records what was expected at the current position when `parser` fails.
+pub fn expected<'a, O>(
+    ctx: &'a SharedContext,
+    what: &'a str,
+    parser: Parser<'a, u8, O>,
+) -> Parser<'a, u8, O>
+where
+    O: 'a,
+{
+    Parser::new(move |input: &'a [u8], start: usize| {
+        let result = (parser.method)(input, start);
+        if result.is_err() {
+            record_failure(ctx, ParseError::Expected(String::from(what), start));
+        }
+        result
+    })
+}
+
+/// Prints where `parser` starts and ends to stderr when the crate is
+/// built with the `trace` feature, stdout may carry a protocol.
 pub fn trace<'a, O>(name: &'a str, parser: Parser<'a, u8, O>) -> Parser<'a, u8, O>
 where
     O: 'a + Clone,
 {
+    if !cfg!(feature = "trace") {
+        return parser;
+    }
     Parser::new(move |input: &'a [u8], start: usize| {
         let result = (parser.method)(input, start);
-        println!(
+        eprintln!(
             "[trace:{}] {} -> {}",
             name,
             start,
@@ -265,6 +476,31 @@ fn eol<'a>() -> Parser<'a, u8, ()> {
     sym(b'\n').discard().name("eol")
 }
 
+fn line_comment<'a>() -> Parser<'a, u8, String> {
+    let comment = sym(b'#') + none_of(b"\n").repeat(0..);
+    comment
+        .collect()
+        .convert(|c| String::from_utf8(c.to_vec()))
+        .name("line_comment")
+}
+
+fn block_comment<'a>() -> Parser<'a, u8, String> {
+    let comment = seq(b"/*") + (!seq(b"*/") * any()).repeat(0..) + seq(b"*/");
+    comment
+        .collect()
+        .convert(|c| String::from_utf8(c.to_vec()))
+        .name("block_comment")
+}
+
+fn comment<'a>() -> Parser<'a, u8, String> {
+    line_comment() | block_comment()
+}
+
+/// A single blank, comments are blanks too.
+fn blank<'a>() -> Parser<'a, u8, ()> {
+    one_of(b" \t").discard() | comment().discard()
+}
+
 fn block_sep<'a>() -> Parser<'a, u8, ()> {
     let first_eol = trace("first_eol", trailing_space());
     let second_eol = trace("second_eol", trailing_space()).repeat(1..);
@@ -272,23 +508,28 @@ fn block_sep<'a>() -> Parser<'a, u8, ()> {
 }
 
 fn strict_spacing<'a>() -> Parser<'a, u8, ()> {
-    one_of(b" \t").repeat(1..).discard()
+    blank().repeat(1..).discard()
+}
+
+fn continuation<'a>() -> Parser<'a, u8, ()> {
+    let line = (blank().repeat(0..).discard()) - (eol() + strict_spacing());
+    line.repeat(1..).discard()
 }
 
 fn spacing<'a>() -> Parser<'a, u8, ()> {
     let strict = strict_spacing();
-    let multiline = (one_of(b" \t").repeat(0..).discard()) - (eol() + strict_spacing());
+    let multiline = continuation();
     multiline | strict
 }
 
 fn opt_spacing<'a>() -> Parser<'a, u8, ()> {
-    let strict = one_of(b" \t").repeat(0..).discard();
-    let multiline = (one_of(b" \t").repeat(0..).discard()) - (eol() + strict_spacing());
+    let strict = blank().repeat(0..).discard();
+    let multiline = continuation();
     multiline | strict
 }
 
 fn trailing_space<'a>() -> Parser<'a, u8, ()> {
-    one_of(b" \t").repeat(0..).discard() - eol()
+    blank().repeat(0..).discard() - eol()
 }
 
 fn paren<'a, O>(p: Parser<'a, u8, O>) -> Parser<'a, u8, O>
@@ -407,16 +648,31 @@ where
 
             let sep_open = (op() + compress(open) + term()).convert(move |((s, n), t)| {
                 s1.try_borrow_mut().map(|mut state| {
-                    println!("on_open {} {}", state.depth, n);
                     state.depth += n;
                     state.pendings.push((s, t));
                 })
             });
 
             let sns1 = ns2.clone();
-            let on_close = compress(close).convert(move |n| {
+            let s3 = state.clone();
+            // closing parens past our depth belong to an enclosing group
+            let closes = Parser::new(move |input: &'a [u8], start: usize| {
+                let depth = s3.try_borrow().map(|s| s.depth).unwrap_or(0);
+                let (mut n, mut position) = (0, start);
+                while n < depth {
+                    match spaced(sym(close)).parse_at(input, position) {
+                        Ok((_, next)) => {
+                            n += 1;
+                            position = next;
+                        }
+                        Err(err) if n == 0 => return Err(err),
+                        Err(_) => break,
+                    }
+                }
+                Ok((n, position))
+            });
+            let on_close = closes.convert(move |n| {
                 s2.try_borrow_mut().map(|mut state| {
-                    println!("on_close {} {}", state.depth, n);
                     state.depth -= n;
                     if let Some(pending) = state.pendings.pop() {
                         state.output = (sns1.reducer)(state.output.clone(), pending.0, pending.1);
@@ -512,61 +768,195 @@ fn number<'a>() -> Parser<'a, u8, Num> {
         .name("number")
 }
 
+/// `match` is reserved, so a `match(..)` of the wrong shape is never
+/// read as a function call.
 fn ident<'a>() -> Parser<'a, u8, String> {
     let char_string = (ascii_letter() | digit() | one_of(b"_-.")).repeat(1..);
-    char_string.convert(|chars| String::from_utf8(chars))
+    let name = char_string.convert(|chars| String::from_utf8(chars));
+    name.convert(|s| match s.as_bytes() {
+        KEYWORD_MATCH => Err("a keyword"),
+        _ => Ok(s),
+    })
 }
 
-fn literal<'a>() -> Parser<'a, u8, Literal> {
+fn scalar<'a>() -> Parser<'a, u8, Literal> {
     let n = number().map(|n| Literal::Number(n));
     let s = string().map(|s| Literal::String(s));
     let b = (seq(KEYWORD_TRUE) | seq(KEYWORD_FALSE)).map(|s| Literal::Boolean(s == KEYWORD_TRUE));
-    (n | b | s).name("literal")
+    n | b | s
+}
+
+/// At least two items, `(1)` stays a parenthesised value.
+fn list_literal<'a>() -> Parser<'a, u8, Literal> {
+    let sep = spaced(sym(b','));
+    let items = scalar() + (sep * scalar()).repeat(1..);
+    paren(items).map(|(first, rest)| Literal::List([vec![first], rest].concat()))
+}
+
+fn literal<'a>() -> Parser<'a, u8, Literal> {
+    (scalar() | list_literal()).name("literal")
 }
 
-fn srid<'a>(_ctx: &SharedContext) -> Parser<'a, u8, Directive> {
+fn srid<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     let kw = seq(KEYWORD_SRID);
-    let srid_number = integer().expect("srid wants a srid");
+    let srid_number = expected(ctx, "an integer after `srid`", integer());
     (kw * (spacing() * srid_number))
         .map(|value| Srid { value }.into())
         .name("srid")
 }
 
-// fn extent<'a>(_ctx: &SharedContext) -> Parser<'a, u8, Directive> {
-//     let kw = seq(KEYWORD_EXTENT) - spacing();
-//     let minx = number() - spacing();
-//     let maxx = number() - spacing();
-//     let miny = number() - spacing();
-//     let maxy = number();
-//     let extent = minx + maxx + miny + maxy;
-
-//     (kw * extent)
-//         .map(|(((minx, maxx), miny), maxy)| {
-//             Extent {
-//                 minx,
-//                 maxx,
-//                 miny,
-//                 maxy,
-//             }
-//             .into()
-//         })
-//         .name("extent")
-// }
+fn extent<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_EXTENT) - spacing();
+    let minx = number() - spacing();
+    let miny = number() - spacing();
+    let maxx = number() - spacing();
+    let maxy = number();
+    let extent = expected(
+        ctx,
+        "`minx miny maxx maxy` after `extent`",
+        minx + miny + maxx + maxy,
+    );
+
+    (kw * extent)
+        .map(|(((minx, miny), maxx), maxy)| {
+            Extent {
+                minx,
+                miny,
+                maxx,
+                maxy,
+            }
+            .into()
+        })
+        .name("extent")
+}
+
+fn comment_directive<'a>(_ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let indent = one_of(b" \t").repeat(0..);
+    (indent * comment()).map(|text| Comment { text }.into())
+}
+
+type DirectiveParser = for<'a> fn(&'a SharedContext) -> Parser<'a, u8, Directive>;
+
+fn include_path(ctx: &SharedContext, path: &str, position: usize) -> Result<PathBuf, ParseError> {
+    let ctx = ctx.try_borrow().map_err(|_| ParseError::Mysterious)?;
+    let base = ctx
+        .files
+        .last()
+        .and_then(|f| f.parent())
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+    let resolved = base.join(path);
+    let canonical = resolved.canonicalize().map_err(|e| {
+        ParseError::IncludeNotFound(format!("`{}`: {}", resolved.display(), e), position)
+    })?;
+    if ctx.files.contains(&canonical) {
+        let cycle: Vec<String> = ctx
+            .files
+            .iter()
+            .skip_while(|f| **f != canonical)
+            .chain(std::iter::once(&canonical))
+            .map(|f| format!("{}", f.display()))
+            .collect();
+        return Err(ParseError::IncludeCycle(cycle.join(" -> "), position));
+    }
+    Ok(canonical)
+}
+
+/// Parses an included file with the including block's grammar,
+/// its data lands in the current scope.
+fn include_file(
+    ctx: &SharedContext,
+    body: DirectiveParser,
+    path: String,
+    position: usize,
+) -> Result<Directive, ParseError> {
+    let file = include_path(ctx, &path, position)?;
+    let content = read_to_string(&file).map_err(|e| {
+        ParseError::IncludeNotFound(format!("`{}`: {}", file.display(), e), position)
+    })?;
+
+    let outer_failure = take_failure(ctx);
+    let _ = ctx.try_borrow_mut().map(|mut c| c.files.push(file));
+    let blank_lines = (one_of(b" \t").repeat(0..) - eol()).repeat(0..);
+    let directives = list(body(ctx), trailing_space().repeat(1..));
+    let result =
+        (blank_lines * directives - one_of(b" \t\n").repeat(0..) - end()).parse(content.as_bytes());
+    let _ = ctx.try_borrow_mut().map(|mut c| c.files.pop());
+    let inner_failure = take_failure(ctx);
+    if let Some(failure) = outer_failure {
+        record_failure(ctx, failure);
+    }
+
+    match result {
+        Ok(directives) => Ok(Include {
+            path,
+            directives: filter_comments(ctx, directives),
+        }
+        .into()),
+        Err(e) => {
+            let inner = inner_failure.unwrap_or(ParseError::Wrap(e));
+            let diagnostic = Diagnostic::new(&content, &inner);
+            Err(ParseError::Included(path, Box::new(diagnostic), position))
+        }
+    }
+}
+
+fn include<'a>(ctx: &'a SharedContext, body: DirectiveParser) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_INCLUDE) - spacing();
+    let path = expected(ctx, "a quoted path after `include`", string());
+    Parser::new(move |input: &'a [u8], start: usize| {
+        let (position, path, end) = (kw.method)(input, start).and_then(|(_, position)| {
+            (path.method)(input, position).map(|(path, end)| (position, path, end))
+        })?;
+        match include_file(ctx, body, path, position) {
+            Ok(directive) => Ok((directive, end)),
+            Err(err) => {
+                record_failure(ctx, err.clone());
+                Err(PomError::Custom {
+                    message: format!("{}", err),
+                    position,
+                    inner: None,
+                })
+            }
+        }
+    })
+}
+
+fn map_directive<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    srid(ctx)
+        | extent(ctx)
+        | data(ctx)
+        | function_declaration(ctx)
+        | comment_directive(ctx)
+        | include(ctx, map_directive)
+}
 
 fn map<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, MapBlock> {
-    let map = seq(KEYWORD_MAP) - eol();
-    let body = srid(ctx) | data(ctx);
+    let comment = empty().pos() + comment_directive(ctx);
+    let leading = (comment.map(Some) - trailing_space()) | trailing_space().map(|_| None);
+    let map = expected(ctx, "a `map` block", seq(KEYWORD_MAP)) - eol();
+    let body = empty().pos() + map_directive(ctx);
     let expressions = list(body, trailing_space());
-    (map * expressions).map(|directives| MapBlock { directives })
+    (leading.repeat(0..) + (map * expressions)).map(move |(leading, directives)| {
+        let located = [leading.into_iter().flatten().collect(), directives].concat();
+        let (positions, directives) = locate_directives(ctx, located);
+        MapBlock {
+            directives,
+            positions,
+        }
+    })
 }
 
-fn source<'a>(_ctx: &SharedContext) -> Parser<'a, u8, Directive> {
+fn source<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     let source = seq(KEYWORD_SOURCE) - spacing();
-    let driver = (seq(SOURCE_DRIVER_GEOJSON).map(|_| Driver::Geojson)
-        | seq(SOURCE_DRIVER_POSTGIS).map(|_| Driver::Postgis)
-        | seq(SOURCE_DRIVER_SHAPEFILE).map(|_| Driver::Shapefile))
-        - spacing();
-    let path = string();
+    let driver = expected(
+        ctx,
+        "a driver (`geojson`, `postgis` or `shapefile`) after `source`",
+        seq(SOURCE_DRIVER_GEOJSON).map(|_| Driver::Geojson)
+            | seq(SOURCE_DRIVER_POSTGIS).map(|_| Driver::Postgis)
+            | seq(SOURCE_DRIVER_SHAPEFILE).map(|_| Driver::Shapefile),
+    ) - spacing();
+    let path = expected(ctx, "a quoted path after the source driver", string());
     let opt_srid = (spacing() * number()).opt();
     let everything = source * (driver + path + opt_srid);
     trace(
@@ -575,6 +965,29 @@ fn source<'a>(_ctx: &SharedContext) -> Parser<'a, u8, Directive> {
     )
 }
 
+fn name<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_NAME) - spacing();
+    let value = expected(ctx, "a quoted name after `name`", string());
+    (kw * value).map(|value| Name { value }.into())
+}
+
+fn visible<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_VISIBLE) - spacing();
+    let value = (seq(KEYWORD_TRUE) | seq(KEYWORD_FALSE)).map(|s| s == KEYWORD_TRUE);
+    let value = expected(ctx, "`true` or `false` after `visible`", value);
+    (kw * value).map(|value| Visible { value }.into())
+}
+
+fn opacity<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_OPACITY) - spacing();
+    let value = number().convert(|n| match n.as_float() {
+        f if (0.0..=1.0).contains(&f) => Ok(n),
+        _ => Err(ParseError::Mysterious),
+    });
+    let value = expected(ctx, "a number between 0 and 1 after `opacity`", value);
+    (kw * value).map(|value| Opacity { value }.into())
+}
+
 fn datatype<'a>(_ctx: &SharedContext) -> Parser<'a, u8, DataType> {
     seq(DATATYPE_STRING).map(|_| DataType::String)
         | seq(DATATYPE_NUMBER).map(|_| DataType::Number)
@@ -582,15 +995,24 @@ fn datatype<'a>(_ctx: &SharedContext) -> Parser<'a, u8, DataType> {
 }
 
 fn constructor<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Constructor> {
-    let sel = ((seq(KEYWORD_SELECT) - spacing()) * (string() + (spacing() * datatype(ctx))))
+    let datatype = expected(
+        ctx,
+        "a datatype (`string`, `number` or `bool`) after the selector",
+        datatype(ctx),
+    );
+    let sel = ((seq(KEYWORD_SELECT) - spacing())
+        * (expected(ctx, "a quoted selector after `select`", string()) + (spacing() * datatype)))
         .map(|(selector, datatype)| Constructor::Select(Select { selector, datatype }));
     let val = value(ctx).map(|v| Constructor::Val(v));
-    trace("constructor", sel | val)
+    trace(
+        "constructor",
+        expected(ctx, "a value or a `select` after the data name", sel | val),
+    )
 }
 
 fn data<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     let data = trace("data_keyword", seq(KEYWORD_DATA)) - spacing();
-    let identifier = ident() - spacing();
+    let identifier = expected(ctx, "a name after `data`", ident()) - spacing();
     let constructor = constructor(ctx);
     trace(
         "data",
@@ -605,6 +1027,52 @@ fn data<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     )
 }
 
+/// Declares a function in the current scope, then opens the scope
+/// of its body where the parameters are data.
+fn declare_function(
+    ctx: &SharedContext,
+    name: String,
+    params: Vec<String>,
+    position: usize,
+) -> Result<(String, Vec<String>), ParseError> {
+    if get_function(ctx, &name).is_some() {
+        let err = ParseError::FunctionDeclared(name, position);
+        record_failure(ctx, err.clone());
+        return Err(err);
+    }
+    let mut ctx = ctx.borrow_mut();
+    ctx.put_function(name.clone(), params.len());
+    ctx.push_scope();
+    for (i, param) in params.iter().enumerate() {
+        let data = Data {
+            ident: param.clone(),
+            constructor: Box::new(Constructor::Param(i)),
+        };
+        ctx.put_data(param.clone(), data);
+    }
+    Ok((name, params))
+}
+
+/// `fn name(a, b) = value`, a function is in scope in its own body,
+/// which makes recursion possible, and can't be declared twice.
+fn function_declaration<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_FN) - spacing();
+    let sep = opt_spacing() + sym(b',') + opt_spacing();
+    let params = paren(list(ident(), sep));
+    let header = (empty().pos()
+        + expected(ctx, "a name after `fn`", ident())
+        + expected(ctx, "parameters after the function name", params))
+    .convert(move |((position, name), params)| declare_function(ctx, name, params, position));
+    let head = kw * header;
+    let body = spaced(sym(b'=')) * expected(ctx, "a value after `=`", value(ctx));
+    Parser::new(move |input: &'a [u8], start: usize| {
+        let ((name, params), end) = (head.method)(input, start)?;
+        let result = (body.method)(input, end);
+        pop_scope(ctx);
+        result.map(|(body, end)| (FunctionDef { name, params, body }.into(), end))
+    })
+}
+
 fn function<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, FunctionCall> {
     let sep = opt_spacing() + sym(b',') + opt_spacing();
     let args: Parser<'a, u8, Vec<Value>> = list(call(move || value(ctx)), sep);
@@ -614,21 +1082,93 @@ fn function<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, FunctionCall> {
         .name("function")
 }
 
-fn value<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Value> {
+/// Only the shape is checked here, the types of the branches are left
+/// to `apply::check` that knows what functions return.
+fn make_match(args: Vec<Value>, position: usize) -> Result<Value, ParseError> {
+    match args.as_slice() {
+        [subject, rest @ .., default] if !rest.is_empty() && rest.len().is_multiple_of(2) => {
+            let arms: Vec<ValuePair> = rest
+                .chunks(2)
+                .map(|arm| pair(arm[0].clone(), arm[1].clone()))
+                .collect();
+            Ok(Match {
+                subject: subject.clone(),
+                arms,
+                default: default.clone(),
+            }
+            .into())
+        }
+        _ => Err(ParseError::Match(
+            String::from("takes a subject, pairs of case and value, then a default"),
+            position,
+        )),
+    }
+}
+
+fn match_expr<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Value> {
+    let sep = opt_spacing() + sym(b',') + opt_spacing();
+    let args = list(call(move || value(ctx)), sep);
+    (empty().pos() - seq(KEYWORD_MATCH) + paren(args)).convert(move |(position, args)| {
+        make_match(args, position).inspect_err(|err| record_failure(ctx, err.clone()))
+    })
+}
+
+fn operand<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Value> {
     let lit = literal().map(|l| Value::Lit(l));
-    let dat = ident()
-        .convert(move |s| match get_data(ctx, s.clone()) {
+    let dat = (empty().pos() + ident())
+        .convert(move |(position, s)| match get_data(ctx, s.clone()) {
             Some(d) => Ok(d),
             _ => {
-                println!("NO DATA FOR: '{}'", s.clone());
-                Err(ParseError::DataNotInScope(s.clone()))
+                let err = ParseError::DataNotInScope(s, position);
+                record_failure(ctx, err.clone());
+                Err(err)
             }
         })
-        // .convert(move |s| get_data(ctx, s.clone()).ok_or(ParseError::DataNotInScope(s)))
         .map(|d| Value::Data(d));
-    let fun = function(ctx).map(|f| Value::Fn(f));
+    // declared functions win over the built-in ones
+    let fun = (empty().pos() + function(ctx)).convert(move |(position, f)| {
+        match get_function(ctx, &f.name) {
+            None => Ok(Value::Fn(f)),
+            Some(arity) if arity == f.args.len() => Ok(Value::Call(f)),
+            Some(arity) => {
+                let err = ParseError::Arity(f.name, arity, position);
+                record_failure(ctx, err.clone());
+                Err(err)
+            }
+        }
+    });
+    let group = paren(call(move || value(ctx)));
+    let neg = (sym(b'-') * call(move || operand(ctx))).map(|v| Expr::Neg(v).into());
+    lit | match_expr(ctx) | fun | dat | group | neg
+}
+
+fn arith(left: Value, (op, right): (u8, Value)) -> Value {
+    let operands = pair(left, right);
+    match op {
+        ARITH_ADD => Expr::Add(operands),
+        ARITH_SUB => Expr::Sub(operands),
+        ARITH_MUL => Expr::Mul(operands),
+        ARITH_DIV => Expr::Div(operands),
+        _ => Expr::Rem(operands),
+    }
+    .into()
+}
+
+/// Operands joined by arithmetic operators, `*`, `/` and `%`
+/// bind tighter than `+` and `-`, all are left associative.
+/// A binary `-` wants blanks around it, as `a-b` is an identifier
+/// and `a -1` two values, the second one negative.
+fn value<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Value> {
+    let product = || {
+        let op = spaced(one_of(&[ARITH_MUL, ARITH_DIV, ARITH_REM]));
+        (operand(ctx) + (op + operand(ctx)).repeat(0..))
+            .map(|(first, rest)| rest.into_iter().fold(first, arith))
+    };
+    let op = spaced(sym(ARITH_ADD)) | (spacing() * sym(ARITH_SUB) - spacing());
+    let sum = (product() + (op + product()).repeat(0..))
+        .map(|(first, rest)| rest.into_iter().fold(first, arith));
     with_init(
-        with_finalizer(lit | fun | dat, move || {
+        with_finalizer(sum, move || {
             dec_depth(&ctx.clone());
         }),
         move || inc_depth(&ctx.clone()),
@@ -638,37 +1178,81 @@ fn value<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Value> {
 fn predicate_single<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
     let op = trace(
         "pred op",
-        seq(PRED_OP_NOTEQ)
-            | seq(PRED_OP_LTE)
-            | seq(PRED_OP_GTE)
-            | seq(PRED_OP_EQ)
-            | seq(PRED_OP_GT)
-            | seq(PRED_OP_LT),
+        empty().pos()
+            + (seq(PRED_OP_NOTEQ)
+                | seq(PRED_OP_LTE)
+                | seq(PRED_OP_GTE)
+                | seq(PRED_OP_EQ)
+                | seq(PRED_OP_GT)
+                | seq(PRED_OP_LT)
+                | seq(PRED_OP_IN)
+                | seq(PRED_OP_LIKE)
+                | seq(PRED_OP_ILIKE)
+                | seq(PRED_OP_IMATCH)
+                | seq(PRED_OP_MATCH)
+                | seq(PRED_OP_PREFIX)
+                | (seq(KEYWORD_NOT) - spacing() - seq(PRED_OP_IN)).map(|_| PRED_OP_NOTIN)),
     );
+    let right = expected(ctx, "a value after the comparison operator", value(ctx));
     trace(
         "predicate",
-        spaced((value(ctx)) + spaced(op) + value(ctx)).convert(|((left, op), right)| match op {
-            PRED_OP_EQ => Ok(PredGroup::Pred(Predicate::Equal(pair(left, right)))),
-            PRED_OP_NOTEQ => Ok(PredGroup::Pred(Predicate::NotEqual(pair(left, right)))),
-            PRED_OP_GT => Ok(PredGroup::Pred(Predicate::GreaterThan(pair(left, right)))),
-            PRED_OP_GTE => Ok(PredGroup::Pred(Predicate::GreaterThanOrEqual(pair(
-                left, right,
-            )))),
-            PRED_OP_LT => Ok(PredGroup::Pred(Predicate::LesserThan(pair(left, right)))),
-            PRED_OP_LTE => Ok(PredGroup::Pred(Predicate::LesserThanOrEqual(pair(
-                left, right,
-            )))),
-            _ => Err(ParseError::UnknownPredicate(
-                String::from_utf8(op.into()).unwrap_or(String::new()),
-            )),
-        }),
+        spaced((value(ctx)) + spaced(op) + right).convert(
+            |((left, (position, op)), right)| match op {
+                PRED_OP_EQ => Ok(PredGroup::Pred(Predicate::Equal(pair(left, right)))),
+                PRED_OP_NOTEQ => Ok(PredGroup::Pred(Predicate::NotEqual(pair(left, right)))),
+                PRED_OP_GT => Ok(PredGroup::Pred(Predicate::GreaterThan(pair(left, right)))),
+                PRED_OP_GTE => Ok(PredGroup::Pred(Predicate::GreaterThanOrEqual(pair(
+                    left, right,
+                )))),
+                PRED_OP_LT => Ok(PredGroup::Pred(Predicate::LesserThan(pair(left, right)))),
+                PRED_OP_LTE => Ok(PredGroup::Pred(Predicate::LesserThanOrEqual(pair(
+                    left, right,
+                )))),
+                PRED_OP_IN => Ok(PredGroup::Pred(Predicate::In(pair(left, right)))),
+                PRED_OP_NOTIN => Ok(PredGroup::Pred(Predicate::NotIn(pair(left, right)))),
+                PRED_OP_LIKE => Ok(PredGroup::Pred(Predicate::Like(pair(left, right)))),
+                PRED_OP_ILIKE => Ok(PredGroup::Pred(Predicate::ILike(pair(left, right)))),
+                PRED_OP_MATCH => Ok(PredGroup::Pred(Predicate::Match(pair(left, right)))),
+                PRED_OP_IMATCH => Ok(PredGroup::Pred(Predicate::IMatch(pair(left, right)))),
+                PRED_OP_PREFIX => Ok(PredGroup::Pred(Predicate::StartsWith(pair(left, right)))),
+                _ => Err(ParseError::UnknownPredicate(
+                    String::from_utf8(op.into()).unwrap_or(String::new()),
+                    position,
+                )),
+            },
+        ),
     )
 }
 
+/// A single predicate, negated with `!` or `not` when it is
+/// followed by a single predicate or a parenthesised group.
+fn pred_term<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
+    let bang = (seq(KEYWORD_BANG) | (seq(KEYWORD_NOT) - spacing())) - opt_spacing();
+    let negated = bang * (pred_nest(ctx) | call(move || pred_term(ctx)));
+    negated.map(|group| PredGroup::Not(Box::new(group)))
+        | geometry_predicate(ctx)
+        | predicate_single(ctx)
+}
+
+fn geometry_predicate<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
+    let kw = seq(KEYWORD_GEOMETRY) - spacing() - seq(KEYWORD_IS) - spacing();
+    let class = seq(GEOMETRY_POINT).map(|_| GeometryClass::Point)
+        | seq(GEOMETRY_LINE).map(|_| GeometryClass::Line)
+        | seq(GEOMETRY_POLYGON).map(|_| GeometryClass::Polygon)
+        | seq(GEOMETRY_MULTI).map(|_| GeometryClass::Multi)
+        | seq(GEOMETRY_COLLECTION).map(|_| GeometryClass::Collection);
+    let class = expected(
+        ctx,
+        "a geometry class (`point`, `line`, `polygon`, `multi` or `collection`) after `is`",
+        class,
+    );
+    (kw * class).map(|class| PredGroup::Pred(Predicate::GeometryIs(class)))
+}
+
 fn predicate_group<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
-    let start = predicate_single(ctx) - opt_spacing();
+    let start = pred_term(ctx) - opt_spacing();
     let op = opt_spacing() * (seq(KEYWORD_OR) | seq(KEYWORD_AND));
-    let right = opt_spacing() * predicate_single(ctx);
+    let right = opt_spacing() * pred_term(ctx);
     let op_right = op + right;
     let next_list = op_right.repeat(1..);
 
@@ -695,34 +1279,35 @@ fn predicate_group<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
     )
 }
 
-fn any_pred<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
-    spaced(
-        call(move || {
-            let nester = Nester {
-                op: || {
-                    spaced(seq(KEYWORD_OR) | seq(KEYWORD_AND)).convert(|op| match op {
-                        KEYWORD_AND => Ok(PredOp::And),
-                        KEYWORD_OR => Ok(PredOp::Or),
-                        _ => Err(ParseError::Mysterious),
-                    })
+fn pred_nest<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
+    call(move || {
+        let nester = Nester {
+            op: || {
+                spaced(seq(KEYWORD_OR) | seq(KEYWORD_AND)).convert(|op| match op {
+                    KEYWORD_AND => Ok(PredOp::And),
+                    KEYWORD_OR => Ok(PredOp::Or),
+                    _ => Err(ParseError::Mysterious),
+                })
+            },
+            term: move || pred_term(ctx),
+            reducer: |left: PredGroup, op: PredOp, right: PredGroup| match op {
+                PredOp::And => PredGroup::And {
+                    left: Box::new(left),
+                    right: Box::new(right),
                 },
-                term: move || predicate_single(ctx),
-                reducer: |left: PredGroup, op: PredOp, right: PredGroup| match op {
-                    PredOp::And => PredGroup::And {
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    },
-                    PredOp::Or => PredGroup::Or {
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    },
+                PredOp::Or => PredGroup::Or {
+                    left: Box::new(left),
+                    right: Box::new(right),
                 },
-            };
+            },
+        };
 
-            nested_expr(nester)
-        }) | predicate_group(ctx)
-            | predicate_single(ctx),
-    )
+        nested_expr(nester)
+    })
+}
+
+fn any_pred<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, PredGroup> {
+    spaced(pred_nest(ctx) | predicate_group(ctx) | pred_term(ctx))
 }
 
 #[derive(Debug, Clone)]
@@ -808,17 +1393,41 @@ fn command<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Command> {
     )
 }
 
+fn consequent<'a>(ctx: &'a SharedContext, after: &'static str) -> Parser<'a, u8, Vec<Command>> {
+    let first_sep = expected(ctx, after, seq(KEYWORD_COMMAND)) - opt_spacing();
+    let sep = seq(KEYWORD_COMMAND) - opt_spacing();
+    let command = || expected(ctx, "a command after `->`", command(ctx)) - opt_spacing();
+    ((first_sep * command()) + (sep * command()).repeat(0..))
+        .map(|(first, rest)| [vec![first], rest].concat())
+}
+
+/// `minscale N` then `maxscale N`, each optional.
+fn scale_range<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, ScaleRange> {
+    let bound = |kw: &'static [u8], what: &'static str| {
+        (seq(kw) - spacing()) * expected(ctx, what, number()) - opt_spacing()
+    };
+    let min = bound(KEYWORD_MINSCALE, "a scale denominator after `minscale`");
+    let max = bound(KEYWORD_MAXSCALE, "a scale denominator after `maxscale`");
+    (min.opt() + max.opt()).map(|(min, max)| ScaleRange { min, max })
+}
+
+fn scale<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    scale_range(ctx).convert(|range| match range.is_open() {
+        true => Err(ParseError::Mysterious),
+        false => Ok(Directive::from(range)),
+    })
+}
+
 fn symbology<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     let kw = seq(KEYWORD_SYM) - spacing();
-    let pred = predicate(ctx) - opt_spacing();
-    let sep = seq(KEYWORD_COMMAND) - opt_spacing();
-    let command = command(ctx) - opt_spacing();
-    let commands = (sep * command).repeat(1..);
+    let pred = expected(ctx, "a predicate after `sym`", predicate(ctx)) - opt_spacing();
+    let commands = consequent(ctx, "`->` after predicate");
 
     trace(
         "sym",
-        (kw * (pred + commands)).map(|(predicate, consequent)| {
+        (kw * (scale_range(ctx) + pred + commands)).map(|((scale, predicate), consequent)| {
             Sym {
+                scale,
                 predicate,
                 consequent,
             }
@@ -827,6 +1436,22 @@ fn symbology<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     )
 }
 
+fn fallback<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_ELSE) | (seq(KEYWORD_SYM) - spacing() - sym(b'*'));
+    let commands = consequent(ctx, "`->` after `else`");
+    trace(
+        "else",
+        ((kw - opt_spacing()) * commands).map(|consequent| Else { consequent }.into()),
+    )
+}
+
+fn matching<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
+    let kw = seq(KEYWORD_MATCHING) - spacing();
+    let mode =
+        seq(MATCHING_FIRST).map(|_| Matching::First) | seq(MATCHING_ALL).map(|_| Matching::All);
+    kw * expected(ctx, "`first` or `all` after `matching`", mode).map(Directive::from)
+}
+
 fn anchor<'a>(_ctx: &'a SharedContext) -> Parser<'a, u8, Intent> {
     let kw = seq(INTENT_ANCHOR) - spacing();
     let anchor = (seq(ANCHOR_POINT) | seq(ANCHOR_CENTROID)).convert(|k| match k {
@@ -852,15 +1477,18 @@ fn intent<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Intent> {
 
 fn label<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     let kw = seq(KEYWORD_LABEL) - spacing();
-    let pred = predicate(ctx) - opt_spacing();
+    let pred = expected(ctx, "a predicate after `label`", predicate(ctx)) - opt_spacing();
+    let first_sep = expected(ctx, "`->` after predicate", seq(KEYWORD_INTENT)) - opt_spacing();
     let sep = seq(KEYWORD_INTENT) - opt_spacing();
-    let intent = intent(ctx) - opt_spacing();
-    let intents = (sep * intent).repeat(1..);
+    let intent = || expected(ctx, "an intent after `->`", intent(ctx)) - opt_spacing();
+    let intents = (first_sep * intent()) + (sep * intent()).repeat(0..);
 
     trace(
         "label",
-        (kw * (pred + intents)).map(|(predicate, consequent)| {
+        (kw * (scale_range(ctx) + pred + intents)).map(|((scale, predicate), (first, rest))| {
+            let consequent = [vec![first], rest].concat();
             Label {
+                scale,
                 predicate,
                 consequent,
             }
@@ -872,21 +1500,43 @@ fn label<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
 fn directive<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, Directive> {
     trace(
         "directive",
-        source(ctx) | data(ctx) | symbology(ctx) | label(ctx),
+        expected(
+            ctx,
+            "a directive",
+            source(ctx)
+                | name(ctx)
+                | visible(ctx)
+                | opacity(ctx)
+                | data(ctx)
+                | function_declaration(ctx)
+                | fallback(ctx)
+                | matching(ctx)
+                | scale(ctx)
+                | symbology(ctx)
+                | label(ctx)
+                | comment_directive(ctx)
+                | include(ctx, directive),
+        ),
     )
 }
 
 fn layer<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, LayerBlock> {
-    let layer = seq(KEYWORD_LAYER) - trailing_space();
+    let layer = expected(ctx, "a `layer` block", seq(KEYWORD_LAYER)) - trailing_space();
     let sep = trailing_space();
-    let directives = list(directive(ctx), sep);
+    let directives = list(empty().pos() + directive(ctx), sep);
 
     trace(
         "layer",
         with_init(
             with_finalizer(
                 (layer * directives)
-                    .map(|directives| LayerBlock { directives })
+                    .map(move |located| {
+                        let (positions, directives) = locate_directives(ctx, located);
+                        LayerBlock {
+                            directives,
+                            positions,
+                        }
+                    })
                     .name("layer"),
                 move || pop_scope(&ctx.clone()),
             ),
@@ -896,22 +1546,47 @@ fn layer<'a>(ctx: &'a SharedContext) -> Parser<'a, u8, LayerBlock> {
 }
 
 pub fn parse_str<'a>(map_str: &'a str) -> Result<MapSpec, ParseError> {
-    let ctx = new_context();
+    parse_str_with_options(map_str, ParseOptions::default())
+}
+
+pub fn parse_str_with_options(map_str: &str, options: ParseOptions) -> Result<MapSpec, ParseError> {
+    let keep_comments = options.keep_comments;
+    let ctx = new_context_with_options(options);
     push_scope(&ctx);
     let map = map(&ctx) - block_sep();
     let layers = list(layer(&ctx), block_sep());
-    let everything = map + layers;
-    let spec = everything
+    let everything = map + layers - one_of(b" \t\n").repeat(0..) - end();
+    let result = everything
         .map(|(map, layers)| MapSpec { map, layers })
-        .parse(map_str.as_bytes())
-        .map_err(|e| ParseError::Wrap(e))?;
-    Ok(spec)
+        .parse(map_str.as_bytes());
+    let result = result.map(|mut spec| {
+        if keep_comments {
+            keep_inner_comments(map_str, &mut spec);
+        }
+        spec
+    });
+    result.map_err(|e| {
+        record_failure(&ctx, ParseError::Wrap(e));
+        take_failure(&ctx).unwrap_or(ParseError::Mysterious)
+    })
 }
 
 #[cfg(test)]
 mod parser_test {
     use super::*;
+    use crate::ast::splice;
     // use crate::ast::*;
+
+    /// Source of a test map, `map` holds the directives following its
+    /// header and each of `layers` the lines of a layer, newline ended.
+    fn map_source(map: &str, layers: &[&str]) -> String {
+        let mut source = format!("map\nsrid 3857\nextent 0 0 1 1\n{}", map);
+        for layer in layers {
+            source.push_str(&format!("\n\nlayer\n{}", layer));
+        }
+        source
+    }
+
     #[test]
     fn trailing_space_works() {
         let map_str = "aaaaaaaaaaaaaa
@@ -943,6 +1618,22 @@ data blue  rgb(0, 0, 255)
         assert_eq!(result.unwrap().directives.len(), 3);
     }
 
+    #[test]
+    fn extent_works() {
+        let map_str = "extent 11111 22222.2 333333 444444";
+
+        let result = extent(&new_context()).parse(map_str.as_bytes());
+        match result {
+            Ok(Directive::Extent(e)) => {
+                assert_eq!(e.minx, Num::Integer(11111));
+                assert_eq!(e.miny, Num::Float(22222.2));
+                assert_eq!(e.maxx, Num::Integer(333333));
+                assert_eq!(e.maxy, Num::Integer(444444));
+            }
+            _ => panic!("extent failed to parse"),
+        }
+    }
+
     #[test]
     fn parent_works() {
         let map_str = "(truc )";
@@ -995,6 +1686,485 @@ data blue  rgb(0, 0, 255)
             }
         };
     }
+    #[test]
+    fn parse_comments() {
+        let map_str = include_str!("../data/map-format-comments");
+        let is_comment = |d: &Directive| matches!(d, Directive::Comment(_));
+
+        match parse_str(map_str) {
+            Ok(spec) => {
+                assert_eq!(spec.map.directives.len(), 4);
+                assert_eq!(spec.layers[0].directives.len(), 5);
+                assert!(!spec.map.directives.iter().any(is_comment));
+            }
+            Err(err) => {
+                panic!("\n**ERROR**\n{}\n", err);
+            }
+        };
+
+        let options = ParseOptions {
+            keep_comments: true,
+            ..ParseOptions::default()
+        };
+        match parse_str_with_options(map_str, options) {
+            Ok(spec) => {
+                let comments: Vec<String> = spec
+                    .map
+                    .directives
+                    .iter()
+                    .chain(spec.layers[0].directives.iter())
+                    .filter_map(|d| match d {
+                        Directive::Comment(c) => Some(c.text.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                assert_eq!(
+                    comments,
+                    vec![
+                        "# comments are allowed anywhere a blank is",
+                        "/* including block comments,\n   spanning several lines */",
+                        "# web mercator",
+                        "# a palette",
+                        "# sym prop1 = \"disabled\" -> fill blue",
+                        "# seats",
+                        "/* inline */",
+                        "# radius in pixels",
+                        "# then colour it",
+                    ]
+                );
+                // inner comments follow the directive they were in
+                let layer = &spec.layers[0];
+                assert!(matches!(layer.directives[4], Directive::Sym(_)));
+                assert!(matches!(layer.directives[5], Directive::Comment(_)));
+                assert!(layer.positions.windows(2).all(|w| w[0] < w[1]));
+            }
+            Err(err) => {
+                panic!("\n**ERROR**\n{}\n", err);
+            }
+        };
+    }
+
+    fn parse_include_file(name: &str) -> Result<MapSpec, ParseError> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("data/include")
+            .join(name);
+        let map_str = read_to_string(&path).unwrap();
+        let options = ParseOptions {
+            path: Some(path),
+            ..ParseOptions::default()
+        };
+        parse_str_with_options(&map_str, options)
+    }
+
+    #[test]
+    fn parse_include() {
+        match parse_include_file("map-format-include") {
+            Ok(spec) => {
+                let map_data = splice(&spec.map.directives)
+                    .iter()
+                    .filter(|d| matches!(d, Directive::Data(_)))
+                    .count();
+                assert_eq!(map_data, 3);
+                assert!(matches!(
+                    spec.layers[0].directives[1],
+                    Directive::Include(_)
+                ));
+                assert_eq!(splice(&spec.layers[0].directives).len(), 8);
+            }
+            Err(err) => {
+                panic!("\n**ERROR**\n{}\n", err);
+            }
+        };
+    }
+
+    #[test]
+    fn include_cycle() {
+        match parse_include_file("map-format-include-cycle") {
+            Err(ParseError::Included(path, diagnostic, _)) => {
+                assert_eq!(path, "cycle-a.map");
+                assert!(diagnostic.message.contains("include cycle"));
+            }
+            other => panic!("expected an include cycle, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn include_missing() {
+        match parse_include_file("map-format-include-missing") {
+            Err(ParseError::IncludeNotFound(message, _)) => {
+                assert!(message.contains("no-such-palette.map"));
+            }
+            other => panic!("expected a missing include, got {:?}", other),
+        };
+    }
+
+    fn show(value: &Value) -> String {
+        let op = |name: &str, (l, r): &ValuePair| format!("({} {} {})", name, show(l), show(r));
+        match value {
+            Value::Lit(l) => format!("{}", l),
+            Value::Data(d) => d.ident.clone(),
+            Value::Fn(f) => format!("{}(..)", f.name),
+            Value::Call(f) => format!("{}!(..)", f.name),
+            Value::Match(_) => String::from("match(..)"),
+            Value::Expr(e) => match e.as_ref() {
+                Expr::Neg(v) => format!("(neg {})", show(v)),
+                Expr::Add(p) => op("+", p),
+                Expr::Sub(p) => op("-", p),
+                Expr::Mul(p) => op("*", p),
+                Expr::Div(p) => op("/", p),
+                Expr::Rem(p) => op("%", p),
+            },
+        }
+    }
+
+    #[test]
+    fn arithmetic_works() {
+        let ctx = new_context();
+        push_scope(&ctx);
+        let x = Data {
+            ident: String::from("x"),
+            constructor: Box::new(Constructor::Val(Value::Lit(Literal::from(1)))),
+        };
+        put_data(&ctx, String::from("x"), x);
+
+        let cases = [
+            ("1 + 2 * 3", "(+ 1 (* 2 3))"),
+            ("1 - 2 - 3", "(- (- 1 2) 3)"),
+            ("(x + 2) * 3 - 4 % 5", "(- (* (+ x 2) 3) (% 4 5))"),
+            ("-x / 2", "(/ (neg x) 2)"),
+            ("rgb(1, 2, 3) * -2", "(* rgb(..) -2)"),
+            ("x*2", "(* x 2)"),
+        ];
+        for (input, expected) in cases.iter() {
+            let result = value(&ctx).parse(input.as_bytes()).unwrap();
+            assert_eq!(show(&result), *expected);
+        }
+    }
+
+    #[test]
+    fn parse_arith() {
+        let spec = parse_str(include_str!("../data/map-format-arith")).unwrap();
+        match &spec.layers[0].directives[3] {
+            Directive::Data(d) => match d.constructor.as_ref() {
+                Constructor::Val(v) => assert_eq!(tree(v), "(+ (* base 2) 1)"),
+                other => panic!("unexpected constructor {:?}", other),
+            },
+            other => panic!("unexpected directive {:?}", other),
+        }
+        let consequents: Vec<String> = spec.layers[0]
+            .directives
+            .iter()
+            .filter_map(|d| match d {
+                Directive::Sym(s) => Some(s.consequent.iter()),
+                _ => None,
+            })
+            .flatten()
+            .map(|c| match c {
+                Command::Circle(c) => tree(&c.radius),
+                Command::Fill(f) => tree(&f.color),
+                other => format!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            consequents,
+            vec![
+                "(* radius -2)",
+                "rgb(255, (- 255 (* base 10)), 0)",
+                "(- radius)",
+            ]
+        );
+
+        let commands = |line: &str| {
+            let layer = format!(
+                "source geojson \"a\"\ndata w select \"w\" number\nsym true = true -> {}\n",
+                line
+            );
+            let map_str = map_source("", &[&layer]);
+            match parse_str(&map_str) {
+                Ok(spec) => match &spec.layers[0].directives[2] {
+                    Directive::Sym(s) => s
+                        .consequent
+                        .iter()
+                        .map(|c| match c {
+                            Command::Stroke(s) => format!("{} {}", tree(&s.color), tree(&s.size)),
+                            Command::Circle(c) => tree(&c.radius),
+                            other => format!("{:?}", other),
+                        })
+                        .collect::<Vec<_>>(),
+                    other => panic!("unexpected directive {:?}", other),
+                },
+                Err(err) => panic!("\n{}\n{}", line, err),
+            }
+        };
+        assert_eq!(commands("stroke w -1"), vec!["w -1"]);
+        assert_eq!(commands("stroke \"#ff0000\" -1"), vec!["#ff0000 -1"]);
+        assert_eq!(commands("stroke w - 1 2"), vec!["(- w 1) 2"]);
+        assert_eq!(commands("circle w * -2"), vec!["(* w -2)"]);
+        assert_eq!(commands("circle 10 - 2 - w"), vec!["(- (- 10 2) w)"]);
+    }
+
+    /// Arithmetic as prefix expressions, to see precedence and grouping.
+    fn tree(value: &Value) -> String {
+        let binary = |op: &str, (left, right): &ValuePair| {
+            format!("({} {} {})", op, tree(left), tree(right))
+        };
+        match value {
+            Value::Lit(l) => format!("{}", l),
+            Value::Data(d) => d.ident.clone(),
+            Value::Fn(f) => format!(
+                "{}({})",
+                f.name,
+                f.args.iter().map(tree).collect::<Vec<_>>().join(", ")
+            ),
+            Value::Expr(e) => match e.as_ref() {
+                Expr::Neg(v) => format!("(- {})", tree(v)),
+                Expr::Add(p) => binary("+", p),
+                Expr::Sub(p) => binary("-", p),
+                Expr::Mul(p) => binary("*", p),
+                Expr::Div(p) => binary("/", p),
+                Expr::Rem(p) => binary("%", p),
+            },
+            other => format!("{:?}", other),
+        }
+    }
+
+    fn shape(group: &PredGroup) -> String {
+        match group {
+            PredGroup::Empty => String::from("_"),
+            PredGroup::Pred(_) => String::from("p"),
+            PredGroup::Not(inner) => format!("!{}", shape(inner)),
+            PredGroup::And { left, right } => format!("({} & {})", shape(left), shape(right)),
+            PredGroup::Or { left, right } => format!("({} | {})", shape(left), shape(right)),
+        }
+    }
+
+    #[test]
+    fn parse_in() {
+        let spec = parse_str(include_str!("../data/map-format-pred-in")).unwrap();
+        let furniture = Literal::List(vec![
+            Literal::from("bench"),
+            Literal::from("chair"),
+            Literal::from("something else"),
+        ]);
+        match &spec.map.directives[3] {
+            Directive::Data(d) => match d.constructor.as_ref() {
+                Constructor::Val(Value::Lit(l)) => assert_eq!(*l, furniture),
+                other => panic!("unexpected constructor {:?}", other),
+            },
+            other => panic!("unexpected directive {:?}", other),
+        }
+        let predicates: Vec<String> = spec.layers[0]
+            .directives
+            .iter()
+            .filter_map(|d| match d {
+                Directive::Sym(s) => Some(format!("{:?}", s.predicate)),
+                _ => None,
+            })
+            .collect();
+        assert!(predicates[0].starts_with("Pred(In("));
+        assert!(predicates[1].starts_with("And { left: Pred(NotIn("));
+        assert!(predicates[1].contains("In((Data"));
+        // a parenthesised single value is not a list
+        assert!(predicates[2].contains("Lit(String(\"park\"))"));
+    }
+
+    #[test]
+    fn parse_not() {
+        let spec = parse_str(include_str!("../data/map-format-pred-not")).unwrap();
+        let shapes: Vec<String> = spec.layers[0]
+            .directives
+            .iter()
+            .filter_map(|d| match d {
+                Directive::Sym(s) => Some(shape(&s.predicate)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            shapes,
+            vec!["!(p | p)", "(!p & p)", "(p | !(p & !p))", "!!p",]
+        );
+    }
+
+    #[test]
+    fn parse_geometry() {
+        let spec = parse_str(include_str!("../data/map-format-geometry")).unwrap();
+        match &spec.layers[0].directives[2] {
+            Directive::Sym(s) => assert!(matches!(
+                s.predicate,
+                PredGroup::Pred(Predicate::GeometryIs(GeometryClass::Polygon))
+            )),
+            other => panic!("unexpected directive {:?}", other),
+        }
+        let unknown = map_source(
+            "",
+            &["source geojson \"a\"\nsym geometry is square -> fill red\n"],
+        );
+        match parse_str(&unknown) {
+            Err(ParseError::Expected(what, _)) => assert!(what.starts_with("a geometry class")),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_scale() {
+        let spec = parse_str(include_str!("../data/map-format-scale")).unwrap();
+        let first = &spec.layers[0].directives;
+        match &first[1] {
+            Directive::Scale(range) => {
+                assert_eq!(range.min, Some(Num::Integer(1000)));
+                assert_eq!(range.max, Some(Num::Integer(500000)));
+                assert!(range.contains(1000.0));
+                assert!(!range.contains(500000.0));
+            }
+            other => panic!("unexpected directive {:?}", other),
+        }
+        match &first[3] {
+            Directive::Sym(s) => {
+                assert_eq!(s.scale.min, None);
+                assert_eq!(s.scale.max, Some(Num::Integer(25000)));
+            }
+            other => panic!("unexpected directive {:?}", other),
+        }
+        match &first[6] {
+            Directive::Label(l) => assert_eq!(l.scale.max, Some(Num::Integer(10000))),
+            other => panic!("unexpected directive {:?}", other),
+        }
+        match &spec.layers[1].directives[1] {
+            Directive::Scale(range) => assert!(range.min.is_none() && range.max.is_some()),
+            other => panic!("unexpected directive {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_layer_meta() {
+        let spec = parse_str(include_str!("../data/map-format-layer-meta")).unwrap();
+        let (parks, roads) = (&spec.layers[0], &spec.layers[1]);
+        assert_eq!(parks.name(), Some(String::from("parks")));
+        assert!(parks.visible());
+        assert_eq!(parks.opacity(), 0.6);
+        assert_eq!(roads.name(), Some(String::from("roads")));
+        assert!(!roads.visible());
+        assert_eq!(roads.opacity(), 1.0);
+
+        let opaque = map_source("", &["source geojson \"a\"\nopacity 1.5\n"]);
+        match parse_str(&opaque) {
+            Err(ParseError::Expected(what, _)) => {
+                assert_eq!(what, "a number between 0 and 1 after `opacity`")
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_else() {
+        let spec = parse_str(include_str!("../data/map-format-else")).unwrap();
+        let first = &spec.layers[0].directives;
+        assert!(matches!(first[1], Directive::Matching(Matching::First)));
+        match &first[4] {
+            Directive::Else(e) => assert_eq!(e.consequent.len(), 1),
+            other => panic!("unexpected directive {:?}", other),
+        }
+        match &spec.layers[1].directives[3] {
+            Directive::Else(e) => assert_eq!(e.consequent.len(), 2),
+            other => panic!("unexpected directive {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_match() {
+        let spec = parse_str(include_str!("../data/map-format-match")).unwrap();
+        match &spec.layers[0].directives[3] {
+            Directive::Sym(s) => match &s.consequent[0] {
+                Command::Fill(f) => match &f.color {
+                    Value::Match(m) => {
+                        assert_eq!(m.arms.len(), 2);
+                        assert!(matches!(m.arms[1].0, Value::Lit(Literal::List(_))));
+                    }
+                    other => panic!("unexpected value {:?}", other),
+                },
+                other => panic!("unexpected command {:?}", other),
+            },
+            other => panic!("unexpected directive {:?}", other),
+        }
+
+        let mixed = map_source(
+            "",
+            &["source geojson \"a\"\nsym true = true -> circle match(1, 1, 2, \"three\")\n"],
+        );
+        assert!(parse_str(&mixed).is_ok());
+        let odd = map_source(
+            "",
+            &["source geojson \"a\"\nsym true = true -> circle match(1, 1, 2)\n"],
+        );
+        assert!(matches!(parse_str(&odd), Err(ParseError::Match(_, _))));
+        let short = map_source(
+            "",
+            &["source geojson \"a\"\nsym true = true -> circle match(1, 2)\n"],
+        );
+        assert!(matches!(parse_str(&short), Err(ParseError::Match(_, _))));
+        let named = map_source(
+            "",
+            &["source geojson \"a\"\ndata match select \"m\" number\n"],
+        );
+        assert!(parse_str(&named).is_err());
+    }
+
+    #[test]
+    fn parse_fn() {
+        let spec = parse_str(include_str!("../data/map-format-fn")).unwrap();
+        match &spec.map.directives[2] {
+            Directive::Fn(f) => {
+                assert_eq!(f.params, vec![String::from("size")]);
+                assert_eq!(show(&f.body), "(* size 1.5)");
+                match &f.body {
+                    Value::Expr(e) => match e.as_ref() {
+                        Expr::Mul((Value::Data(d), _)) => {
+                            assert!(matches!(d.constructor.as_ref(), Constructor::Param(0)))
+                        }
+                        other => panic!("unexpected expression {:?}", other),
+                    },
+                    other => panic!("unexpected body {:?}", other),
+                }
+            }
+            other => panic!("unexpected directive {:?}", other),
+        }
+        // the parameter hides the layer data, a function can call itself
+        match &spec.layers[0].directives[3] {
+            Directive::Fn(f) => assert_eq!(show(&f.body), "match(..)"),
+            other => panic!("unexpected directive {:?}", other),
+        }
+        match &spec.layers[0].directives[4] {
+            Directive::Sym(s) => match &s.consequent[0] {
+                Command::Stroke(stroke) => assert_eq!(show(&stroke.size), "road_width!(..)"),
+                other => panic!("unexpected command {:?}", other),
+            },
+            other => panic!("unexpected directive {:?}", other),
+        }
+
+        let arity = map_source(
+            "fn double(x) = x * 2\n",
+            &["source geojson \"a\"\nsym true = true -> circle double(1, 2)\n"],
+        );
+        match parse_str(&arity) {
+            Err(ParseError::Arity(name, 1, _)) => assert_eq!(name, "double"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let twice = map_source(
+            "fn double(x) = x * 2\n",
+            &["source geojson \"a\"\nfn double(y) = y + y\n"],
+        );
+        match parse_str(&twice) {
+            Err(ParseError::FunctionDeclared(name, _)) => assert_eq!(name, "double"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        // parameters don't leak out of the body
+        let leak = map_source(
+            "fn double(x) = x * 2\n",
+            &["source geojson \"a\"\nsym true = true -> circle x\n"],
+        );
+        assert!(parse_str(&leak).is_err());
+    }
+
     #[test]
     fn parse_nested() {
         let input = "(a + (b+ c + (d)) + ((e + f)+ g + h   )   )";